@@ -1,6 +1,6 @@
 export KSU_HAS_METAMODULE="true"
 export KSU_METAMODULE="Hybrid-Mount"
-BASE_DIR="/data/adb/Hybrid-Mount"
+BASE_DIR="/data/adb/hybrid-mount"
 BUILTIN_PARTITIONS="system vendor product system_ext odm oem apex"
 
 handle_partition() {
@@ -1,5 +1,5 @@
 MODDIR="${0%/*}"
-BASE_DIR="/data/adb/Hybrid-Mount"
+BASE_DIR="/data/adb/hybrid-mount"
 
 mkdir -p "$BASE_DIR"
 
@@ -3,7 +3,7 @@
 
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 use crate::defs;
 
@@ -28,22 +28,42 @@ pub enum Commands {
         #[arg(short = 'o', long = "output", default_value = defs::CONFIG_FILE)]
         output: PathBuf,
     },
-    ShowConfig,
+    ShowConfig {
+        #[arg(long)]
+        sources: bool,
+    },
     #[command(name = "save-config")]
     SaveConfig {
-        #[arg(long)]
-        payload: String,
+        #[command(flatten)]
+        input: PayloadArgs,
     },
     #[command(name = "save-module-rules")]
     SaveModuleRules {
         #[arg(long)]
         module: String,
-        #[arg(long)]
-        payload: String,
+        #[command(flatten)]
+        input: PayloadArgs,
+    },
+    // Without a payload check-config validates the config on disk.
+    #[command(name = "check-config", mut_group("PayloadArgs", |g| g.required(false)))]
+    CheckConfig {
+        #[arg(long, conflicts_with = "PayloadArgs")]
+        file: Option<PathBuf>,
+        #[command(flatten)]
+        input: Option<PayloadArgs>,
+    },
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
     },
     Modules,
     Conflicts,
     Diagnostics,
+    Doctor,
+    Sync {
+        #[arg(long)]
+        dry_run: bool,
+    },
     Poaceae {
         #[arg(short, long, default_value = defs::POACEAE_MOUNT_POINT)]
         target: String,
@@ -53,6 +73,25 @@ pub enum Commands {
     },
 }
 
+// JSON payload source: hex on the command line, a file, or stdin.
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+pub struct PayloadArgs {
+    #[arg(long)]
+    pub payload: Option<String>,
+    #[arg(long)]
+    pub payload_file: Option<PathBuf>,
+    #[arg(long)]
+    pub stdin: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileAction {
+    Use { name: String },
+    Clear,
+    List,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum PoaceaeAction {
     Hide {
@@ -1,19 +1,62 @@
-use std::{fs::File, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use serde::Serialize;
 
 use crate::{
     conf::{
-        cli::{Cli, PoaceaeAction},
-        config::{self, Config},
+        cli::{Cli, PayloadArgs, PoaceaeAction, ProfileAction},
+        config::{self, Config, ConfigSources, OverlayMode},
+        patch,
+        validate::{self, ValidationReport},
+    },
+    core::{
+        inventory,
+        inventory::model as modules,
+        ops::{planner, sync},
+        state, storage,
     },
-    core::{inventory, inventory::model as modules, ops::planner},
     defs,
-    sys::poaceae,
+    sys::{
+        caps::{self, Caps, MissingCap},
+        mount::is_mounted,
+        poaceae,
+    },
     utils,
 };
 
+#[derive(Serialize)]
+struct ShowConfigJson {
+    config: serde_json::Value,
+    sources: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct ProfileListJson {
+    active: Option<String>,
+    profiles: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DoctorJson<'a> {
+    #[serde(flatten)]
+    caps: &'a Caps,
+    missing: Vec<MissingCap>,
+}
+
+#[derive(Serialize)]
+struct SyncReportJson {
+    storage: storage::StorageKind,
+    root: Option<PathBuf>,
+    bytes: u64,
+    changes: Vec<sync::SyncChange>,
+}
+
 #[derive(Serialize)]
 struct DiagnosticIssueJson {
     level: String,
@@ -52,34 +95,164 @@ fn load_config(cli: &Cli) -> Result<Config> {
     }
 }
 
+fn load_config_with_sources(cli: &Cli) -> Result<(Config, ConfigSources)> {
+    match &cli.config {
+        Some(config_path) => Config::load_layered(config_path, None).with_context(|| {
+            format!(
+                "Failed to load config from custom path: {}",
+                config_path.display()
+            )
+        }),
+        None => Config::load_default_with_sources()
+            .with_context(|| format!("Failed to load default config from {}", defs::CONFIG_FILE)),
+    }
+}
+
+fn collect_value_sources(
+    key: &str,
+    value: &serde_json::Value,
+    sources: &ConfigSources,
+    out: &mut BTreeMap<String, String>,
+) {
+    match value {
+        serde_json::Value::Object(map) if !map.is_empty() => {
+            for (k, v) in map {
+                let child = if key.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{key}.{k}")
+                };
+                collect_value_sources(&child, v, sources, out);
+            }
+        }
+        serde_json::Value::Array(items) if !items.is_empty() => {
+            for (i, v) in items.iter().enumerate() {
+                collect_value_sources(&format!("{key}[{i}]"), v, sources, out);
+            }
+        }
+        _ => {
+            let origin = sources
+                .get(key)
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|| "default".to_string());
+            out.insert(key.to_string(), origin);
+        }
+    }
+}
+
+fn decode_hex_payload(payload: &str) -> Result<Vec<u8>> {
+    let payload = payload.trim();
+
+    if !payload.len().is_multiple_of(2) {
+        bail!("Hex payload has odd length {}", payload.len());
+    }
+
+    payload
+        .as_bytes()
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let high = (pair[0] as char).to_digit(16);
+            let low = (pair[1] as char).to_digit(16);
+            match (high, low) {
+                (Some(h), Some(l)) => Ok((h * 16 + l) as u8),
+                _ => bail!("Invalid hex digit in payload at offset {}", i * 2),
+            }
+        })
+        .collect()
+}
+
+fn read_payload(input: &PayloadArgs) -> Result<Vec<u8>> {
+    read_payload_from(input, io::stdin())
+}
+
+// The hex payload wins over the file, and stdin is only read when neither is given.
+fn read_payload_from(input: &PayloadArgs, mut stdin: impl Read) -> Result<Vec<u8>> {
+    if let Some(payload) = &input.payload {
+        return decode_hex_payload(payload);
+    }
+
+    if let Some(path) = &input.payload_file {
+        return fs::read(path)
+            .with_context(|| format!("Failed to read payload file {}", path.display()));
+    }
+
+    let mut buf = Vec::new();
+    stdin
+        .read_to_end(&mut buf)
+        .context("Failed to read payload from stdin")?;
+
+    Ok(buf)
+}
+
+fn ensure_valid(config: &Config) -> Result<()> {
+    let report = validate::validate(config);
+
+    let errors: Vec<String> = report
+        .errors()
+        .map(|i| format!("{}: {}", i.field, i.message))
+        .collect();
+
+    if !errors.is_empty() {
+        bail!("Config validation failed:\n{}", errors.join("\n"));
+    }
+
+    Ok(())
+}
+
 pub fn handle_gen_config(output: &Path) -> Result<()> {
     Config::default()
         .save_to_file(output)
         .with_context(|| format!("Failed to save generated config to {}", output.display()))
 }
 
-pub fn handle_show_config(cli: &Cli) -> Result<()> {
-    let config = load_config(cli)?;
+pub fn handle_show_config(cli: &Cli, with_sources: bool) -> Result<()> {
+    if !with_sources {
+        let config = load_config(cli)?;
+
+        let json = serde_json::to_string(&config).context("Failed to serialize config to JSON")?;
 
-    let json = serde_json::to_string(&config).context("Failed to serialize config to JSON")?;
+        println!("{}", json);
+
+        return Ok(());
+    }
+
+    let (config, sources) = load_config_with_sources(cli)?;
+
+    let config = serde_json::to_value(&config).context("Failed to serialize config to JSON")?;
+
+    let mut value_sources = BTreeMap::new();
+    collect_value_sources("", &config, &sources, &mut value_sources);
+
+    let json = serde_json::to_string(&ShowConfigJson {
+        config,
+        sources: value_sources,
+    })
+    .context("Failed to serialize config sources to JSON")?;
 
     println!("{}", json);
 
     Ok(())
 }
 
-pub fn handle_save_config(payload: &str) -> Result<()> {
-    let json_bytes = (0..payload.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&payload[i..i + 2], 16))
-        .collect::<Result<Vec<u8>, _>>()
-        .context("Failed to decode hex payload")?;
+pub fn handle_save_config(input: &PayloadArgs) -> Result<()> {
+    let json_bytes = read_payload(input)?;
 
-    let config: Config =
+    let edited: serde_json::Value =
         serde_json::from_slice(&json_bytes).context("Failed to parse config JSON payload")?;
 
-    config
-        .save_to_file(defs::CONFIG_FILE)
+    let (effective, sources) =
+        Config::load_default_with_sources().context("Failed to load current config")?;
+    let patch = patch::from_effective(&effective, &edited)?;
+
+    let config_path = Path::new(defs::CONFIG_FILE);
+    patch::ensure_not_shadowed(&patch, &sources, config_path)?;
+    let patched = patch::apply(config_path, &patch)?;
+
+    ensure_valid(&patched.config)?;
+
+    patched
+        .save(config_path)
         .context("Failed to save config file")?;
 
     println!("Configuration saved successfully.");
@@ -87,22 +260,22 @@ pub fn handle_save_config(payload: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn handle_save_module_rules(module_id: &str, payload: &str) -> Result<()> {
+pub fn handle_save_module_rules(module_id: &str, input: &PayloadArgs) -> Result<()> {
     utils::validate_module_id(module_id)?;
-    let json_bytes = (0..payload.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&payload[i..i + 2], 16))
-        .collect::<Result<Vec<u8>, _>>()
-        .context("Failed to decode hex payload")?;
+    let json_bytes = read_payload(input)?;
 
-    let new_rules: config::ModuleRules =
-        serde_json::from_slice(&json_bytes).context("Failed to parse module rules JSON")?;
-    let mut config = Config::load_default().unwrap_or_default();
+    let config_path = Path::new(defs::CONFIG_FILE);
+    let patched = patch_module_rules(
+        config_path,
+        Some(Path::new(defs::CONFIG_DROP_IN_DIR)),
+        module_id,
+        &json_bytes,
+    )?;
 
-    config.rules.insert(module_id.to_string(), new_rules);
+    ensure_valid(&patched.config)?;
 
-    config
-        .save_to_file(defs::CONFIG_FILE)
+    patched
+        .save(config_path)
         .context("Failed to update config file with new rules")?;
 
     println!("Module rules saved for {} into config.toml", module_id);
@@ -110,6 +283,128 @@ pub fn handle_save_module_rules(module_id: &str, payload: &str) -> Result<()> {
     Ok(())
 }
 
+// Like a config save, the rules are diffed against what the module gets with drop-ins and
+// the profile applied, so only the edits reach the base file.
+fn patch_module_rules(
+    config_path: &Path,
+    drop_in_dir: Option<&Path>,
+    module_id: &str,
+    json_bytes: &[u8],
+) -> Result<patch::PatchedConfig> {
+    let payload: serde_json::Value =
+        serde_json::from_slice(json_bytes).context("Failed to parse module rules JSON")?;
+    let new_rules: config::ModuleRules =
+        serde_json::from_value(payload.clone()).context("Failed to parse module rules JSON")?;
+    let mut new_rules =
+        serde_json::to_value(&new_rules).context("Failed to serialize module rules")?;
+
+    let (effective, sources) =
+        Config::load_layered(config_path, drop_in_dir).context("Failed to load current config")?;
+    let mut old_rules = match effective.rules.get(module_id) {
+        Some(rules) => serde_json::to_value(rules).context("Failed to serialize module rules")?,
+        None => serde_json::Value::Null,
+    };
+
+    // Rule editors that don't know about `when` blocks leave the key out and must not drop
+    // them. An empty list clears them.
+    if payload.get("when").is_none() {
+        for rules in [&mut old_rules, &mut new_rules] {
+            if let Some(rules) = rules.as_object_mut() {
+                rules.remove("when");
+            }
+        }
+    }
+
+    let rules_patch = serde_json::json!({
+        "rules": { module_id: patch::diff(&old_rules, &new_rules) }
+    });
+    patch::ensure_not_shadowed(&rules_patch, &sources, config_path)?;
+
+    patch::apply(config_path, &rules_patch)
+}
+
+pub fn handle_profile(cli: &Cli, action: &ProfileAction) -> Result<()> {
+    let config_path = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| defs::CONFIG_FILE.into());
+
+    match action {
+        ProfileAction::Use { name } => {
+            let config = load_config(cli)?;
+
+            if !config.profile_names().contains(name) {
+                bail!(
+                    "Unknown profile '{}'. Available: {}",
+                    name,
+                    config
+                        .profile_names()
+                        .into_iter()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+
+            if let Err(e) = config::check_profile(&config, name) {
+                bail!(e);
+            }
+
+            Config::set_active_profile(&config_path, Some(name))
+                .context("Failed to update active profile")?;
+
+            println!("Profile '{}' will be active from the next boot", name);
+        }
+        ProfileAction::Clear => {
+            Config::set_active_profile(&config_path, None)
+                .context("Failed to clear active profile")?;
+
+            println!("Active profile cleared");
+        }
+        ProfileAction::List => {
+            let config = load_config(cli)?;
+
+            let json = serde_json::to_string(&ProfileListJson {
+                profiles: config.profile_names().into_iter().collect(),
+                active: config.profile,
+            })
+            .context("Failed to serialize profile list")?;
+
+            println!("{}", json);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn handle_check_config(
+    cli: &Cli,
+    file: Option<&Path>,
+    input: Option<&PayloadArgs>,
+) -> Result<()> {
+    let parsed = match (file, input) {
+        (Some(path), _) => Config::from_file(path),
+        (None, Some(input)) => read_payload(input).and_then(|bytes| {
+            serde_json::from_slice::<Config>(&bytes).context("Failed to parse config JSON payload")
+        }),
+        (None, None) => load_config(cli),
+    };
+
+    let report = match parsed {
+        Ok(config) => validate::validate(&config),
+        Err(e) => ValidationReport::parse_error(format!("{:#}", e)),
+    };
+
+    let json = serde_json::to_string(&report).context("Failed to serialize validation report")?;
+
+    println!("{}", json);
+
+    if !report.valid {
+        bail!("Config validation failed");
+    }
+
+    Ok(())
+}
+
 pub fn handle_modules(cli: &Cli) -> Result<()> {
     let config = load_config(cli)?;
 
@@ -135,6 +430,55 @@ pub fn handle_conflicts(cli: &Cli) -> Result<()> {
     Ok(())
 }
 
+// Only ext4 keeps module copies between boots, so only ext4 is compared against what is
+// stored; every other backend is reported as starting empty.
+pub fn handle_sync(cli: &Cli, dry_run: bool) -> Result<()> {
+    if !dry_run {
+        bail!("Modules are synced into storage at boot, only --dry-run is supported");
+    }
+
+    let config = load_config(cli)?;
+
+    let module_list = inventory::scan(&config.moduledir, &config)
+        .context("Failed to scan modules for sync report")?;
+
+    let state = state::RuntimeState::load().unwrap_or_default();
+    let storage = if state.pid != 0 {
+        state.storage_mode
+    } else {
+        config
+            .effective_storage_chain()
+            .first()
+            .map(storage::StorageKind::from)
+            .unwrap_or_default()
+    };
+
+    let root = if storage == storage::StorageKind::Ext4 && state.pid != 0 {
+        if !is_mounted(&state.mount_point) {
+            bail!(
+                "Ext4 storage is not mounted at {}",
+                state.mount_point.display()
+            );
+        }
+        Some(state.mount_point)
+    } else {
+        None
+    };
+
+    let changes = sync::dry_run(&module_list, root.as_deref())?;
+    let report = SyncReportJson {
+        storage,
+        bytes: changes.iter().map(|c| c.bytes).sum(),
+        root,
+        changes,
+    };
+
+    let json = serde_json::to_string(&report).context("Failed to serialize sync report")?;
+    println!("{}", json);
+
+    Ok(())
+}
+
 pub fn handle_diagnostics(cli: &Cli) -> Result<()> {
     let config = load_config(cli)?;
 
@@ -146,11 +490,31 @@ pub fn handle_diagnostics(cli: &Cli) -> Result<()> {
 
     let report = plan.analyze();
 
-    let json_issues: Vec<DiagnosticIssueJson> = report
-        .diagnostics
-        .into_iter()
+    let mode_issues = module_list.iter().filter_map(|m| {
+        let modes = m.partition_modes(&config);
+        if modes.is_empty() {
+            return None;
+        }
+        let summary: Vec<String> = modes
+            .iter()
+            .map(|(part, mode)| format!("{}={:?}", part, mode).to_lowercase())
+            .collect();
+        Some(planner::DiagnosticIssue {
+            level: planner::DiagnosticLevel::Info,
+            context: m.id.clone(),
+            message: format!("Effective modes: {}", summary.join(", ")),
+        })
+    });
+
+    let json_issues: Vec<DiagnosticIssueJson> = mode_issues
+        .chain(erofs_tool_issues(&config))
+        .chain(squashfs_tool_issues(&config))
+        .chain(storage_issues())
+        .chain(storage_size_issues(&config))
+        .chain(report.diagnostics)
         .map(|i| DiagnosticIssueJson {
             level: match i.level {
+                planner::DiagnosticLevel::Info => "Info".to_string(),
                 planner::DiagnosticLevel::Warning => "Warning".to_string(),
                 planner::DiagnosticLevel::Critical => "Critical".to_string(),
             },
@@ -167,6 +531,184 @@ pub fn handle_diagnostics(cli: &Cli) -> Result<()> {
     Ok(())
 }
 
+pub fn handle_doctor() -> Result<()> {
+    println!("{}", doctor_json(caps::probe())?);
+
+    Ok(())
+}
+
+fn doctor_json(caps: &Caps) -> Result<String> {
+    let report = DoctorJson {
+        caps,
+        missing: caps.missing(),
+    };
+
+    serde_json::to_string(&report).context("Failed to serialize doctor report")
+}
+
+fn erofs_tool_issues(config: &Config) -> Vec<planner::DiagnosticIssue> {
+    let uses_erofs = config
+        .effective_storage_chain()
+        .contains(&OverlayMode::Erofs);
+    let issue = |level, message| planner::DiagnosticIssue {
+        level,
+        context: "mkfs.erofs".to_string(),
+        message,
+    };
+
+    let summary = match &caps::probe().tools.mkfs_erofs {
+        Some(info) => format!(
+            "{}; compressors: {}; features: {}",
+            info.version,
+            info.compressors.join(", "),
+            info.features.join(", ")
+        ),
+        None => "mkfs.erofs not found, images are built with the built-in writer".to_string(),
+    };
+
+    let mut issues = vec![issue(planner::DiagnosticLevel::Info, summary)];
+
+    if uses_erofs && let Err(e) = storage::erofs_builder(&config.erofs) {
+        issues.push(issue(
+            planner::DiagnosticLevel::Warning,
+            format!("{:#}, storage will fall back from EROFS", e),
+        ));
+    }
+
+    issues
+}
+
+// Explains from the last boot's record why earlier backends in the chain were skipped.
+fn storage_issues() -> Vec<planner::DiagnosticIssue> {
+    let state = state::RuntimeState::load().unwrap_or_default();
+
+    let mut issues: Vec<planner::DiagnosticIssue> = state
+        .storage_attempts
+        .iter()
+        .map(|attempt| match &attempt.error {
+            Some(error) => planner::DiagnosticIssue {
+                level: planner::DiagnosticLevel::Warning,
+                context: "storage".to_string(),
+                message: format!("{} was not used: {}", attempt.backend.label(), error),
+            },
+            None => planner::DiagnosticIssue {
+                level: planner::DiagnosticLevel::Info,
+                context: "storage".to_string(),
+                message: format!("Using {} storage", attempt.backend.label()),
+            },
+        })
+        .collect();
+
+    if state.pid != 0 {
+        issues.push(planner::DiagnosticIssue {
+            level: planner::DiagnosticLevel::Info,
+            context: "storage".to_string(),
+            message: format!(
+                "Tmpfs xattrs {} ({})",
+                if state.tmpfs_xattr_supported {
+                    "supported"
+                } else {
+                    "not supported"
+                },
+                state.tmpfs_xattr_method.name()
+            ),
+        });
+    }
+
+    if state.dedup.files > 0 {
+        issues.push(planner::DiagnosticIssue {
+            level: planner::DiagnosticLevel::Info,
+            context: "storage".to_string(),
+            message: format!(
+                "{} module files share data with an identical copy, saving {}",
+                state.dedup.files,
+                storage::format_size(state.dedup.bytes)
+            ),
+        });
+    }
+
+    issues
+}
+
+fn storage_size_issues(config: &Config) -> Vec<planner::DiagnosticIssue> {
+    let issue = |level, message| planner::DiagnosticIssue {
+        level,
+        context: "storage".to_string(),
+        message,
+    };
+
+    let estimate = match storage::SizeEstimate::scan(&config.moduledir) {
+        Ok(estimate) => estimate,
+        Err(e) => {
+            return vec![issue(
+                planner::DiagnosticLevel::Warning,
+                format!("Failed to estimate module size: {:#}", e),
+            )];
+        }
+    };
+
+    let mut issues = vec![issue(
+        planner::DiagnosticLevel::Info,
+        format!(
+            "Modules: {} files, {} directories, {} symlinks, {} of data, {} bytes of xattrs; \
+             ext4 image {} with {} inodes, tmpfs about {}",
+            estimate.files,
+            estimate.dirs,
+            estimate.symlinks,
+            storage::format_size(estimate.bytes),
+            estimate.xattr_bytes,
+            storage::format_size(estimate.ext4_image_size()),
+            estimate.ext4_inodes(),
+            storage::format_size(estimate.tmpfs_bytes()),
+        ),
+    )];
+
+    let uses_tmpfs = config.effective_storage_chain().iter().any(|mode| {
+        matches!(
+            mode,
+            OverlayMode::Tmpfs | OverlayMode::Erofs | OverlayMode::Squashfs
+        )
+    });
+
+    if uses_tmpfs {
+        issues.push(match estimate.check_tmpfs(&config.tmpfs) {
+            Ok(()) => issue(
+                planner::DiagnosticLevel::Info,
+                "Modules fit within the tmpfs limits and available RAM".to_string(),
+            ),
+            Err(e) => issue(
+                planner::DiagnosticLevel::Warning,
+                format!("{:#}, tmpfs and image staging will fall back", e),
+            ),
+        });
+    }
+
+    issues
+}
+
+fn squashfs_tool_issues(config: &Config) -> Vec<planner::DiagnosticIssue> {
+    if !config
+        .effective_storage_chain()
+        .contains(&OverlayMode::Squashfs)
+    {
+        return Vec::new();
+    }
+
+    let issue = |level, message| planner::DiagnosticIssue {
+        level,
+        context: "mksquashfs".to_string(),
+        message,
+    };
+
+    match caps::probe().tools.mksquashfs.clone() {
+        Some(version) => vec![issue(planner::DiagnosticLevel::Info, version)],
+        None => vec![issue(
+            planner::DiagnosticLevel::Warning,
+            "mksquashfs not found, storage will fall back from SquashFS".to_string(),
+        )],
+    }
+}
+
 pub fn handle_poaceae(target_path: &str, action: &PoaceaeAction) -> Result<()> {
     let file = File::open(target_path)
         .with_context(|| format!("Failed to open PoaceaeFS root at {}", target_path))?;
@@ -220,3 +762,169 @@ pub fn handle_poaceae(target_path: &str, action: &PoaceaeAction) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const WITH_WHEN: &str = "[rules.mod]\npriority = 1\n\n[[rules.mod.when]]\n\
+                             partitions = [\"vendor\"]\ndefault_mode = \"magic\"\n";
+
+    fn save_rules(dir: &TempDir, base: &str, payload: &str) -> Result<String> {
+        let path = dir.path().join("config.toml");
+        fs::write(&path, base).unwrap();
+
+        let drop_ins = dir.path().join("config.d");
+        patch_module_rules(&path, Some(&drop_ins), "mod", payload.as_bytes())?
+            .save(&path)
+            .unwrap();
+        Ok(fs::read_to_string(&path).unwrap())
+    }
+
+    fn rules(saved: &str) -> config::ModuleRules {
+        let config = Config::from_table(saved.parse().unwrap()).unwrap();
+        config.rules["mod"].clone()
+    }
+
+    #[test]
+    fn hex_payload_round_trips() {
+        let json = r#"{"priority": 2, "id": "é"}"#;
+        let hex: String = json.bytes().map(|b| format!("{b:02x}")).collect();
+
+        assert_eq!(decode_hex_payload(&hex).unwrap(), json.as_bytes());
+        assert_eq!(
+            decode_hex_payload(&hex.to_uppercase()).unwrap(),
+            json.as_bytes()
+        );
+        assert_eq!(
+            decode_hex_payload(&format!(" \n{hex}\t\n")).unwrap(),
+            json.as_bytes()
+        );
+        assert!(decode_hex_payload("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_hex_payloads_are_refused() {
+        let cases = [
+            ("7b2", "odd length 3"),
+            ("7g", "offset 0"),
+            ("7b7x", "offset 2"),
+            ("+7", "offset 0"),
+            ("éé", "offset 0"),
+            ("7bé", "offset 2"),
+            ("7b 7d", "odd length 5"),
+        ];
+        for (payload, expected) in cases {
+            let err = decode_hex_payload(payload).unwrap_err();
+            assert!(err.to_string().contains(expected), "{payload:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn payload_sources_take_precedence_in_order() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("payload.json");
+        fs::write(&file, "file").unwrap();
+        let args = |payload: Option<&str>, payload_file: Option<&Path>| PayloadArgs {
+            payload: payload.map(str::to_string),
+            payload_file: payload_file.map(Path::to_path_buf),
+            stdin: true,
+        };
+        let stdin = || &b"stdin"[..];
+
+        let read = read_payload_from(&args(Some("6865780a"), Some(&file)), stdin()).unwrap();
+        assert_eq!(read, b"hex\n");
+        let read = read_payload_from(&args(None, Some(&file)), stdin()).unwrap();
+        assert_eq!(read, b"file");
+        let read = read_payload_from(&args(None, None), stdin()).unwrap();
+        assert_eq!(read, b"stdin");
+
+        let missing = dir.path().join("missing.json");
+        let err = read_payload_from(&args(None, Some(&missing)), stdin()).unwrap_err();
+        assert!(err.to_string().contains("missing.json"), "{err}");
+    }
+
+    #[test]
+    fn doctor_reports_caps_and_what_is_missing() {
+        let mut caps = Caps::default();
+        caps.kernel.overlayfs = true;
+        caps.kernel.erofs = true;
+        caps.tools.mkfs_ext4 = Some(PathBuf::from("/system/bin/mkfs.ext4"));
+
+        let json: serde_json::Value = serde_json::from_str(&doctor_json(&caps).unwrap()).unwrap();
+
+        assert_eq!(json["kernel"]["overlayfs"], true);
+        assert_eq!(json["kernel"]["squashfs"], false);
+        assert_eq!(json["tools"]["mkfs_ext4"], "/system/bin/mkfs.ext4");
+        assert!(json["tools"]["mkfs_erofs"].is_null());
+        let missing: Vec<&str> = json["missing"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            missing,
+            [
+                "/dev/loop-control",
+                "fsopen",
+                "tmpfs xattrs",
+                "squashfs",
+                "e2fsck",
+                "resize2fs",
+                "mkfs.erofs",
+                "mksquashfs",
+            ]
+        );
+        assert_eq!(
+            json["missing"][0]["impact"],
+            "ext4, EROFS and SquashFS storage can't be mounted"
+        );
+    }
+
+    #[test]
+    fn rules_without_when_keep_the_blocks() {
+        let dir = TempDir::new().unwrap();
+        let saved = save_rules(&dir, WITH_WHEN, r#"{"priority": 2}"#).unwrap();
+
+        let rules = rules(&saved);
+        assert_eq!(rules.priority, Some(2));
+        assert_eq!(rules.when.len(), 1);
+    }
+
+    #[test]
+    fn empty_when_clears_the_blocks() {
+        let dir = TempDir::new().unwrap();
+        let saved = save_rules(&dir, WITH_WHEN, r#"{"priority": 1, "when": []}"#).unwrap();
+
+        assert!(rules(&saved).when.is_empty());
+        assert!(!saved.contains("when"));
+    }
+
+    #[test]
+    fn drop_in_values_stay_out_of_the_base_file() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("config.d")).unwrap();
+        fs::write(
+            dir.path().join("config.d/10-mod.toml"),
+            "[rules.mod]\npriority = 5\n",
+        )
+        .unwrap();
+
+        // the editor sends back the effective priority untouched
+        let saved = save_rules(
+            &dir,
+            "",
+            r#"{"priority": 5, "paths": {"system/app": "ignore"}}"#,
+        )
+        .unwrap();
+        let rules = rules(&saved);
+        assert_eq!(rules.priority, None);
+        assert_eq!(rules.paths["system/app"], config::MountMode::Ignore);
+
+        let err = save_rules(&dir, "", r#"{"priority": 7}"#).unwrap_err();
+        assert!(format!("{err:#}").contains("rules.mod.priority"), "{err:#}");
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2026 Hybrid Mount Developers
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{conf::config::MountMode, sys::device::DeviceFacts};
+
+// A block of rule overrides that only applies when every condition holds, e.g.
+// `props = { "ro.build.version.sdk" = ">=34" }` or `kernel = ">=5.10"`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ConditionalRules {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub props: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partitions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_mode: Option<MountMode>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub paths: HashMap<String, MountMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+}
+
+impl ConditionalRules {
+    pub fn matches(&self, facts: &DeviceFacts) -> bool {
+        self.props
+            .iter()
+            .all(|(key, expr)| facts.prop(key).is_some_and(|value| compare(value, expr)))
+            && self
+                .kernel
+                .as_ref()
+                .is_none_or(|expr| compare_kernel(facts.kernel_release(), expr))
+            && self.partitions.iter().all(|p| facts.has_partition(p))
+    }
+
+    pub fn expressions(&self) -> impl Iterator<Item = (String, &str)> {
+        self.props
+            .iter()
+            .map(|(key, expr)| (format!("props.{key}"), expr.as_str()))
+            .chain(
+                self.kernel
+                    .as_deref()
+                    .map(|expr| ("kernel".to_string(), expr)),
+            )
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+const OPERATORS: &[(&str, Op)] = &[
+    (">=", Op::Ge),
+    ("<=", Op::Le),
+    ("!=", Op::Ne),
+    (">", Op::Gt),
+    ("<", Op::Lt),
+    ("=", Op::Eq),
+];
+
+fn parse_expr(expr: &str) -> (Op, &str) {
+    let expr = expr.trim();
+    OPERATORS
+        .iter()
+        .find_map(|(prefix, op)| expr.strip_prefix(prefix).map(|rest| (*op, rest.trim())))
+        .unwrap_or((Op::Eq, expr))
+}
+
+// Leading dotted numbers, so "5.10.198-android13" compares as 5.10.198.
+fn version_key(value: &str) -> Option<Vec<u64>> {
+    let end = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let parts: Option<Vec<u64>> = value[..end]
+        .split('.')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().ok())
+        .collect();
+    parts.filter(|p| !p.is_empty())
+}
+
+// Equality compares strings; ordering operators compare version numbers.
+fn compare(actual: &str, expr: &str) -> bool {
+    let (op, expected) = parse_expr(expr);
+    let actual = actual.trim();
+
+    let ordering =
+        || -> Option<Ordering> { Some(version_key(actual)?.cmp(&version_key(expected)?)) };
+
+    match op {
+        Op::Eq => actual == expected,
+        Op::Ne => actual != expected,
+        Op::Lt => ordering().is_some_and(Ordering::is_lt),
+        Op::Le => ordering().is_some_and(Ordering::is_le),
+        Op::Gt => ordering().is_some_and(Ordering::is_gt),
+        Op::Ge => ordering().is_some_and(Ordering::is_ge),
+    }
+}
+
+// A kernel version names a series: "5.10" matches every 5.10.x release, and only as many
+// components as the condition gives are compared.
+fn compare_kernel(release: &str, expr: &str) -> bool {
+    let (op, expected) = parse_expr(expr);
+    let (Some(mut actual), Some(expected)) = (version_key(release), version_key(expected)) else {
+        return compare(release, expr);
+    };
+    actual.truncate(expected.len());
+
+    let ordering = actual.cmp(&expected);
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+    }
+}
+
+pub fn validate_expr(expr: &str) -> Result<(), String> {
+    let (op, expected) = parse_expr(expr);
+
+    if expected.is_empty() {
+        return Err(format!(
+            "Condition '{expr}' has no value to compare against"
+        ));
+    }
+
+    if matches!(op, Op::Lt | Op::Le | Op::Gt | Op::Ge) && version_key(expected).is_none() {
+        return Err(format!(
+            "Condition '{expr}' compares order against a non-numeric value"
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn version_key_reads_leading_dotted_numbers() {
+        let cases: &[(&str, Option<&[u64]>)] = &[
+            ("34", Some(&[34])),
+            ("5.10.198", Some(&[5, 10, 198])),
+            ("5.10.198-android13-4-gabcdef", Some(&[5, 10, 198])),
+            ("6.1-rc3", Some(&[6, 1])),
+            ("14.", Some(&[14])),
+            ("UpsideDownCake", None),
+            ("", None),
+            ("-1", None),
+            ("99999999999999999999999", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(version_key(value).as_deref(), *expected, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn compare_handles_every_operator() {
+        let cases = [
+            ("34", "34", true),
+            ("34", "=34", true),
+            ("34", "= 34", true),
+            ("34", "!=34", false),
+            ("34", "!=33", true),
+            ("34", ">=34", true),
+            ("34", ">33", true),
+            ("34", ">34", false),
+            ("34", "<=34", true),
+            ("34", "<35", true),
+            ("34", "<34", false),
+            ("9", "<10", true),
+            (" 34 ", ">=34", true),
+        ];
+        for (actual, expr, expected) in cases {
+            assert_eq!(compare(actual, expr), expected, "{actual:?} {expr:?}");
+        }
+    }
+
+    #[test]
+    fn compare_orders_versions_with_suffixes() {
+        assert!(compare("5.10.198-android13", ">=5.10"));
+        assert!(compare("5.10.198-android13", "<5.15"));
+        assert!(compare("6.1-rc3", ">6.0.9"));
+        assert!(!compare("5.4.0", ">=5.10"));
+        // equality stays a string comparison
+        assert!(!compare("5.10.198-android13", "=5.10"));
+    }
+
+    #[test]
+    fn compare_never_orders_garbage() {
+        assert!(!compare("user", ">=1"));
+        assert!(!compare("user", "<1"));
+        assert!(!compare("34", ">=abc"));
+        assert!(!compare("", ">0"));
+        assert!(compare("user", "=user"));
+        assert!(compare("user", "!=userdebug"));
+    }
+
+    #[test]
+    fn kernel_versions_match_their_series() {
+        let release = "5.10.198-android13-4-gabcdef";
+        let cases = [
+            ("5.10", true),
+            ("=5.10", true),
+            ("5.10.198", true),
+            ("5.1", false),
+            ("5", true),
+            ("!=5.10", false),
+            ("!=5.15", true),
+            (">=5.10", true),
+            ("<=5.10", true),
+            (">5.10", false),
+            ("<5.15", true),
+            (">5.10.100", true),
+            (release, true),
+        ];
+        for (expr, expected) in cases {
+            assert_eq!(compare_kernel(release, expr), expected, "{expr:?}");
+        }
+
+        assert!(compare_kernel("custom", "custom"));
+        assert!(!compare_kernel("custom", ">=5.10"));
+    }
+
+    #[test]
+    fn conditions_read_the_device_facts() {
+        let dir = TempDir::new().unwrap();
+        let props = dir.path().join("build.prop");
+        let release = dir.path().join("osrelease");
+        fs::write(&props, "ro.build.version.sdk=34\nro.build.type=user\n").unwrap();
+        fs::write(&release, "5.10.198-android13\n").unwrap();
+        fs::create_dir(dir.path().join("vendor")).unwrap();
+        let facts = DeviceFacts::new(Some(props), &release, dir.path());
+
+        let rules = |content: &str| toml::from_str::<ConditionalRules>(content).unwrap();
+        assert!(rules("props = { \"ro.build.version.sdk\" = \">=34\" }").matches(&facts));
+        assert!(!rules("props = { \"ro.build.version.sdk\" = \">34\" }").matches(&facts));
+        assert!(!rules("props = { \"ro.missing\" = \"!=1\" }").matches(&facts));
+        assert!(rules("kernel = \"5.10\"\npartitions = [\"vendor\"]").matches(&facts));
+        assert!(!rules("partitions = [\"odm\"]").matches(&facts));
+        assert!(!rules("partitions = [\"vendor/lib\"]").matches(&facts));
+    }
+}
@@ -1,13 +1,48 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
     fs,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
 };
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::defs;
+use crate::{
+    conf::{condition::ConditionalRules, patch},
+    defs, utils,
+};
+
+pub const CONFIG_VERSION: u32 = 1;
+
+const MERGED_ARRAY_KEYS: &[&str] = &["partitions"];
+
+const RENAMED_KEYS: &[(&str, &str)] =
+    &[("module_dir", "moduledir"), ("mount_source", "mountsource")];
+
+const OBSOLETE_KEYS: &[&str] = &["logfile", "verbose", "backup"];
+
+type Migration = fn(&mut toml::Table);
+
+// MIGRATIONS[n] upgrades a table from version n to n + 1.
+const MIGRATIONS: &[Migration] = &[migrate_v0_to_v1];
+
+// Profiles overlay the merged config; a `[profiles.<name>]` table replaces the built-in one.
+const BUILTIN_PROFILES: &[(&str, &str)] = &[
+    (
+        "safe",
+        "overlay_mode = \"tmpfs\"\ndefault_mode = \"magic\"\n",
+    ),
+    (
+        "full",
+        "overlay_mode = \"erofs\"\ndefault_mode = \"overlay\"\n",
+    ),
+    ("debug", "disable_umount = true\n"),
+];
+
+const PROFILE_KEYS: &[&str] = &["config_version", "profile", "profiles"];
+
+const TMPFS_PAGE_SIZE: u64 = 4096;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
 #[serde(rename_all = "lowercase")]
@@ -16,6 +51,114 @@ pub enum OverlayMode {
     #[default]
     Ext4,
     Erofs,
+    Squashfs,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ErofsCompressor {
+    None,
+    Lz4,
+    #[default]
+    Lz4hc,
+    Lzma,
+    Deflate,
+}
+
+impl ErofsCompressor {
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            ErofsCompressor::None => None,
+            ErofsCompressor::Lz4 => Some("lz4"),
+            ErofsCompressor::Lz4hc => Some("lz4hc"),
+            ErofsCompressor::Lzma => Some("lzma"),
+            ErofsCompressor::Deflate => Some("deflate"),
+        }
+    }
+
+    pub fn level_range(&self) -> Option<(u32, u32)> {
+        match self {
+            ErofsCompressor::None | ErofsCompressor::Lz4 => None,
+            ErofsCompressor::Lz4hc => Some((0, 12)),
+            ErofsCompressor::Lzma => Some((0, 109)),
+            ErofsCompressor::Deflate => Some((0, 9)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct ErofsConfig {
+    pub compressor: ErofsCompressor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<u32>,
+    // maximum physical cluster size in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pcluster_size: Option<u32>,
+    pub dedupe: bool,
+    pub fragments: bool,
+    // fixed UNIX build time, for reproducible images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+// Limits for every tmpfs that holds module files: the tmpfs backend and the image staging area.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct TmpfsConfig {
+    // tmpfs size= syntax: bytes with an optional k/m/g/t suffix, or a percentage of RAM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nr_inodes: Option<u64>,
+}
+
+impl TmpfsConfig {
+    // None when unset or 0, which tmpfs treats as unlimited. Rounded up to whole pages the way
+    // tmpfs does, after taking a percentage of `mem_total`.
+    pub fn size_bytes(&self, mem_total: u64) -> Result<Option<u64>> {
+        let Some(size) = self.size.as_deref().map(str::trim) else {
+            return Ok(None);
+        };
+
+        let bytes = if let Some(percent) = size.strip_suffix('%') {
+            let percent: u64 = percent
+                .parse()
+                .with_context(|| format!("invalid tmpfs size '{size}'"))?;
+            u64::try_from(u128::from(mem_total) * u128::from(percent) / 100)
+                .with_context(|| format!("tmpfs size '{size}' is too large"))?
+        } else {
+            let (digits, shift) = match size.chars().last().map(|c| c.to_ascii_lowercase()) {
+                Some('k') => (&size[..size.len() - 1], 10),
+                Some('m') => (&size[..size.len() - 1], 20),
+                Some('g') => (&size[..size.len() - 1], 30),
+                Some('t') => (&size[..size.len() - 1], 40),
+                _ => (size, 0),
+            };
+            let value: u64 = digits
+                .parse()
+                .with_context(|| format!("invalid tmpfs size '{size}'"))?;
+            value
+                .checked_mul(1 << shift)
+                .with_context(|| format!("tmpfs size '{size}' is too large"))?
+        };
+        let bytes = bytes
+            .checked_next_multiple_of(TMPFS_PAGE_SIZE)
+            .with_context(|| format!("tmpfs size '{size}' is too large"))?;
+
+        Ok((bytes > 0).then_some(bytes))
+    }
+
+    pub fn mount_options(&self) -> String {
+        let mut options = String::new();
+        if let Some(size) = &self.size {
+            options.push_str(&format!(",size={}", size.trim()));
+        }
+        if let Some(nr_inodes) = self.nr_inodes {
+            options.push_str(&format!(",nr_inodes={nr_inodes}"));
+        }
+        options
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
@@ -37,23 +180,160 @@ pub enum MountMode {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ModuleRules {
-    #[serde(default)]
-    pub default_mode: MountMode,
+    // Unset leaves the module to the partition modes and then the global default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_mode: Option<MountMode>,
     #[serde(default)]
     pub paths: HashMap<String, MountMode>,
+    // Higher priority wins file conflicts and sits higher in the overlay stack.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub when: Vec<ConditionalRules>,
+    #[serde(skip)]
+    pub partition_defaults: HashMap<String, MountMode>,
+    #[serde(skip)]
+    pub global_default: MountMode,
 }
 
 impl ModuleRules {
+    // Keys are globs matched per component (`*`, `?`, `**`) and cover everything below
+    // the path they match; the key matching the deepest ancestor wins.
     pub fn get_mode(&self, relative_path: &str) -> MountMode {
-        if let Some(mode) = self.paths.get(relative_path) {
-            return mode.clone();
+        let path = split_rule_path(relative_path);
+
+        self.paths
+            .iter()
+            .filter_map(|(pattern, mode)| {
+                rule_specificity(pattern, &path).map(|spec| (spec, pattern, mode))
+            })
+            .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1)))
+            .map(|(_, _, mode)| mode)
+            .or(self.default_mode.as_ref())
+            .or_else(|| path.first().and_then(|p| self.partition_defaults.get(*p)))
+            .unwrap_or(&self.global_default)
+            .clone()
+    }
+
+    pub fn effective_default(&self) -> MountMode {
+        self.default_mode
+            .clone()
+            .unwrap_or_else(|| self.global_default.clone())
+    }
+
+    pub fn has_nested_rules(&self, relative_path: &str, mode: &MountMode) -> bool {
+        let path = split_rule_path(relative_path);
+
+        self.paths
+            .iter()
+            .any(|(pattern, m)| m != mode && matches_below(&split_rule_path(pattern), &path))
+    }
+}
+
+pub fn validate_rule_path(pattern: &str) -> Result<(), String> {
+    if pattern.trim().is_empty() {
+        return Err("Path rule must not be empty".to_string());
+    }
+
+    if pattern.starts_with('/') {
+        return Err(format!(
+            "Path rule '{pattern}' must be relative to the module root"
+        ));
+    }
+
+    for component in pattern.trim_end_matches('/').split('/') {
+        match component {
+            "" => return Err(format!("Path rule '{pattern}' contains an empty component")),
+            "." | ".." => {
+                return Err(format!(
+                    "Path rule '{pattern}' must not contain '.' or '..'"
+                ));
+            }
+            c if c.contains("**") && c != "**" => {
+                return Err(format!(
+                    "'**' must be a whole component in path rule '{pattern}'"
+                ));
+            }
+            _ => {}
         }
-        self.default_mode.clone()
     }
+
+    Ok(())
+}
+
+fn split_rule_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+fn rule_specificity(pattern: &str, path: &[&str]) -> Option<(usize, usize)> {
+    let pattern = split_rule_path(pattern);
+
+    if pattern.is_empty() {
+        return None;
+    }
+
+    let literals = pattern.iter().filter(|c| !c.contains(['*', '?'])).count();
+
+    (1..=path.len())
+        .rev()
+        .find(|&depth| match_components(&pattern, &path[..depth]))
+        .map(|depth| (depth, literals))
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| match_components(rest, &path[i..])),
+        Some((first, rest)) => path.split_first().is_some_and(|(head, tail)| {
+            wildcard_match(first, head) && match_components(rest, tail)
+        }),
+    }
+}
+
+// Whether the pattern can match something strictly below `path`. Patterns are not matched
+// against real entries here, so `**` followed by anything can always reach below.
+fn matches_below(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.split_first(), path.split_first()) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some((&"**", rest)), Some(_)) => {
+            rest.is_empty() || (0..=path.len()).any(|i| matches_below(rest, &path[i..]))
+        }
+        (Some((first, rest)), Some((head, tail))) => {
+            wildcard_match(first, head) && matches_below(rest, tail)
+        }
+    }
+}
+
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, mark)) = backtrack {
+            pi = star + 1;
+            ti = mark + 1;
+            backtrack = Some((star, mark + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == '*')
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    #[serde(default = "default_config_version")]
+    pub config_version: u32,
     #[serde(default = "default_moduledir")]
     pub moduledir: PathBuf,
     #[serde(default = "default_mountsource")]
@@ -63,13 +343,29 @@ pub struct Config {
     #[serde(default)]
     pub overlay_mode: OverlayMode,
     #[serde(default)]
+    pub erofs: ErofsConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage_chain: Vec<OverlayMode>,
+    #[serde(default)]
+    pub tmpfs: TmpfsConfig,
+    #[serde(default)]
     pub disable_umount: bool,
     #[serde(default)]
     pub allow_umount_coexistence: bool,
     #[serde(default)]
     pub default_mode: DefaultMode,
     #[serde(default)]
+    pub partition_modes: HashMap<String, MountMode>,
+    #[serde(default)]
     pub rules: HashMap<String, ModuleRules>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, toml::Table>,
+}
+
+fn default_config_version() -> u32 {
+    CONFIG_VERSION
 }
 
 fn default_moduledir() -> PathBuf {
@@ -104,29 +400,126 @@ where
 impl Default for Config {
     fn default() -> Self {
         Self {
+            config_version: CONFIG_VERSION,
             moduledir: default_moduledir(),
             mountsource: default_mountsource(),
             partitions: Vec::new(),
             overlay_mode: OverlayMode::default(),
+            erofs: ErofsConfig::default(),
+            storage_chain: Vec::new(),
+            tmpfs: TmpfsConfig::default(),
             disable_umount: false,
             allow_umount_coexistence: false,
             default_mode: DefaultMode::default(),
+            partition_modes: HashMap::new(),
             rules: HashMap::new(),
+            profile: None,
+            profiles: BTreeMap::new(),
         }
     }
 }
 
 impl Config {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = fs::read_to_string(path.as_ref()).context("failed to read config file")?;
+        Self::from_table(read_table(path.as_ref())?)
+    }
+
+    pub fn from_table(mut table: toml::Table) -> Result<Self> {
+        migrate_table(&mut table);
+        apply_profile(&mut table, &mut ConfigSources::default());
 
-        let config: Config = toml::from_str(&content).context("failed to parse config file")?;
+        let config: Config = toml::Value::Table(table)
+            .try_into()
+            .context("failed to parse config file")?;
 
         Ok(config)
     }
 
     pub fn load_default() -> Result<Self> {
-        Self::from_file(defs::CONFIG_FILE)
+        Self::load_default_with_sources().map(|(config, _)| config)
+    }
+
+    pub fn load_default_with_sources() -> Result<(Self, ConfigSources)> {
+        Self::load_layered(defs::CONFIG_FILE, Some(Path::new(defs::CONFIG_DROP_IN_DIR)))
+    }
+
+    pub fn load_layered<P: AsRef<Path>>(
+        base: P,
+        drop_in_dir: Option<&Path>,
+    ) -> Result<(Self, ConfigSources)> {
+        let base = base.as_ref();
+        let mut layers = Vec::new();
+
+        if base.exists() {
+            layers.push(base.to_path_buf());
+        }
+
+        if let Some(dir) = drop_in_dir {
+            layers.extend(drop_in_files(dir)?);
+        }
+
+        let mut merged = toml::Table::new();
+        let mut sources = ConfigSources::default();
+
+        for layer in &layers {
+            let mut table = read_table(layer)
+                .with_context(|| format!("failed to load config layer {}", layer.display()))?;
+
+            // Only boot rewrites files, see migrate_on_disk.
+            if migrate_table(&mut table).is_some() {
+                log::debug!("Upgraded legacy keys in {} (in memory)", layer.display());
+            }
+            if layer != base {
+                table.remove("config_version");
+            }
+
+            merge_tables(&mut merged, table, "", layer, &mut sources);
+        }
+
+        apply_profile(&mut merged, &mut sources);
+
+        let config: Config = toml::Value::Table(merged)
+            .try_into()
+            .context("failed to parse merged config")?;
+
+        Ok((config, sources))
+    }
+
+    // Storage backends in the order they are tried. Without an explicit chain the overlay mode
+    // comes first, with tmpfs and then ext4 as fallbacks.
+    pub fn effective_storage_chain(&self) -> Vec<OverlayMode> {
+        let chain = if self.storage_chain.is_empty() {
+            let mut chain = vec![self.overlay_mode.clone()];
+            if self.overlay_mode != OverlayMode::Ext4 {
+                chain.extend([OverlayMode::Tmpfs, OverlayMode::Ext4]);
+            }
+            chain
+        } else {
+            self.storage_chain.clone()
+        };
+
+        let mut seen = Vec::new();
+        for mode in chain {
+            if !seen.contains(&mode) {
+                seen.push(mode);
+            }
+        }
+        seen
+    }
+
+    pub fn profile_names(&self) -> BTreeSet<String> {
+        BUILTIN_PROFILES
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(self.profiles.keys().cloned())
+            .collect()
+    }
+
+    // Only touches the `profile` key of the given file so the rest of it stays as written.
+    pub fn set_active_profile<P: AsRef<Path>>(path: P, name: Option<&str>) -> Result<()> {
+        let path = path.as_ref();
+
+        patch::apply(path, &serde_json::json!({ "profile": name }))?.save(path)
     }
 
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
@@ -160,3 +553,822 @@ impl Config {
         }
     }
 }
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ConfigSources(BTreeMap<String, PathBuf>);
+
+impl ConfigSources {
+    pub fn get(&self, key: &str) -> Option<&Path> {
+        self.0.get(key).map(PathBuf::as_path)
+    }
+
+    // Where `key` and everything nested below it come from.
+    pub fn origins_under<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a Path> + 'a {
+        self.0
+            .iter()
+            .filter(move |(k, _)| {
+                k.strip_prefix(key)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+            })
+            .map(|(_, origin)| origin.as_path())
+    }
+
+    fn record(&mut self, key: String, origin: &Path) {
+        self.0.insert(key, origin.to_path_buf());
+    }
+
+    fn record_value(&mut self, key: &str, value: &toml::Value, origin: &Path) {
+        match value {
+            toml::Value::Table(table) => {
+                for (k, v) in table {
+                    self.record_value(&join_key(key, k), v, origin);
+                }
+            }
+            toml::Value::Array(items) => {
+                for (i, _) in items.iter().enumerate() {
+                    self.record(format!("{key}[{i}]"), origin);
+                }
+            }
+            _ => self.record(key.to_string(), origin),
+        }
+    }
+
+    fn forget(&mut self, key: &str) {
+        let nested = format!("{key}.");
+        let indexed = format!("{key}[");
+        self.0
+            .retain(|k, _| k != key && !k.starts_with(&nested) && !k.starts_with(&indexed));
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+fn drop_in_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("failed to read config directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+
+    files.sort();
+
+    Ok(files)
+}
+
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let content = fs::read_to_string(path).context("failed to read config file")?;
+
+    let mut table: toml::Table = toml::from_str(&content).context("failed to parse config file")?;
+
+    if let Some(toml::Value::String(s)) = table.get("partitions") {
+        let items = s
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+            .map(|item| toml::Value::String(item.to_string()))
+            .collect();
+        table.insert("partitions".to_string(), toml::Value::Array(items));
+    }
+
+    Ok(table)
+}
+
+fn migrate_table(table: &mut toml::Table) -> Option<u32> {
+    let from = table
+        .get("config_version")
+        .and_then(toml::Value::as_integer)
+        .map(|v| v.clamp(0, u32::MAX as i64) as u32)
+        .unwrap_or(0);
+
+    if from > CONFIG_VERSION {
+        log::warn!(
+            "Config version {} is newer than supported version {}, loading as-is",
+            from,
+            CONFIG_VERSION
+        );
+        return None;
+    }
+
+    if from == CONFIG_VERSION {
+        return None;
+    }
+
+    for step in &MIGRATIONS[from as usize..] {
+        step(table);
+    }
+
+    table.insert(
+        "config_version".to_string(),
+        toml::Value::Integer(CONFIG_VERSION as i64),
+    );
+
+    Some(from)
+}
+
+fn migrate_v0_to_v1(table: &mut toml::Table) {
+    for (old, new) in RENAMED_KEYS {
+        if let Some(value) = table.remove(*old) {
+            table.entry(*new).or_insert(value);
+        }
+    }
+
+    if let Some(toml::Value::Boolean(true)) = table.remove("force_ext4") {
+        table
+            .entry("overlay_mode")
+            .or_insert_with(|| toml::Value::String("ext4".to_string()));
+    }
+
+    for key in OBSOLETE_KEYS {
+        table.remove(*key);
+    }
+
+    lowercase_value(table.get_mut("overlay_mode"));
+    lowercase_value(table.get_mut("default_mode"));
+
+    if let Some(toml::Value::Table(rules)) = table.get_mut("rules") {
+        for (_, rule) in rules.iter_mut() {
+            let toml::Value::Table(rule) = rule else {
+                continue;
+            };
+
+            lowercase_value(rule.get_mut("default_mode"));
+
+            if let Some(toml::Value::Table(paths)) = rule.get_mut("paths") {
+                for (_, mode) in paths.iter_mut() {
+                    lowercase_value(Some(mode));
+                }
+            }
+        }
+    }
+}
+
+fn lowercase_value(value: Option<&mut toml::Value>) {
+    if let Some(toml::Value::String(s)) = value {
+        *s = s.to_lowercase();
+    }
+}
+
+fn persist_migration(path: &Path, from: u32, original: &toml::Table, table: &toml::Table) {
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "config.toml".to_string());
+    let backup = path.with_file_name(format!("{file_name}.v{from}.bak"));
+
+    if let Err(e) = fs::copy(path, &backup) {
+        log::warn!(
+            "Failed to back up {} before migration, keeping it unchanged: {}",
+            path.display(),
+            e
+        );
+        return;
+    }
+
+    // Written as a patch so comments and key order in the file are kept.
+    let result = serde_json::to_value(original)
+        .and_then(|old| Ok(patch::diff(&old, &serde_json::to_value(table)?)))
+        .context("failed to serialize migrated config")
+        .and_then(|changes| patch::apply(path, &changes))
+        .and_then(|patched| patched.save(path));
+
+    match result {
+        Ok(()) => log::info!(
+            "Migrated {} from version {} to {} (backup: {})",
+            path.display(),
+            from,
+            CONFIG_VERSION,
+            backup.display()
+        ),
+        Err(e) => log::warn!(
+            "Failed to write migrated config {}: {:#}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+// Moves legacy data directories and upgrades config.toml in place. Boot runs this before it
+// reads the config; every other command only migrates in memory, so reading the config never
+// touches the disk.
+pub fn migrate_on_disk() {
+    let legacy: Vec<&Path> = defs::LEGACY_BASE_DIRS.iter().map(Path::new).collect();
+    migrate_files(
+        &legacy,
+        Path::new(defs::BASE_DIR),
+        Path::new(defs::CONFIG_FILE),
+    );
+}
+
+fn migrate_files(legacy_dirs: &[&Path], base: &Path, config_file: &Path) {
+    migrate_legacy_dirs(legacy_dirs, base);
+
+    if !config_file.exists() {
+        return;
+    }
+
+    match read_table(config_file) {
+        Ok(original) => {
+            let mut table = original.clone();
+            if let Some(from) = migrate_table(&mut table) {
+                persist_migration(config_file, from, &original, &table);
+            }
+        }
+        Err(e) => log::warn!("Not migrating {}: {:#}", config_file.display(), e),
+    }
+}
+
+fn migrate_legacy_dirs(legacy_dirs: &[&Path], base: &Path) {
+    for legacy in legacy_dirs {
+        if !legacy.is_dir() || is_same_dir(legacy, base) {
+            continue;
+        }
+
+        if let Err(e) = utils::ensure_dir_exists(base) {
+            log::warn!("Failed to create {}: {:#}", base.display(), e);
+            return;
+        }
+
+        let Ok(entries) = fs::read_dir(legacy) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let target = base.join(&name);
+
+            if name == "run" {
+                let _ = fs::remove_dir_all(entry.path());
+                continue;
+            }
+
+            if target.exists() {
+                log::warn!(
+                    "Not migrating {}: {} already exists",
+                    entry.path().display(),
+                    target.display()
+                );
+                continue;
+            }
+
+            match fs::rename(entry.path(), &target) {
+                Ok(()) => log::info!(
+                    "Moved legacy {} to {}",
+                    entry.path().display(),
+                    target.display()
+                ),
+                Err(e) => log::warn!("Failed to move {}: {}", entry.path().display(), e),
+            }
+        }
+
+        if fs::remove_dir(legacy).is_err() {
+            log::debug!(
+                "Legacy directory {} not empty, leaving it",
+                legacy.display()
+            );
+        }
+    }
+}
+
+fn is_same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+// A `[profiles.<name>]` table named after a built-in profile overrides it key by key.
+fn profile_overlay(table: &toml::Table, name: &str) -> Option<toml::Table> {
+    let custom = table
+        .get("profiles")
+        .and_then(|p| p.get(name))
+        .and_then(|p| p.as_table())
+        .cloned();
+    let builtin = BUILTIN_PROFILES
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .and_then(|(_, content)| content.parse::<toml::Table>().ok());
+
+    match (builtin, custom) {
+        (Some(mut builtin), Some(custom)) => {
+            let origin = PathBuf::from(format!("profiles.{name}"));
+            merge_tables(
+                &mut builtin,
+                custom,
+                "",
+                &origin,
+                &mut ConfigSources::default(),
+            );
+            Some(builtin)
+        }
+        (builtin, custom) => custom.or(builtin),
+    }
+}
+
+// Returns an error message if the profile would not produce a loadable config.
+pub fn check_profile(config: &Config, name: &str) -> Result<(), String> {
+    let mut table =
+        toml::Table::try_from(config).map_err(|e| format!("Failed to serialize config: {e}"))?;
+    table.insert("profile".to_string(), toml::Value::from(name));
+
+    if !apply_profile(&mut table, &mut ConfigSources::default()) {
+        return Err(format!("Profile '{name}' is not defined"));
+    }
+
+    toml::Value::Table(table)
+        .try_into::<Config>()
+        .map(|_| ())
+        .map_err(|e| {
+            format!(
+                "Profile '{name}' does not produce a valid config: {}",
+                e.to_string().trim()
+            )
+        })
+}
+
+fn apply_profile(table: &mut toml::Table, sources: &mut ConfigSources) -> bool {
+    let Some(name) = table
+        .get("profile")
+        .and_then(|p| p.as_str())
+        .map(str::to_string)
+    else {
+        return true;
+    };
+
+    let Some(mut overlay) = profile_overlay(table, &name) else {
+        log::warn!("Profile '{}' is not defined, ignoring it", name);
+        return false;
+    };
+
+    for key in PROFILE_KEYS {
+        overlay.remove(*key);
+    }
+
+    let origin = PathBuf::from(format!("profile:{name}"));
+    merge_tables(table, overlay, "", &origin, sources);
+
+    true
+}
+
+fn merge_tables(
+    base: &mut toml::Table,
+    overlay: toml::Table,
+    prefix: &str,
+    origin: &Path,
+    sources: &mut ConfigSources,
+) {
+    for (k, v) in overlay {
+        let key = join_key(prefix, &k);
+
+        match (base.get_mut(&k), v) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(incoming)) => {
+                merge_tables(existing, incoming, &key, origin, sources);
+            }
+            (Some(toml::Value::Array(existing)), toml::Value::Array(incoming))
+                if MERGED_ARRAY_KEYS.contains(&key.as_str()) =>
+            {
+                for item in incoming {
+                    if !existing.contains(&item) {
+                        sources.record(format!("{key}[{}]", existing.len()), origin);
+                        existing.push(item);
+                    }
+                }
+            }
+            (_, v) => {
+                sources.forget(&key);
+                sources.record_value(&key, &v, origin);
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn write(dir: &TempDir, name: &str, content: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn drop_ins_override_base_in_name_order() {
+        let dir = TempDir::new().unwrap();
+        let base = write(
+            &dir,
+            "config.toml",
+            "config_version = 1\nmountsource = \"KSU\"\ndisable_umount = false\n\
+             [tmpfs]\nsize = \"1g\"\nnr_inodes = 100\n",
+        );
+        let first = write(
+            &dir,
+            "config.d/10-first.toml",
+            "disable_umount = true\n[tmpfs]\nsize = \"2g\"\n",
+        );
+        let second = write(&dir, "config.d/20-second.toml", "[tmpfs]\nsize = \"3g\"\n");
+        write(&dir, "config.d/30-ignored.txt", "disable_umount = false\n");
+
+        let (config, sources) =
+            Config::load_layered(&base, Some(&dir.path().join("config.d"))).unwrap();
+
+        assert!(config.disable_umount);
+        assert_eq!(config.tmpfs.size.as_deref(), Some("3g"));
+        assert_eq!(config.tmpfs.nr_inodes, Some(100));
+        assert_eq!(sources.get("mountsource"), Some(base.as_path()));
+        assert_eq!(sources.get("disable_umount"), Some(first.as_path()));
+        assert_eq!(sources.get("tmpfs.size"), Some(second.as_path()));
+        assert_eq!(sources.get("tmpfs.nr_inodes"), Some(base.as_path()));
+    }
+
+    #[test]
+    fn drop_ins_append_partitions() {
+        let dir = TempDir::new().unwrap();
+        let base = write(
+            &dir,
+            "config.toml",
+            "mountsource = \"KSU\"\npartitions = \"mi_ext, my_bigball\"\n",
+        );
+        let extra = write(
+            &dir,
+            "config.d/extra.toml",
+            "partitions = [\"my_bigball\", \"my_heytap\"]\n",
+        );
+
+        let (config, sources) =
+            Config::load_layered(&base, Some(&dir.path().join("config.d"))).unwrap();
+
+        assert_eq!(config.partitions, ["mi_ext", "my_bigball", "my_heytap"]);
+        assert_eq!(sources.get("partitions[0]"), Some(base.as_path()));
+        assert_eq!(sources.get("partitions[2]"), Some(extra.as_path()));
+    }
+
+    #[test]
+    fn drop_in_replaces_a_whole_non_table_value() {
+        let dir = TempDir::new().unwrap();
+        let base = write(
+            &dir,
+            "config.toml",
+            "mountsource = \"KSU\"\nstorage_chain = [\"erofs\", \"tmpfs\"]\n",
+        );
+        write(&dir, "config.d/chain.toml", "storage_chain = [\"ext4\"]\n");
+
+        let (config, _) = Config::load_layered(&base, Some(&dir.path().join("config.d"))).unwrap();
+
+        assert_eq!(config.storage_chain, [OverlayMode::Ext4]);
+    }
+
+    #[test]
+    fn missing_base_loads_drop_ins_alone() {
+        let dir = TempDir::new().unwrap();
+        write(
+            &dir,
+            "config.d/only.toml",
+            "mountsource = \"KSU\"\ndisable_umount = true\n",
+        );
+
+        let (config, _) = Config::load_layered(
+            dir.path().join("config.toml"),
+            Some(&dir.path().join("config.d")),
+        )
+        .unwrap();
+
+        assert!(config.disable_umount);
+    }
+
+    const LEGACY_CONFIG: &str = "module_dir = \"/data/adb/modules\"\nmount_source = \"KSU\"\n\
+                                 force_ext4 = true\nverbose = true\n\
+                                 # kept\ndefault_mode = \"Magic\" # inline\n";
+
+    #[test]
+    fn migrates_v0_keys() {
+        let mut table: toml::Table = LEGACY_CONFIG.parse().unwrap();
+
+        assert_eq!(migrate_table(&mut table), Some(0));
+
+        assert_eq!(table["moduledir"].as_str(), Some("/data/adb/modules"));
+        assert_eq!(table["mountsource"].as_str(), Some("KSU"));
+        assert_eq!(table["overlay_mode"].as_str(), Some("ext4"));
+        assert_eq!(table["default_mode"].as_str(), Some("magic"));
+        assert_eq!(table["config_version"].as_integer(), Some(1));
+        for key in ["module_dir", "mount_source", "force_ext4", "verbose"] {
+            assert!(!table.contains_key(key), "{key} was not migrated");
+        }
+    }
+
+    #[test]
+    fn renamed_key_does_not_override_new_key() {
+        let mut table: toml::Table = "module_dir = \"/old\"\nmoduledir = \"/new\"\n"
+            .parse()
+            .unwrap();
+
+        migrate_table(&mut table);
+
+        assert_eq!(table["moduledir"].as_str(), Some("/new"));
+    }
+
+    #[test]
+    fn leaves_current_and_newer_versions_alone() {
+        let mut current: toml::Table = "config_version = 1\nverbose = true\n".parse().unwrap();
+        let mut newer: toml::Table = "config_version = 99\nverbose = true\n".parse().unwrap();
+
+        assert_eq!(migrate_table(&mut current), None);
+        assert_eq!(migrate_table(&mut newer), None);
+        assert!(current.contains_key("verbose"));
+        assert_eq!(newer["config_version"].as_integer(), Some(99));
+    }
+
+    #[test]
+    fn loading_migrates_in_memory_only() {
+        let dir = TempDir::new().unwrap();
+        let base = write(&dir, "config.toml", LEGACY_CONFIG);
+
+        let (config, _) = Config::load_layered(&base, None).unwrap();
+
+        assert_eq!(config.overlay_mode, OverlayMode::Ext4);
+        assert_eq!(config.default_mode, DefaultMode::Magic);
+        assert_eq!(fs::read_to_string(&base).unwrap(), LEGACY_CONFIG);
+        assert!(!dir.path().join("config.toml.v0.bak").exists());
+    }
+
+    #[test]
+    fn persisted_migration_keeps_comments_and_a_backup() {
+        let dir = TempDir::new().unwrap();
+        let base = write(&dir, "config.toml", LEGACY_CONFIG);
+        let original = read_table(&base).unwrap();
+        let mut table = original.clone();
+        let from = migrate_table(&mut table).unwrap();
+
+        persist_migration(&base, from, &original, &table);
+
+        let migrated = fs::read_to_string(&base).unwrap();
+        assert!(migrated.contains("# kept\ndefault_mode = \"magic\" # inline\n"));
+        assert_eq!(read_table(&base).unwrap(), table);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("config.toml.v0.bak")).unwrap(),
+            LEGACY_CONFIG
+        );
+    }
+
+    #[test]
+    fn boot_loads_the_config_from_a_legacy_dir() {
+        let dir = TempDir::new().unwrap();
+        let legacy = dir.path().join("Hybrid-Mount");
+        let base = dir.path().join("hybrid-mount");
+        write(&dir, "Hybrid-Mount/config.toml", LEGACY_CONFIG);
+        write(&dir, "Hybrid-Mount/run/daemon_state.json", "{}");
+        fs::create_dir_all(base.join("run")).unwrap();
+
+        let config_file = base.join("config.toml");
+        migrate_files(&[&legacy], &base, &config_file);
+
+        let (config, sources) = Config::load_layered(&config_file, None).unwrap();
+        assert_eq!(sources.get("overlay_mode"), Some(config_file.as_path()));
+        assert_eq!(config.overlay_mode, OverlayMode::Ext4);
+        assert_eq!(config.default_mode, DefaultMode::Magic);
+        assert!(!legacy.exists());
+        assert!(base.join("config.toml.v0.bak").exists());
+    }
+
+    fn rules(paths: &[(&str, MountMode)]) -> ModuleRules {
+        ModuleRules {
+            paths: paths
+                .iter()
+                .map(|(p, m)| (p.to_string(), m.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn glob_rules_match_components() {
+        let rules = rules(&[
+            ("system/lib*/libfoo.so", MountMode::Magic),
+            ("vendor/?in", MountMode::Ignore),
+        ]);
+
+        assert_eq!(rules.get_mode("system/lib64/libfoo.so"), MountMode::Magic);
+        assert_eq!(rules.get_mode("system/lib64/libbar.so"), MountMode::Overlay);
+        assert_eq!(rules.get_mode("vendor/bin/sh"), MountMode::Ignore);
+        assert_eq!(rules.get_mode("vendor/xbin"), MountMode::Overlay);
+    }
+
+    #[test]
+    fn deepest_then_most_literal_rule_wins() {
+        let rules = rules(&[
+            ("system", MountMode::Magic),
+            ("system/*/libfoo.so", MountMode::Ignore),
+            ("system/lib/libfoo.so", MountMode::Overlay),
+        ]);
+
+        assert_eq!(rules.get_mode("system/bin/sh"), MountMode::Magic);
+        assert_eq!(rules.get_mode("system/lib64/libfoo.so"), MountMode::Ignore);
+        assert_eq!(rules.get_mode("system/lib/libfoo.so"), MountMode::Overlay);
+    }
+
+    #[test]
+    fn double_star_at_start() {
+        let rules = rules(&[("**/libfoo.so", MountMode::Magic)]);
+
+        assert_eq!(rules.get_mode("libfoo.so"), MountMode::Magic);
+        assert_eq!(rules.get_mode("vendor/lib/libfoo.so"), MountMode::Magic);
+        assert_eq!(rules.get_mode("vendor/lib/libbar.so"), MountMode::Overlay);
+        assert!(rules.has_nested_rules("vendor", &MountMode::Overlay));
+        assert!(rules.has_nested_rules("system/lib", &MountMode::Overlay));
+    }
+
+    #[test]
+    fn double_star_in_middle() {
+        let rules = rules(&[("system/**/libfoo.so", MountMode::Magic)]);
+
+        assert_eq!(rules.get_mode("system/libfoo.so"), MountMode::Magic);
+        assert_eq!(
+            rules.get_mode("system/lib/arm64/libfoo.so"),
+            MountMode::Magic
+        );
+        assert_eq!(rules.get_mode("vendor/lib/libfoo.so"), MountMode::Overlay);
+        assert!(rules.has_nested_rules("system", &MountMode::Overlay));
+        assert!(rules.has_nested_rules("system/lib/arm64", &MountMode::Overlay));
+        assert!(!rules.has_nested_rules("vendor", &MountMode::Overlay));
+        assert!(!rules.has_nested_rules("vendor/lib", &MountMode::Overlay));
+    }
+
+    #[test]
+    fn double_star_at_end() {
+        let rules = rules(&[("system/app/**", MountMode::Magic)]);
+
+        assert_eq!(rules.get_mode("system/app"), MountMode::Magic);
+        assert_eq!(rules.get_mode("system/app/Foo/Foo.apk"), MountMode::Magic);
+        assert_eq!(rules.get_mode("system/priv-app/Foo"), MountMode::Overlay);
+        assert!(rules.has_nested_rules("system", &MountMode::Overlay));
+        assert!(!rules.has_nested_rules("system/priv-app", &MountMode::Overlay));
+        assert!(!rules.has_nested_rules("system/app", &MountMode::Magic));
+        assert!(!rules.has_nested_rules("vendor", &MountMode::Overlay));
+    }
+
+    #[test]
+    fn rule_paths_are_validated() {
+        assert!(validate_rule_path("system/**/lib*.so").is_ok());
+        assert!(validate_rule_path("").is_err());
+        assert!(validate_rule_path("/system").is_err());
+        assert!(validate_rule_path("system//lib").is_err());
+        assert!(validate_rule_path("system/../vendor").is_err());
+        assert!(validate_rule_path("system/lib**").is_err());
+    }
+
+    #[test]
+    fn tmpfs_size_parses_like_tmpfs() {
+        const GIB: u64 = 1 << 30;
+        let cases: &[(&str, u64, Option<u64>)] = &[
+            ("4096", 0, Some(4096)),
+            (" 8192 ", 0, Some(8192)),
+            ("1", 0, Some(4096)),
+            ("4097", 0, Some(8192)),
+            ("0", 0, None),
+            ("4k", 0, Some(4096)),
+            ("6K", 0, Some(8192)),
+            ("512m", 0, Some(512 << 20)),
+            ("2G", 0, Some(2 * GIB)),
+            ("1t", 0, Some(1 << 40)),
+            ("50%", 8 * GIB, Some(4 * GIB)),
+            ("33%", 100 * 4096, Some(33 * 4096)),
+            ("1%", 1000, Some(4096)),
+            ("150%", 2 * GIB, Some(3 * GIB)),
+            ("100%", u64::MAX / 4096 * 4096, Some(u64::MAX / 4096 * 4096)),
+            ("0%", 8 * GIB, None),
+            ("50%", 0, None),
+        ];
+        for (size, mem_total, expected) in cases {
+            let tmpfs = TmpfsConfig {
+                size: Some(size.to_string()),
+                nr_inodes: None,
+            };
+            assert_eq!(
+                tmpfs.size_bytes(*mem_total).unwrap(),
+                *expected,
+                "{size:?} of {mem_total}"
+            );
+        }
+
+        assert_eq!(TmpfsConfig::default().size_bytes(GIB).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_tmpfs_sizes_are_errors() {
+        let cases = [
+            ("", "invalid"),
+            ("lots", "invalid"),
+            ("1.5g", "invalid"),
+            ("-1", "invalid"),
+            ("10x", "invalid"),
+            ("g", "invalid"),
+            ("%", "invalid"),
+            ("-5%", "invalid"),
+            ("50 %", "invalid"),
+            ("16777216t", "too large"),
+            ("18446744073709551615", "too large"),
+            ("200%", "too large"),
+        ];
+        for (size, expected) in cases {
+            let tmpfs = TmpfsConfig {
+                size: Some(size.to_string()),
+                nr_inodes: None,
+            };
+            let err = tmpfs.size_bytes(u64::MAX).unwrap_err();
+            assert!(err.to_string().contains(expected), "{size:?}: {err}");
+        }
+    }
+
+    fn with_profile(content: &str) -> (TempDir, Config) {
+        let dir = TempDir::new().unwrap();
+        let content = format!("moduledir = {:?}\n{content}", dir.path().display());
+        let config = Config::from_table(content.parse().unwrap()).unwrap();
+        (dir, config)
+    }
+
+    #[test]
+    fn builtin_profiles_apply_and_validate() {
+        for (name, _) in BUILTIN_PROFILES {
+            let (_dir, config) = with_profile(&format!("profile = {name:?}\n"));
+
+            assert_eq!(check_profile(&config, name), Ok(()));
+            let report = crate::conf::validate::validate(&config);
+            assert!(report.valid, "{name}: {:?}", report.issues);
+            assert_eq!(report.errors().count(), 0, "{name}");
+        }
+
+        let (_dir, safe) = with_profile("profile = \"safe\"\n");
+        assert_eq!(safe.overlay_mode, OverlayMode::Tmpfs);
+        assert_eq!(safe.default_mode, DefaultMode::Magic);
+        let (_dir, full) = with_profile("profile = \"full\"\n");
+        assert_eq!(full.overlay_mode, OverlayMode::Erofs);
+        let (_dir, debug) = with_profile("profile = \"debug\"\n");
+        assert!(debug.disable_umount);
+    }
+
+    #[test]
+    fn user_profile_values_override_the_builtin_profile() {
+        let (_dir, config) = with_profile(
+            "profile = \"safe\"\noverlay_mode = \"erofs\"\ndisable_umount = true\n\
+             [profiles.safe]\noverlay_mode = \"ext4\"\n",
+        );
+
+        assert_eq!(config.overlay_mode, OverlayMode::Ext4);
+        // what neither the user's profile nor the built-in one sets stays as configured
+        assert_eq!(config.default_mode, DefaultMode::Magic);
+        assert!(config.disable_umount);
+    }
+
+    #[test]
+    fn profile_sources_name_the_profile() {
+        let dir = TempDir::new().unwrap();
+        let base = write(
+            &dir,
+            "config.toml",
+            "profile = \"mine\"\ndisable_umount = false\n[profiles.mine]\ndisable_umount = true\n",
+        );
+
+        let (config, sources) = Config::load_layered(&base, None).unwrap();
+
+        assert!(config.disable_umount);
+        assert_eq!(
+            sources.get("disable_umount"),
+            Some(Path::new("profile:mine"))
+        );
+    }
+
+    #[test]
+    fn unknown_or_broken_profiles_fail_the_check() {
+        let (_dir, config) =
+            with_profile("profile = \"nope\"\n[profiles.broken]\noverlay_mode = \"zfs\"\n");
+
+        // the plain config still loads
+        assert_eq!(config.overlay_mode, OverlayMode::default());
+        assert_eq!(
+            check_profile(&config, "nope"),
+            Err("Profile 'nope' is not defined".to_string())
+        );
+        let err = check_profile(&config, "broken").unwrap_err();
+        assert!(
+            err.starts_with("Profile 'broken' does not produce a valid config"),
+            "{err}"
+        );
+    }
+}
@@ -3,4 +3,7 @@
 
 pub mod cli;
 pub mod cli_handlers;
+pub mod condition;
 pub mod config;
+pub mod patch;
+pub mod validate;
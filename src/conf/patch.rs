@@ -0,0 +1,425 @@
+// Copyright 2026 Hybrid Mount Developers
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Value as Json};
+use toml_edit::{
+    Array, ArrayOfTables, Decor, DocumentMut, InlineTable, Item, RawString, Table, TableLike, Value,
+};
+
+use crate::{
+    conf::config::{Config, ConfigSources},
+    utils,
+};
+
+// A config file with a JSON merge patch (RFC 7396) applied. Only the patched keys are
+// rewritten, so comments and ordering elsewhere in the file survive the save.
+pub struct PatchedConfig {
+    doc: DocumentMut,
+    pub config: Config,
+}
+
+impl PatchedConfig {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("failed to create config directory")?;
+        }
+
+        utils::atomic_write(path, self.doc.to_string())
+    }
+}
+
+pub fn load_document(path: &Path) -> Result<DocumentMut> {
+    if !path.exists() {
+        return Ok(DocumentMut::new());
+    }
+
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+
+    let doc: DocumentMut = content.parse().with_context(|| {
+        format!(
+            "refusing to update {}: the existing file is not valid TOML",
+            path.display()
+        )
+    })?;
+
+    if let Err(e) = parse_document(&doc) {
+        bail!(
+            "refusing to update {}: the existing file is not a valid config: {:#}",
+            path.display(),
+            e
+        );
+    }
+
+    Ok(doc)
+}
+
+pub fn apply(path: &Path, patch: &Json) -> Result<PatchedConfig> {
+    let Json::Object(patch) = patch else {
+        bail!("config patch must be a JSON object");
+    };
+
+    let mut doc = load_document(path)?;
+    merge_into(doc.as_table_mut(), patch);
+
+    let config = parse_document(&doc).context("patched config is invalid")?;
+
+    Ok(PatchedConfig { doc, config })
+}
+
+// Builds a merge patch that turns `old` into `new`.
+pub fn diff(old: &Json, new: &Json) -> Json {
+    let (Json::Object(old), Json::Object(new)) = (old, new) else {
+        return new.clone();
+    };
+
+    let mut patch = Map::new();
+
+    for key in old.keys() {
+        if !new.contains_key(key) {
+            patch.insert(key.clone(), Json::Null);
+        }
+    }
+
+    for (key, value) in new {
+        match old.get(key) {
+            Some(existing) if existing == value => {}
+            Some(existing @ Json::Object(_)) if value.is_object() => {
+                patch.insert(key.clone(), diff(existing, value));
+            }
+            _ => {
+                patch.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    Json::Object(patch)
+}
+
+// The WebUI edits the config as show-config prints it, with drop-ins and the profile applied.
+// Only what differs from that goes into the file, and keys missing from the edit are deleted.
+pub fn from_effective(effective: &Config, edited: &Json) -> Result<Json> {
+    let effective = serde_json::to_value(effective).context("failed to convert config to JSON")?;
+
+    Ok(diff(&effective, edited))
+}
+
+// Fails when the patch touches a key that a drop-in or the profile sets on top of `base`.
+// Writing it to `base` would change nothing the next boot sees.
+pub fn ensure_not_shadowed(patch: &Json, sources: &ConfigSources, base: &Path) -> Result<()> {
+    let mut shadowed = Vec::new();
+    collect_shadowed(patch, "", sources, base, &mut shadowed);
+
+    if !shadowed.is_empty() {
+        bail!(
+            "these keys are overridden, edit them where they are set: {}",
+            shadowed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn collect_shadowed(
+    patch: &Json,
+    key: &str,
+    sources: &ConfigSources,
+    base: &Path,
+    out: &mut Vec<String>,
+) {
+    if let Json::Object(map) = patch
+        && !map.is_empty()
+    {
+        for (k, v) in map {
+            let child = if key.is_empty() {
+                k.clone()
+            } else {
+                format!("{key}.{k}")
+            };
+            collect_shadowed(v, &child, sources, base, out);
+        }
+        return;
+    }
+
+    if let Some(origin) = sources.origins_under(key).find(|origin| *origin != base) {
+        out.push(format!("{} ({})", key, origin.display()));
+    }
+}
+
+fn parse_document(doc: &DocumentMut) -> Result<Config> {
+    let table: toml::Table = doc.to_string().parse().context("failed to parse config")?;
+
+    Config::from_table(table)
+}
+
+fn merge_into(table: &mut dyn TableLike, patch: &Map<String, Json>) {
+    for (key, value) in patch {
+        match value {
+            Json::Null => remove_key(table, key),
+            Json::Object(fields) => match table.get_mut(key).and_then(Item::as_table_like_mut) {
+                Some(existing) => merge_into(existing, fields),
+                None => {
+                    table.insert(key, to_item(value));
+                }
+            },
+            _ => {
+                let mut item = to_item(value);
+                match table.get_mut(key) {
+                    // Replace in place so comments around the key and value survive.
+                    Some(existing) => {
+                        if let (Item::Value(old), Item::Value(new)) = (&*existing, &mut item) {
+                            *new.decor_mut() = old.decor().clone();
+                        }
+                        *existing = item;
+                    }
+                    None => {
+                        table.insert(key, item);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Drops the key with the comment lines right above it. Comments set apart by a blank line, such
+// as a file header, move to the next key instead.
+fn remove_key(table: &mut dyn TableLike, key: &str) {
+    let detached = table
+        .key(key)
+        .and_then(|k| k.leaf_decor().prefix())
+        .and_then(RawString::as_str)
+        .and_then(|prefix| prefix.rfind("\n\n").map(|i| prefix[..i + 2].to_string()));
+    let next = table
+        .iter()
+        .map(|(k, _)| k.to_string())
+        .skip_while(|k| k != key)
+        .nth(1);
+
+    table.remove(key);
+
+    let (Some(detached), Some(next)) = (detached, next) else {
+        return;
+    };
+
+    // A table header keeps its comments on the table, anything else on the key.
+    if let Some(Item::Table(sub)) = table.get_mut(&next) {
+        prepend_comment(sub.decor_mut(), &detached);
+    } else if let Some(mut next) = table.key_mut(&next) {
+        prepend_comment(next.leaf_decor_mut(), &detached);
+    }
+}
+
+fn prepend_comment(decor: &mut Decor, comment: &str) {
+    let prefix = decor
+        .prefix()
+        .and_then(RawString::as_str)
+        .unwrap_or_default();
+    let merged = format!("{}{}", comment, prefix);
+    decor.set_prefix(merged);
+}
+
+fn to_item(value: &Json) -> Item {
+    match value {
+        Json::Object(fields) => {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            for (key, value) in fields.iter().filter(|(_, v)| !v.is_null()) {
+                table.insert(key, to_item(value));
+            }
+            Item::Table(table)
+        }
+        Json::Array(items) if !items.is_empty() && items.iter().all(Json::is_object) => {
+            let mut tables = ArrayOfTables::new();
+            for item in items {
+                if let Item::Table(table) = to_item(item) {
+                    tables.push(table);
+                }
+            }
+            Item::ArrayOfTables(tables)
+        }
+        _ => Item::Value(to_value(value)),
+    }
+}
+
+fn to_value(value: &Json) -> Value {
+    match value {
+        Json::Null => Value::from(""),
+        Json::Bool(b) => Value::from(*b),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => Value::from(i),
+            None => Value::from(n.as_f64().unwrap_or_default()),
+        },
+        Json::String(s) => Value::from(s.as_str()),
+        Json::Array(items) => Value::Array(
+            items
+                .iter()
+                .filter(|v| !v.is_null())
+                .map(to_value)
+                .collect::<Array>(),
+        ),
+        Json::Object(fields) => Value::InlineTable(
+            fields
+                .iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.clone(), to_value(v)))
+                .collect::<InlineTable>(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn config_file(dir: &TempDir, content: &str) -> std::path::PathBuf {
+        let path = dir.path().join("config.toml");
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn patched(dir: &TempDir, content: &str, patch: Json) -> String {
+        let path = config_file(dir, content);
+        apply(&path, &patch).unwrap().save(&path).unwrap();
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn diff_builds_a_minimal_merge_patch() {
+        let old = json!({
+            "a": 1,
+            "b": { "c": true, "d": "x" },
+            "gone": 2,
+            "list": [1, 2],
+        });
+        let new = json!({
+            "a": 1,
+            "b": { "c": false, "d": "x" },
+            "list": [1, 2, 3],
+            "added": "y",
+        });
+
+        assert_eq!(
+            diff(&old, &new),
+            json!({
+                "b": { "c": false },
+                "gone": null,
+                "list": [1, 2, 3],
+                "added": "y",
+            })
+        );
+        assert_eq!(diff(&old, &old), json!({}));
+    }
+
+    #[test]
+    fn apply_keeps_comments_and_order() {
+        let dir = TempDir::new().unwrap();
+        let content = "# header\n\nmountsource = \"KSU\" # source\n\n# umount\n\
+                       disable_umount = false # inline\n\n[tmpfs]\n# limit\nsize = \"1g\"\n";
+
+        let saved = patched(
+            &dir,
+            content,
+            json!({ "disable_umount": true, "tmpfs": { "nr_inodes": 10 } }),
+        );
+
+        assert_eq!(
+            saved,
+            "# header\n\nmountsource = \"KSU\" # source\n\n# umount\n\
+             disable_umount = true # inline\n\n[tmpfs]\n# limit\nsize = \"1g\"\nnr_inodes = 10\n"
+        );
+    }
+
+    #[test]
+    fn removing_a_key_keeps_detached_comments() {
+        let dir = TempDir::new().unwrap();
+        let content = "# header\n\n# about moduledir\nmoduledir = \"/data/adb/modules\"\n\
+                       mountsource = \"KSU\"\n";
+
+        let saved = patched(&dir, content, json!({ "moduledir": null }));
+
+        assert_eq!(saved, "# header\n\nmountsource = \"KSU\"\n");
+    }
+
+    #[test]
+    fn removing_the_last_value_moves_the_header_to_the_next_table() {
+        let dir = TempDir::new().unwrap();
+        let content = "# header\n\nmountsource = \"KSU\"\n\n[tmpfs]\nsize = \"1g\"\n";
+
+        let saved = patched(&dir, content, json!({ "mountsource": null }));
+
+        assert_eq!(saved, "# header\n\n\n[tmpfs]\nsize = \"1g\"\n");
+    }
+
+    #[test]
+    fn apply_refuses_an_invalid_result() {
+        let dir = TempDir::new().unwrap();
+        let path = config_file(&dir, "mountsource = \"KSU\"\n");
+
+        assert!(apply(&path, &json!({ "overlay_mode": "zfs" })).is_err());
+        assert!(apply(&path, &json!([1])).is_err());
+    }
+
+    #[test]
+    fn saving_the_effective_config_writes_only_edits() {
+        let dir = TempDir::new().unwrap();
+        let path = config_file(
+            &dir,
+            "mountsource = \"KSU\"\npartitions = [\"my_product\"]\n",
+        );
+        fs::create_dir(dir.path().join("config.d")).unwrap();
+        fs::write(
+            dir.path().join("config.d/10-umount.toml"),
+            "disable_umount = true\n",
+        )
+        .unwrap();
+
+        let (effective, _) =
+            Config::load_layered(&path, Some(&dir.path().join("config.d"))).unwrap();
+        let mut edited = serde_json::to_value(&effective).unwrap();
+        edited["allow_umount_coexistence"] = json!(true);
+        edited.as_object_mut().unwrap().remove("partitions");
+
+        let patch = from_effective(&effective, &edited).unwrap();
+        assert_eq!(
+            patch,
+            json!({ "allow_umount_coexistence": true, "partitions": null })
+        );
+
+        apply(&path, &patch).unwrap().save(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "mountsource = \"KSU\"\nallow_umount_coexistence = true\n"
+        );
+    }
+
+    #[test]
+    fn edits_to_profile_values_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = config_file(
+            &dir,
+            "profile = \"slow\"\n[profiles.slow]\ndisable_umount = true\n",
+        );
+
+        let (effective, sources) = Config::load_layered(&path, None).unwrap();
+        let mut edited = serde_json::to_value(&effective).unwrap();
+        edited["mountsource"] = json!("APatch");
+        let patch = from_effective(&effective, &edited).unwrap();
+        assert!(ensure_not_shadowed(&patch, &sources, &path).is_ok());
+
+        edited["disable_umount"] = json!(false);
+        let patch = from_effective(&effective, &edited).unwrap();
+        let err = ensure_not_shadowed(&patch, &sources, &path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "these keys are overridden, edit them where they are set: \
+             disable_umount (profile:slow)"
+        );
+    }
+}
@@ -0,0 +1,516 @@
+// Copyright 2026 Hybrid Mount Developers
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashSet, path::Path};
+
+use procfs::{Current, Meminfo};
+use serde::Serialize;
+
+use crate::{
+    conf::{
+        condition,
+        config::{self, Config, OverlayMode},
+    },
+    core::{inventory, storage},
+    defs,
+    sys::mount::detect_mount_source,
+    utils,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    ParseError,
+    UnknownPartition,
+    InvalidModuleId,
+    UnknownModule,
+    InvalidPathRule,
+    InvalidCondition,
+    InvalidProfile,
+    InvalidErofsOption,
+    InvalidStorageChain,
+    InvalidTmpfsOption,
+    MountSourceMismatch,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueLevel {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigIssue {
+    pub kind: IssueKind,
+    pub level: IssueLevel,
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl ValidationReport {
+    pub fn parse_error(message: String) -> Self {
+        Self {
+            valid: false,
+            issues: vec![ConfigIssue {
+                kind: IssueKind::ParseError,
+                level: IssueLevel::Error,
+                field: String::new(),
+                message,
+            }],
+        }
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &ConfigIssue> {
+        self.issues.iter().filter(|i| i.level == IssueLevel::Error)
+    }
+
+    fn push(&mut self, kind: IssueKind, level: IssueLevel, field: String, message: String) {
+        self.issues.push(ConfigIssue {
+            kind,
+            level,
+            field,
+            message,
+        });
+    }
+}
+
+pub fn validate(config: &Config) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    for (i, partition) in config.partitions.iter().enumerate() {
+        let known = defs::BUILTIN_PARTITIONS.contains(&partition.as_str());
+        let present = !partition.contains('/') && Path::new("/").join(partition).is_dir();
+
+        if !known && !present {
+            report.push(
+                IssueKind::UnknownPartition,
+                IssueLevel::Warning,
+                format!("partitions[{i}]"),
+                format!(
+                    "Partition '{partition}' is not built in and does not exist on this device"
+                ),
+            );
+        }
+    }
+
+    let mut partition_keys: Vec<&String> = config.partition_modes.keys().collect();
+    partition_keys.sort();
+
+    for partition in partition_keys {
+        if !defs::BUILTIN_PARTITIONS.contains(&partition.as_str())
+            && !config.partitions.contains(partition)
+        {
+            report.push(
+                IssueKind::UnknownPartition,
+                IssueLevel::Warning,
+                format!("partition_modes.{partition}"),
+                format!("'{partition}' is not a managed partition, so this mode never applies"),
+            );
+        }
+    }
+
+    validate_storage_chain(config, &mut report);
+    validate_tmpfs(config, &mut report);
+    validate_erofs(config, &mut report);
+
+    let installed: HashSet<String> = match inventory::scan(&config.moduledir, config) {
+        Ok(modules) => modules.into_iter().map(|m| m.id).collect(),
+        Err(e) => {
+            log::warn!("Failed to scan modules for validation: {:#}", e);
+            HashSet::new()
+        }
+    };
+
+    let mut rule_ids: Vec<&String> = config.rules.keys().collect();
+    rule_ids.sort();
+
+    for id in rule_ids {
+        let field = format!("rules.{id}");
+
+        if let Err(e) = utils::validate_module_id(id) {
+            report.push(
+                IssueKind::InvalidModuleId,
+                IssueLevel::Error,
+                field,
+                e.to_string(),
+            );
+            continue;
+        }
+
+        if !installed.contains(id) {
+            report.push(
+                IssueKind::UnknownModule,
+                IssueLevel::Warning,
+                field.clone(),
+                format!("Module '{id}' is not installed or not enabled"),
+            );
+        }
+
+        let mut keys: Vec<(String, &String)> = config.rules[id]
+            .paths
+            .keys()
+            .map(|key| (format!("{field}.paths.{key}"), key))
+            .collect();
+
+        for (i, block) in config.rules[id].when.iter().enumerate() {
+            let block_field = format!("{field}.when[{i}]");
+
+            for (name, expr) in block.expressions() {
+                if let Err(e) = condition::validate_expr(expr) {
+                    report.push(
+                        IssueKind::InvalidCondition,
+                        IssueLevel::Error,
+                        format!("{block_field}.{name}"),
+                        e,
+                    );
+                }
+            }
+
+            keys.extend(
+                block
+                    .paths
+                    .keys()
+                    .map(|key| (format!("{block_field}.paths.{key}"), key)),
+            );
+        }
+
+        keys.sort();
+
+        for (field, key) in keys {
+            if let Err(e) = config::validate_rule_path(key) {
+                report.push(IssueKind::InvalidPathRule, IssueLevel::Error, field, e);
+                continue;
+            }
+
+            let first = key.split('/').next().unwrap_or_default();
+            if !first.contains(['*', '?'])
+                && !defs::BUILTIN_PARTITIONS.contains(&first)
+                && !config.partitions.iter().any(|p| p == first)
+            {
+                report.push(
+                    IssueKind::InvalidPathRule,
+                    IssueLevel::Warning,
+                    field,
+                    format!("'{first}' is not a managed partition, so this rule never applies"),
+                );
+            }
+        }
+    }
+
+    if let Some(name) = &config.profile
+        && !config.profiles.contains_key(name)
+        && let Err(e) = config::check_profile(config, name)
+    {
+        report.push(
+            IssueKind::InvalidProfile,
+            IssueLevel::Error,
+            "profile".to_string(),
+            e,
+        );
+    }
+
+    for name in config.profiles.keys() {
+        if let Err(e) = config::check_profile(config, name) {
+            report.push(
+                IssueKind::InvalidProfile,
+                IssueLevel::Error,
+                format!("profiles.{name}"),
+                e,
+            );
+        }
+    }
+
+    let detected = detect_mount_source();
+    if config.mountsource != detected {
+        report.push(
+            IssueKind::MountSourceMismatch,
+            IssueLevel::Warning,
+            "mountsource".to_string(),
+            format!(
+                "Mount source '{}' does not match detected '{}'",
+                config.mountsource, detected
+            ),
+        );
+    }
+
+    report.valid = !report.issues.iter().any(|i| i.level == IssueLevel::Error);
+
+    report
+}
+
+fn validate_storage_chain(config: &Config, report: &mut ValidationReport) {
+    for (i, mode) in config.storage_chain.iter().enumerate() {
+        if config.storage_chain[..i].contains(mode) {
+            report.push(
+                IssueKind::InvalidStorageChain,
+                IssueLevel::Warning,
+                format!("storage_chain[{i}]"),
+                format!(
+                    "'{}' is listed more than once, only the first entry is tried",
+                    format!("{mode:?}").to_lowercase()
+                ),
+            );
+        }
+    }
+}
+
+fn validate_tmpfs(config: &Config, report: &mut ValidationReport) {
+    let mem_total = Meminfo::current().map(|m| m.mem_total).unwrap_or(0);
+
+    match config.tmpfs.size_bytes(mem_total) {
+        Err(e) => report.push(
+            IssueKind::InvalidTmpfsOption,
+            IssueLevel::Error,
+            "tmpfs.size".to_string(),
+            format!("{:#}", e),
+        ),
+        Ok(Some(size)) if mem_total > 0 && size > mem_total => report.push(
+            IssueKind::InvalidTmpfsOption,
+            IssueLevel::Warning,
+            "tmpfs.size".to_string(),
+            format!(
+                "{} is more than the {} of RAM on this device",
+                storage::format_size(size),
+                storage::format_size(mem_total)
+            ),
+        ),
+        Ok(_) => {}
+    }
+}
+
+fn validate_erofs(config: &Config, report: &mut ValidationReport) {
+    let erofs = &config.erofs;
+
+    if let Some(level) = erofs.level {
+        match erofs.compressor.level_range() {
+            None => report.push(
+                IssueKind::InvalidErofsOption,
+                IssueLevel::Error,
+                "erofs.level".to_string(),
+                format!("{:?} compression has no level", erofs.compressor).to_lowercase(),
+            ),
+            Some((min, max)) if level < min || level > max => report.push(
+                IssueKind::InvalidErofsOption,
+                IssueLevel::Error,
+                "erofs.level".to_string(),
+                format!(
+                    "Level {level} is out of range for {} ({min}-{max})",
+                    erofs.compressor.name().unwrap_or_default()
+                ),
+            ),
+            Some(_) => {}
+        }
+    }
+
+    if let Some(size) = erofs.pcluster_size
+        && (size < 4096 || !size.is_power_of_two())
+    {
+        report.push(
+            IssueKind::InvalidErofsOption,
+            IssueLevel::Error,
+            "erofs.pcluster_size".to_string(),
+            format!("pcluster_size {size} must be a power of two of at least 4096"),
+        );
+    }
+
+    if !config
+        .effective_storage_chain()
+        .contains(&OverlayMode::Erofs)
+    {
+        return;
+    }
+
+    if let Err(e) = storage::erofs_builder(erofs) {
+        report.push(
+            IssueKind::InvalidErofsOption,
+            IssueLevel::Warning,
+            "erofs".to_string(),
+            format!("{:#}, storage will fall back from EROFS", e),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // Validates `content` with an empty module directory, so installed modules stay out of it.
+    fn report(content: &str) -> (TempDir, ValidationReport) {
+        let dir = TempDir::new().unwrap();
+        let content = format!("moduledir = {:?}\n{content}", dir.path().display());
+        let config = Config::from_table(content.parse().unwrap()).unwrap();
+        let report = validate(&config);
+        (dir, report)
+    }
+
+    fn issues(content: &str, kind: IssueKind) -> Vec<ConfigIssue> {
+        let (_dir, report) = report(content);
+        report
+            .issues
+            .into_iter()
+            .filter(|i| i.kind == kind)
+            .collect()
+    }
+
+    fn fields(issues: &[ConfigIssue]) -> Vec<(&str, IssueLevel)> {
+        issues.iter().map(|i| (i.field.as_str(), i.level)).collect()
+    }
+
+    #[test]
+    fn default_config_reports_nothing() {
+        let (_dir, report) = report("");
+
+        assert!(report.valid);
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn parse_error_is_an_invalid_report() {
+        let report = ValidationReport::parse_error("expected `=`".to_string());
+
+        assert!(!report.valid);
+        assert_eq!(report.errors().count(), 1);
+        assert_eq!(report.issues[0].kind, IssueKind::ParseError);
+    }
+
+    #[test]
+    fn unknown_partitions_are_warnings() {
+        let issues = issues(
+            "partitions = [\"no_such_partition\"]\n\
+             [partition_modes]\nvendor = \"magic\"\nodm_dlkm2 = \"magic\"\n",
+            IssueKind::UnknownPartition,
+        );
+
+        assert_eq!(
+            fields(&issues),
+            [
+                ("partitions[0]", IssueLevel::Warning),
+                ("partition_modes.odm_dlkm2", IssueLevel::Warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_module_id_is_an_error() {
+        let issues = issues(
+            "[rules.\"1mod\"]\npriority = 1\n",
+            IssueKind::InvalidModuleId,
+        );
+
+        assert_eq!(fields(&issues), [("rules.1mod", IssueLevel::Error)]);
+    }
+
+    #[test]
+    fn rules_for_missing_modules_are_warnings() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("installed")).unwrap();
+        let content = format!(
+            "moduledir = {:?}\n[rules.installed]\npriority = 1\n[rules.missing]\npriority = 1\n",
+            dir.path().display()
+        );
+        let report = validate(&Config::from_table(content.parse().unwrap()).unwrap());
+
+        let unknown: Vec<_> = report
+            .issues
+            .into_iter()
+            .filter(|i| i.kind == IssueKind::UnknownModule)
+            .collect();
+        assert_eq!(fields(&unknown), [("rules.missing", IssueLevel::Warning)]);
+        assert!(report.valid);
+    }
+
+    #[test]
+    fn path_rules_are_checked_in_rules_and_when_blocks() {
+        let issues = issues(
+            "[rules.mod.paths]\n\"system/../etc\" = \"magic\"\n\"data/app\" = \"magic\"\n\
+             \"*/app\" = \"magic\"\n\
+             [[rules.mod.when]]\npaths = { \"/system\" = \"ignore\" }\n",
+            IssueKind::InvalidPathRule,
+        );
+
+        assert_eq!(
+            fields(&issues),
+            [
+                ("rules.mod.paths.data/app", IssueLevel::Warning),
+                ("rules.mod.paths.system/../etc", IssueLevel::Error),
+                ("rules.mod.when[0].paths./system", IssueLevel::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_conditions_are_errors() {
+        let issues = issues(
+            "[[rules.mod.when]]\nkernel = \">=abc\"\nprops = { \"ro.sdk\" = \"=\" }\n",
+            IssueKind::InvalidCondition,
+        );
+
+        assert_eq!(
+            fields(&issues),
+            [
+                ("rules.mod.when[0].props.ro.sdk", IssueLevel::Error),
+                ("rules.mod.when[0].kernel", IssueLevel::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn undefined_profile_is_an_error() {
+        let (_dir, report) = report("profile = \"nope\"\n");
+
+        assert!(!report.valid);
+        assert_eq!(fields(&report.issues), [("profile", IssueLevel::Error)]);
+        assert_eq!(report.issues[0].kind, IssueKind::InvalidProfile);
+    }
+
+    #[test]
+    fn erofs_options_out_of_range_are_errors() {
+        let issues = issues(
+            "[erofs]\ncompressor = \"lz4hc\"\nlevel = 99\npcluster_size = 1000\n",
+            IssueKind::InvalidErofsOption,
+        );
+
+        assert_eq!(
+            fields(&issues),
+            [
+                ("erofs.level", IssueLevel::Error),
+                ("erofs.pcluster_size", IssueLevel::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_storage_backend_is_a_warning() {
+        let issues = issues(
+            "storage_chain = [\"tmpfs\", \"ext4\", \"tmpfs\"]\n",
+            IssueKind::InvalidStorageChain,
+        );
+
+        assert_eq!(fields(&issues), [("storage_chain[2]", IssueLevel::Warning)]);
+    }
+
+    #[test]
+    fn unparsable_tmpfs_size_is_an_error() {
+        let issues = issues("[tmpfs]\nsize = \"lots\"\n", IssueKind::InvalidTmpfsOption);
+
+        assert_eq!(fields(&issues), [("tmpfs.size", IssueLevel::Error)]);
+    }
+
+    #[test]
+    fn foreign_mount_source_is_a_warning() {
+        let issues = issues("mountsource = \"Magisk\"\n", IssueKind::MountSourceMismatch);
+
+        assert_eq!(fields(&issues), [("mountsource", IssueLevel::Warning)]);
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     fs::{self},
     io::{BufRead, BufReader},
     path::Path,
@@ -13,7 +13,7 @@ use serde::Serialize;
 use super::scanner as inventory;
 use crate::{
     conf::config::{self, MountMode},
-    core::state::RuntimeState,
+    core::{state::RuntimeState, storage::StorageKind},
     defs, utils,
 };
 
@@ -64,18 +64,21 @@ struct ModuleInfo {
     mode: String,
     is_mounted: bool,
     rules: config::ModuleRules,
+    partition_modes: BTreeMap<String, MountMode>,
 }
 
 impl ModuleInfo {
-    fn new(m: inventory::Module, mounted_set: &HashSet<&str>) -> Self {
+    fn new(m: inventory::Module, mounted_set: &HashSet<&str>, cfg: &config::Config) -> Self {
         let prop = ModuleProp::from(m.source_path.join("module.prop").as_path());
 
-        let mode_str = match m.rules.default_mode {
+        let mode_str = match m.rules.effective_default() {
             MountMode::Overlay => "auto",
             MountMode::Magic => "magic",
             MountMode::Ignore => "ignore",
         };
 
+        let partition_modes = m.partition_modes(cfg);
+
         Self {
             is_mounted: mounted_set.contains(m.id.as_str()),
             id: m.id,
@@ -85,6 +88,7 @@ impl ModuleInfo {
             description: prop.description,
             mode: mode_str.to_string(),
             rules: m.rules,
+            partition_modes,
         }
     }
 }
@@ -103,7 +107,7 @@ pub fn print_list(config: &config::Config) -> Result<()> {
 
     let infos: Vec<ModuleInfo> = modules
         .into_iter()
-        .map(|m| ModuleInfo::new(m, &mounted_ids))
+        .map(|m| ModuleInfo::new(m, &mounted_ids, config))
         .collect();
 
     println!("{}", serde_json::to_string(&infos)?);
@@ -111,28 +115,26 @@ pub fn print_list(config: &config::Config) -> Result<()> {
     Ok(())
 }
 
-pub fn update_description(storage_mode: &str, overlay_count: usize, magic_count: usize) {
+pub fn update_description(storage_mode: StorageKind, overlay_count: usize, magic_count: usize) {
     let prop_path = Path::new(defs::MODULE_PROP_FILE);
 
     if !prop_path.exists() {
         return;
     }
 
-    let mode_str = match storage_mode {
-        "tmpfs" => "Tmpfs",
-        "erofs" => "EROFS",
-        _ => "Ext4",
-    };
-
     let status_emoji = match storage_mode {
-        "tmpfs" => "🐾",
-        "erofs" => "🚀",
-        _ => "💿",
+        StorageKind::Tmpfs => "🐾",
+        StorageKind::Erofs => "🚀",
+        StorageKind::Squashfs => "📦",
+        StorageKind::Ext4 => "💿",
     };
 
     let desc_text = format!(
         "description=😋 运行中喵～ ({}) {} | Overlay: {} | Magic: {}",
-        mode_str, status_emoji, overlay_count, magic_count
+        storage_mode.label(),
+        status_emoji,
+        overlay_count,
+        magic_count
     );
 
     let lines: Vec<String> = match fs::File::open(prop_path) {
@@ -1,5 +1,6 @@
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
     fs,
     path::{Path, PathBuf},
 };
@@ -9,22 +10,54 @@ use rayon::prelude::*;
 use serde::Deserialize;
 
 use crate::{
-    conf::config::{self, ModuleRules, MountMode},
+    conf::{
+        condition::ConditionalRules,
+        config::{self, ModuleRules, MountMode},
+    },
     defs,
+    sys::device::DeviceFacts,
 };
 
 #[derive(Deserialize)]
 struct PartialRules {
     default_mode: Option<MountMode>,
     paths: Option<HashMap<String, MountMode>>,
+    priority: Option<i32>,
+    when: Option<Vec<ConditionalRules>>,
 }
 
-fn load_module_rules(module_dir: &Path, module_id: &str, cfg: &config::Config) -> ModuleRules {
+// Applies every matching block in order.
+fn apply_conditional(
+    rules: &mut ModuleRules,
+    blocks: &[ConditionalRules],
+    module_id: &str,
+    facts: &DeviceFacts,
+) {
+    for block in blocks.iter().filter(|block| block.matches(facts)) {
+        log::debug!("Conditional rules matched for module '{}'", module_id);
+        if block.default_mode.is_some() {
+            rules.default_mode = block.default_mode.clone();
+        }
+        rules.paths.extend(block.paths.clone());
+        if block.priority.is_some() {
+            rules.priority = block.priority;
+        }
+    }
+}
+
+fn load_module_rules(
+    module_dir: &Path,
+    module_id: &str,
+    cfg: &config::Config,
+    facts: &DeviceFacts,
+) -> ModuleRules {
+    // Partition modes sit between the global default and a module's own default.
     let mut rules = ModuleRules {
-        default_mode: match cfg.default_mode {
+        global_default: match cfg.default_mode {
             config::DefaultMode::Overlay => MountMode::Overlay,
             config::DefaultMode::Magic => MountMode::Magic,
         },
+        partition_defaults: cfg.partition_modes.clone(),
         ..Default::default()
     };
 
@@ -34,12 +67,16 @@ fn load_module_rules(module_dir: &Path, module_id: &str, cfg: &config::Config) -
         match fs::read_to_string(&internal_config) {
             Ok(content) => match serde_json::from_str::<PartialRules>(&content) {
                 Ok(partial) => {
-                    if let Some(mode) = partial.default_mode {
-                        rules.default_mode = mode;
+                    if partial.default_mode.is_some() {
+                        rules.default_mode = partial.default_mode;
                     }
                     if let Some(paths) = partial.paths {
                         rules.paths = paths;
                     }
+                    rules.priority = partial.priority;
+                    if let Some(when) = partial.when {
+                        apply_conditional(&mut rules, &when, module_id, facts);
+                    }
                 }
                 Err(e) => {
                     log::warn!("Failed to parse rules for module '{}': {}", module_id, e)
@@ -49,9 +86,17 @@ fn load_module_rules(module_dir: &Path, module_id: &str, cfg: &config::Config) -
         }
     }
 
+    // Config rules only override what they set, so a rule with just a priority keeps the
+    // module's own default and the partition modes.
     if let Some(global_rules) = cfg.rules.get(module_id) {
-        rules.default_mode = global_rules.default_mode.clone();
+        if global_rules.default_mode.is_some() {
+            rules.default_mode = global_rules.default_mode.clone();
+        }
         rules.paths.extend(global_rules.paths.clone());
+        if global_rules.priority.is_some() {
+            rules.priority = global_rules.priority;
+        }
+        apply_conditional(&mut rules, &global_rules.when, module_id, facts);
     }
 
     rules
@@ -64,12 +109,45 @@ pub struct Module {
     pub rules: ModuleRules,
 }
 
+impl Module {
+    pub fn priority(&self) -> i32 {
+        self.rules.priority.unwrap_or(0)
+    }
+
+    pub fn partition_modes(&self, cfg: &config::Config) -> BTreeMap<String, MountMode> {
+        let Ok(entries) = fs::read_dir(&self.source_path) else {
+            return BTreeMap::new();
+        };
+
+        entries
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| {
+                defs::BUILTIN_PARTITIONS.contains(&name.as_str()) || cfg.partitions.contains(name)
+            })
+            .map(|name| {
+                let mode = self.rules.get_mode(&name);
+                (name, mode)
+            })
+            .collect()
+    }
+}
+
+// Orders modules from the highest precedence down; ties fall back to reverse ID.
+pub fn precedence(a: &Module, b: &Module) -> Ordering {
+    b.priority()
+        .cmp(&a.priority())
+        .then_with(|| b.id.cmp(&a.id))
+}
+
 pub fn scan(source_dir: &Path, cfg: &config::Config) -> Result<Vec<Module>> {
     if !source_dir.exists() {
         return Ok(Vec::new());
     }
 
     let dir_entries = fs::read_dir(source_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    let facts = DeviceFacts::default();
 
     let mut modules: Vec<Module> = dir_entries
         .into_par_iter()
@@ -96,7 +174,7 @@ pub fn scan(source_dir: &Path, cfg: &config::Config) -> Result<Vec<Module>> {
                 return None;
             }
 
-            let rules = load_module_rules(&path, &id, cfg);
+            let rules = load_module_rules(&path, &id, cfg, &facts);
 
             Some(Module {
                 id,
@@ -106,7 +184,164 @@ pub fn scan(source_dir: &Path, cfg: &config::Config) -> Result<Vec<Module>> {
         })
         .collect();
 
-    modules.sort_by(|a, b| b.id.cmp(&a.id));
+    modules.sort_by(precedence);
 
     Ok(modules)
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn config(content: &str) -> config::Config {
+        config::Config::from_table(content.parse().unwrap()).unwrap()
+    }
+
+    // A device with only a `vendor` partition, SDK 34 and a 5.10 kernel.
+    fn facts(dir: &Path) -> DeviceFacts {
+        let props = dir.join("build.prop");
+        let release = dir.join("osrelease");
+        fs::write(&props, "ro.build.version.sdk=34\n").unwrap();
+        fs::write(&release, "5.10.198-android13-4\n").unwrap();
+        fs::create_dir(dir.join("vendor")).unwrap();
+        DeviceFacts::new(Some(props), &release, dir)
+    }
+
+    fn rules_for(module_rules: Option<&str>, cfg: &config::Config) -> ModuleRules {
+        let dir = TempDir::new().unwrap();
+        let module = dir.path().join("mod");
+        fs::create_dir(&module).unwrap();
+        if let Some(content) = module_rules {
+            fs::write(module.join("hybrid_rules.json"), content).unwrap();
+        }
+        load_module_rules(&module, "mod", cfg, &facts(dir.path()))
+    }
+
+    #[test]
+    fn partition_modes_sit_between_global_and_module_default() {
+        let cfg = config("default_mode = \"magic\"\n[partition_modes]\nvendor = \"overlay\"\n");
+
+        let rules = rules_for(None, &cfg);
+        assert_eq!(rules.get_mode("vendor/lib"), MountMode::Overlay);
+        assert_eq!(rules.get_mode("system/lib"), MountMode::Magic);
+
+        let rules = rules_for(Some(r#"{"default_mode": "ignore"}"#), &cfg);
+        assert_eq!(rules.get_mode("vendor/lib"), MountMode::Ignore);
+    }
+
+    #[test]
+    fn priority_only_rule_keeps_module_and_partition_defaults() {
+        let cfg = config(
+            "[partition_modes]\nvendor = \"magic\"\n[rules.mod]\npriority = 5\n\
+             [rules.mod.paths]\n\"system/app\" = \"ignore\"\n",
+        );
+
+        let rules = rules_for(None, &cfg);
+        assert_eq!(rules.priority, Some(5));
+        assert_eq!(rules.default_mode, None);
+        assert_eq!(rules.get_mode("vendor/lib"), MountMode::Magic);
+        assert_eq!(rules.get_mode("system/app"), MountMode::Ignore);
+
+        let rules = rules_for(Some(r#"{"default_mode": "magic"}"#), &cfg);
+        assert_eq!(rules.effective_default(), MountMode::Magic);
+        assert_eq!(rules.get_mode("system/lib"), MountMode::Magic);
+    }
+
+    #[test]
+    fn config_rule_default_overrides_module_and_partition_defaults() {
+        let cfg = config(
+            "[partition_modes]\nvendor = \"magic\"\n[rules.mod]\ndefault_mode = \"overlay\"\n",
+        );
+
+        let rules = rules_for(Some(r#"{"default_mode": "ignore"}"#), &cfg);
+        assert_eq!(rules.get_mode("vendor/lib"), MountMode::Overlay);
+        assert_eq!(rules.get_mode("system/lib"), MountMode::Overlay);
+    }
+
+    #[test]
+    fn unset_default_is_not_written_back() {
+        let cfg = config("[rules.mod]\npriority = 1\n");
+        let rules = rules_for(None, &cfg);
+
+        let json = serde_json::to_value(&rules).unwrap();
+        assert!(json.get("default_mode").is_none());
+    }
+
+    #[test]
+    fn matching_when_block_without_default_keeps_partition_modes() {
+        let cfg = config(
+            "[partition_modes]\nvendor = \"magic\"\n[[rules.mod.when]]\npartitions = [\"vendor\"]\n\
+             priority = 7\npaths = { \"system/app\" = \"ignore\" }\n",
+        );
+
+        let rules = rules_for(None, &cfg);
+        assert_eq!(rules.priority, Some(7));
+        assert_eq!(rules.default_mode, None);
+        assert_eq!(rules.get_mode("vendor/lib"), MountMode::Magic);
+        assert_eq!(rules.get_mode("system/app"), MountMode::Ignore);
+        assert_eq!(rules.get_mode("system/lib"), MountMode::Overlay);
+    }
+
+    #[test]
+    fn when_blocks_apply_only_when_they_match() {
+        let cfg = config(
+            "[[rules.mod.when]]\npartitions = [\"no_such_partition\"]\ndefault_mode = \"ignore\"\n\
+             [[rules.mod.when]]\npartitions = [\"vendor\"]\ndefault_mode = \"magic\"\n",
+        );
+
+        let rules = rules_for(None, &cfg);
+        assert_eq!(rules.default_mode, Some(MountMode::Magic));
+    }
+
+    #[test]
+    fn when_blocks_match_props_and_kernel() {
+        let cfg = config(
+            "[[rules.mod.when]]\nprops = { \"ro.build.version.sdk\" = \"<34\" }\n\
+             default_mode = \"ignore\"\n\
+             [[rules.mod.when]]\nprops = { \"ro.build.version.sdk\" = \">=34\" }\n\
+             kernel = \"5.10\"\ndefault_mode = \"magic\"\n",
+        );
+
+        let rules = rules_for(None, &cfg);
+        assert_eq!(rules.default_mode, Some(MountMode::Magic));
+    }
+
+    #[test]
+    fn when_block_in_rules_file_merges_set_fields_only() {
+        let cfg = config("default_mode = \"magic\"\n[partition_modes]\nvendor = \"overlay\"\n");
+
+        let rules = rules_for(
+            Some(r#"{"priority": 1, "when": [{"partitions": ["vendor"], "priority": 3}]}"#),
+            &cfg,
+        );
+        assert_eq!(rules.priority, Some(3));
+        assert_eq!(rules.get_mode("vendor/lib"), MountMode::Overlay);
+        assert_eq!(rules.get_mode("system/lib"), MountMode::Magic);
+    }
+
+    #[test]
+    fn precedence_orders_by_priority_then_reverse_id() {
+        let module = |id: &str, priority| Module {
+            id: id.to_string(),
+            source_path: PathBuf::new(),
+            rules: ModuleRules {
+                priority,
+                ..Default::default()
+            },
+        };
+        let mut modules = [
+            module("a", None),
+            module("b", Some(5)),
+            module("c", None),
+            module("d", Some(-1)),
+            module("e", Some(5)),
+        ];
+
+        modules.sort_by(precedence);
+
+        let ids: Vec<&str> = modules.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["e", "b", "c", "a", "d"]);
+    }
+}
@@ -7,30 +7,30 @@ use crate::{
     core::{
         inventory,
         inventory::model as modules,
-        ops::{executor, planner, sync},
+        ops::{executor, planner},
         state, storage,
-        storage::StorageHandle,
+        storage::Storage,
     },
 };
 
 pub struct Init;
 
 pub struct StorageReady {
-    pub handle: StorageHandle,
+    pub storage: Storage,
 }
 
 pub struct ModulesReady {
-    pub handle: StorageHandle,
+    pub storage: Storage,
     pub modules: Vec<inventory::Module>,
 }
 
 pub struct Planned {
-    pub handle: StorageHandle,
+    pub storage: Storage,
     pub plan: planner::MountPlan,
 }
 
 pub struct Executed {
-    pub handle: StorageHandle,
+    pub storage: Storage,
     pub plan: planner::MountPlan,
     pub result: executor::ExecutionResult,
 }
@@ -58,27 +58,13 @@ impl MountController<Init> {
         mnt_base: &Path,
         img_path: &Path,
     ) -> Result<MountController<StorageReady>> {
-        let handle = storage::setup(
-            mnt_base,
-            img_path,
-            &self.config.moduledir,
-            matches!(
-                self.config.overlay_mode,
-                crate::conf::config::OverlayMode::Ext4
-            ),
-            matches!(
-                self.config.overlay_mode,
-                crate::conf::config::OverlayMode::Erofs
-            ),
-            &self.config.mountsource,
-            self.config.disable_umount,
-        )?;
+        let storage = storage::setup(mnt_base, img_path, &self.config)?;
 
-        log::info!(">> Storage Backend: [{}]", handle.mode.to_uppercase());
+        log::info!(">> Storage Backend: [{}]", storage.backend.describe());
 
         Ok(MountController {
             config: self.config,
-            state: StorageReady { handle },
+            state: StorageReady { storage },
             tempdir: self.tempdir,
         })
     }
@@ -93,31 +79,12 @@ impl MountController<StorageReady> {
             modules.len()
         );
 
-        sync::perform_sync(&modules, &self.state.handle.mount_point)?;
-
-        if self.state.handle.mode == "erofs_staging" {
-            let needs_magic = modules.iter().any(|m| {
-                m.rules.default_mode == inventory::MountMode::Magic
-                    || m.rules
-                        .paths
-                        .values()
-                        .any(|v| *v == inventory::MountMode::Magic)
-            });
-
-            if needs_magic {
-                let magic_ws = self.state.handle.mount_point.join("magic_workspace");
-                if !magic_ws.exists() {
-                    let _ = std::fs::create_dir(magic_ws);
-                }
-            }
-        }
-
-        self.state.handle.commit(self.config.disable_umount)?;
+        self.state.storage.populate(&modules)?;
 
         Ok(MountController {
             config: self.config,
             state: ModulesReady {
-                handle: self.state.handle,
+                storage: self.state.storage,
                 modules,
             },
             tempdir: self.tempdir,
@@ -130,13 +97,13 @@ impl MountController<ModulesReady> {
         let plan = planner::generate(
             &self.config,
             &self.state.modules,
-            &self.state.handle.mount_point,
+            self.state.storage.backend.mount_point(),
         )?;
 
         Ok(MountController {
             config: self.config,
             state: Planned {
-                handle: self.state.handle,
+                storage: self.state.storage,
                 plan,
             },
             tempdir: self.tempdir,
@@ -148,12 +115,17 @@ impl MountController<Planned> {
     pub fn execute(self) -> Result<MountController<Executed>> {
         log::info!(">> Link Start! Executing mount plan...");
 
-        let result = executor::execute(&self.state.plan, &self.config, self.tempdir.clone())?;
+        let result = executor::execute(
+            &self.state.plan,
+            &self.config,
+            self.tempdir.clone(),
+            self.state.storage.backend.kind(),
+        )?;
 
         Ok(MountController {
             config: self.config,
             state: Executed {
-                handle: self.state.handle,
+                storage: self.state.storage,
                 plan: self.state.plan,
                 result,
             },
@@ -165,7 +137,7 @@ impl MountController<Planned> {
 impl MountController<Executed> {
     pub fn finalize(self) -> Result<()> {
         modules::update_description(
-            &self.state.handle.mode,
+            self.state.storage.backend.kind(),
             self.state.result.overlay_module_ids.len(),
             self.state.result.magic_module_ids.len(),
         );
@@ -182,8 +154,10 @@ impl MountController<Executed> {
         active_mounts.dedup();
 
         let state = state::RuntimeState::new(
-            self.state.handle.mode,
-            self.state.handle.mount_point,
+            self.state.storage.backend.kind(),
+            self.state.storage.backend.mount_point().to_path_buf(),
+            self.state.storage.attempts,
+            self.state.storage.dedup,
             self.state.result.overlay_module_ids,
             self.state.result.magic_module_ids,
             active_mounts,
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use rustix::fs::{AtFlags, CWD, Timespec, Timestamps, ioctl_ficlone, utimensat};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use super::manifest::hash_file;
+use crate::{core::inventory::Module, utils};
+
+// Smaller files save too little to be worth hashing on every boot.
+const MIN_DEDUP_SIZE: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct DedupStats {
+    // stored copies that share their data with another module's copy
+    pub files: u64,
+    pub bytes: u64,
+}
+
+// What a hardlink would share besides the data.
+#[derive(PartialEq, Eq)]
+struct InodeMeta {
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    mtime: i64,
+    mtime_nsec: i64,
+    xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+struct Inode {
+    paths: Vec<PathBuf>,
+    hash: String,
+    meta: InodeMeta,
+}
+
+// Shares identical files between the stored module copies. A hardlink shares owner, mode,
+// timestamps and xattrs too, so it is only used when those match; otherwise the data is
+// reflinked where the filesystem supports it. ext4 and tmpfs have no reflinks, so there only
+// hardlinks apply.
+pub fn deduplicate(modules: &[Module], target_base: &Path) -> Result<DedupStats> {
+    let mut by_size: HashMap<u64, HashMap<(u64, u64), Vec<PathBuf>>> = HashMap::new();
+
+    for module in modules {
+        let root = target_base.join(&module.id);
+        if !root.is_dir() {
+            continue;
+        }
+
+        for entry in WalkDir::new(&root).min_depth(1).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            if meta.len() < MIN_DEDUP_SIZE {
+                continue;
+            }
+            by_size
+                .entry(meta.len())
+                .or_default()
+                .entry((meta.dev(), meta.ino()))
+                .or_default()
+                .push(entry.into_path());
+        }
+    }
+
+    let mut stats = DedupStats::default();
+
+    for (size, inodes) in by_size {
+        // Copies linked on an earlier boot already share their inode.
+        for paths in inodes.values() {
+            stats.files += paths.len() as u64 - 1;
+            stats.bytes += size * (paths.len() as u64 - 1);
+        }
+
+        if inodes.len() < 2 {
+            continue;
+        }
+
+        let mut inodes: Vec<Inode> = inodes
+            .into_par_iter()
+            .filter_map(|(_, paths)| match describe(&paths[0]) {
+                Ok((hash, meta)) => Some(Inode { paths, hash, meta }),
+                Err(e) => {
+                    log::warn!("Failed to hash {}: {:#}", paths[0].display(), e);
+                    None
+                }
+            })
+            .collect();
+        inodes.sort_by(|a, b| a.paths[0].cmp(&b.paths[0]));
+
+        let mut by_hash: HashMap<&str, Vec<&Inode>> = HashMap::new();
+        for inode in &inodes {
+            by_hash.entry(&inode.hash).or_default().push(inode);
+        }
+
+        for group in by_hash.values().filter(|group| group.len() > 1) {
+            let keep = group[0];
+            for inode in &group[1..] {
+                for path in &inode.paths {
+                    let result = if inode.meta == keep.meta {
+                        link_over(&keep.paths[0], path)
+                    } else {
+                        reflink_over(&keep.paths[0], path)
+                    };
+
+                    match result {
+                        Ok(()) => {
+                            stats.files += 1;
+                            stats.bytes += size;
+                        }
+                        Err(e) => log::debug!("Not deduplicating {}: {:#}", path.display(), e),
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+fn describe(path: &Path) -> Result<(String, InodeMeta)> {
+    let meta = path.symlink_metadata()?;
+    let hash = hash_file(path)?;
+
+    Ok((
+        hash,
+        InodeMeta {
+            uid: meta.uid(),
+            gid: meta.gid(),
+            mode: meta.mode(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            xattrs: utils::list_xattrs(path),
+        },
+    ))
+}
+
+// Swaps `dst` for a hardlink to `src` in one rename, so it never goes missing.
+fn link_over(src: &Path, dst: &Path) -> Result<()> {
+    let name = dst.file_name().context("No file name")?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(".dedup");
+    let tmp = dst.with_file_name(tmp_name);
+
+    let _ = fs::remove_file(&tmp);
+    fs::hard_link(src, &tmp).context("Failed to hardlink")?;
+    if let Err(e) = fs::rename(&tmp, dst) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e).context("Failed to replace with hardlink");
+    }
+    Ok(())
+}
+
+// Shares the data of `src` with `dst` in place, keeping the timestamps cloning would bump.
+fn reflink_over(src: &Path, dst: &Path) -> Result<()> {
+    let meta = dst.symlink_metadata()?;
+    let src_file = File::open(src)?;
+    let dst_file = OpenOptions::new().write(true).open(dst)?;
+    ioctl_ficlone(&dst_file, &src_file).context("Failed to reflink")?;
+
+    let times = Timestamps {
+        last_access: Timespec {
+            tv_sec: meta.atime(),
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        last_modification: Timespec {
+            tv_sec: meta.mtime(),
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    };
+    utimensat(CWD, dst, &times, AtFlags::SYMLINK_NOFOLLOW)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::FileTimes,
+        time::{Duration, SystemTime},
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SIZE: usize = MIN_DEDUP_SIZE as usize;
+
+    fn module(id: &str) -> Module {
+        Module {
+            id: id.to_string(),
+            source_path: PathBuf::from("/unused"),
+            rules: Default::default(),
+        }
+    }
+
+    fn store(base: &Path, id: &str, content: &[u8], mtime: u64) -> PathBuf {
+        let path = base.join(id).join("system/lib/libfoo.so");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_times(
+                FileTimes::new().set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime)),
+            )
+            .unwrap();
+        path
+    }
+
+    fn ino(path: &Path) -> u64 {
+        path.symlink_metadata().unwrap().ino()
+    }
+
+    #[test]
+    fn hardlinks_identical_copies() {
+        let base = TempDir::new().unwrap();
+        let a = store(base.path(), "a", &[7; SIZE], 1_000);
+        let b = store(base.path(), "b", &[7; SIZE], 1_000);
+        let modules = [module("a"), module("b")];
+
+        let stats = deduplicate(&modules, base.path()).unwrap();
+        assert_eq!(ino(&a), ino(&b));
+        assert_eq!((stats.files, stats.bytes), (1, SIZE as u64));
+
+        // a later boot still counts the links it finds
+        let stats = deduplicate(&modules, base.path()).unwrap();
+        assert_eq!((stats.files, stats.bytes), (1, SIZE as u64));
+    }
+
+    #[test]
+    fn keeps_each_copy_its_own_mtime() {
+        let base = TempDir::new().unwrap();
+        let a = store(base.path(), "a", &[7; SIZE], 1_000);
+        let b = store(base.path(), "b", &[7; SIZE], 2_000);
+
+        deduplicate(&[module("a"), module("b")], base.path()).unwrap();
+
+        // reflinked where the filesystem can, left alone otherwise, never hardlinked
+        assert_ne!(ino(&a), ino(&b));
+        assert_eq!(a.symlink_metadata().unwrap().mtime(), 1_000);
+        assert_eq!(b.symlink_metadata().unwrap().mtime(), 2_000);
+        assert_eq!(fs::read(&b).unwrap(), [7; SIZE]);
+    }
+
+    #[test]
+    fn leaves_different_and_small_files_alone() {
+        let base = TempDir::new().unwrap();
+        let a = store(base.path(), "a", &[7; SIZE], 1_000);
+        let b = store(base.path(), "b", &[8; SIZE], 1_000);
+        let c = store(base.path(), "c", &[7; SIZE - 1], 1_000);
+        let d = store(base.path(), "d", &[7; SIZE - 1], 1_000);
+
+        let modules = [module("a"), module("b"), module("c"), module("d")];
+        let stats = deduplicate(&modules, base.path()).unwrap();
+
+        assert_eq!(stats.files, 0);
+        assert_ne!(ino(&a), ino(&b));
+        assert_ne!(ino(&c), ino(&d));
+    }
+}
@@ -1,10 +1,13 @@
-use std::{collections::HashSet, path::Path};
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 
 use crate::{
     conf::config,
-    core::ops::planner::MountPlan,
+    core::{ops::planner::MountPlan, storage::StorageKind},
     defs,
     mount::{
         magic_mount,
@@ -19,11 +22,16 @@ pub struct ExecutionResult {
     pub magic_module_ids: Vec<String>,
 }
 
-pub fn execute<P>(plan: &MountPlan, config: &config::Config, tempdir: P) -> Result<ExecutionResult>
+pub fn execute<P>(
+    plan: &MountPlan,
+    config: &config::Config,
+    tempdir: P,
+    storage: StorageKind,
+) -> Result<ExecutionResult>
 where
     P: AsRef<Path>,
 {
-    let mut final_magic_ids: HashSet<String> = plan.magic_module_ids.iter().cloned().collect();
+    let mut magic_roots: HashMap<String, Vec<PathBuf>> = plan.magic_roots.clone();
     let mut final_overlay_ids: HashSet<String> = HashSet::new();
 
     log::info!(">> Phase 1: OverlayFS Execution...");
@@ -87,19 +95,37 @@ where
                     op.target,
                     e
                 );
-                for id in involved_modules {
-                    final_magic_ids.insert(id);
+                for layer in &op.lowerdirs {
+                    let Some(id) = utils::extract_module_id(layer) else {
+                        continue;
+                    };
+
+                    // An empty root list means the whole module is magic mounted.
+                    match layer.strip_prefix(tempdir.as_ref().join(&id)) {
+                        Ok(relative) => match magic_roots.entry(id) {
+                            Entry::Occupied(mut roots) => {
+                                if !roots.get().is_empty() {
+                                    roots.get_mut().push(relative.to_path_buf());
+                                }
+                            }
+                            Entry::Vacant(roots) => {
+                                roots.insert(vec![relative.to_path_buf()]);
+                            }
+                        },
+                        Err(_) => {
+                            magic_roots.insert(id, Vec::new());
+                        }
+                    }
                 }
             }
         }
     }
 
-    final_overlay_ids.retain(|id| !final_magic_ids.contains(id));
+    final_overlay_ids.retain(|id| magic_roots.get(id).is_none_or(|roots| !roots.is_empty()));
 
-    let mut magic_queue: Vec<String> = final_magic_ids.iter().cloned().collect();
-    magic_queue.sort();
+    let mut final_magic_ids: HashSet<String> = magic_roots.keys().cloned().collect();
 
-    if !magic_queue.is_empty() {
+    if !magic_roots.is_empty() {
         let magic_ws_path = tempdir.as_ref().join("magic_workspace");
         let _ = umount_mgr::TMPFS.set(magic_ws_path.to_string_lossy().to_string());
 
@@ -108,25 +134,25 @@ where
             magic_ws_path.display()
         );
 
-        if matches!(config.overlay_mode, config::OverlayMode::Erofs) {
+        if storage.is_read_only() {
             if magic_ws_path.exists() {
                 crate::sys::mount::mount_tmpfs(&magic_ws_path, "magic_ws")?;
             } else {
-                log::error!("Magic Mount anchor missing in EROFS image!");
+                log::error!("Magic Mount anchor missing in read-only image!");
             }
         } else if !magic_ws_path.exists() {
             std::fs::create_dir_all(&magic_ws_path)?;
         }
 
         let module_dir = tempdir.as_ref();
-        let magic_need_ids: HashSet<String> = magic_queue.iter().cloned().collect();
 
         if let Err(e) = magic_mount::magic_mount(
             &magic_ws_path,
             module_dir,
             &config.mountsource,
             &config.partitions,
-            magic_need_ids,
+            magic_roots,
+            &plan.module_order,
             !config.disable_umount,
         ) {
             log::error!("Magic Mount critical failure: {:#}", e);
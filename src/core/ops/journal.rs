@@ -0,0 +1,272 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+// One `<id>.json` per module whose stored copy is being changed.
+const JOURNAL_DIR: &str = ".journal";
+const TMP_PREFIX: &str = ".tmp_";
+const BACKUP_PREFIX: &str = ".backup_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncStage {
+    // the new copy is being written to .tmp_<id>
+    Copying,
+    // the new copy is complete and being swapped in through .backup_<id>
+    Copied,
+    // the new copy is in place, only the backup is left to delete
+    Committed,
+    // entries of the stored copy are changed in place, its manifest is already gone
+    Patching,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalRecord {
+    module: String,
+    stage: SyncStage,
+}
+
+pub struct Journal {
+    target_base: PathBuf,
+}
+
+impl Journal {
+    pub fn new(target_base: &Path) -> Self {
+        Self {
+            target_base: target_base.to_path_buf(),
+        }
+    }
+
+    pub fn tmp_path(&self, id: &str) -> PathBuf {
+        self.target_base.join(format!("{}{}", TMP_PREFIX, id))
+    }
+
+    pub fn backup_path(&self, id: &str) -> PathBuf {
+        self.target_base.join(format!("{}{}", BACKUP_PREFIX, id))
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.target_base
+            .join(JOURNAL_DIR)
+            .join(format!("{}.json", id))
+    }
+
+    pub fn record(&self, id: &str, stage: SyncStage) -> Result<()> {
+        let path = self.record_path(id);
+        if let Some(parent) = path.parent() {
+            utils::ensure_dir_exists(parent)?;
+        }
+
+        let record = JournalRecord {
+            module: id.to_string(),
+            stage,
+        };
+        let json = serde_json::to_string(&record).context("Failed to serialize sync journal")?;
+        utils::atomic_write(&path, json)
+            .with_context(|| format!("Failed to journal sync of {}", id))
+    }
+
+    pub fn clear(&self, id: &str) {
+        let path = self.record_path(id);
+        if let Err(e) = fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            log::warn!("Failed to clear sync journal for {}: {}", id, e);
+        }
+    }
+
+    // Settles every swap an earlier run left half done, then whatever temporary copies no
+    // record accounts for. Runs before anything new is synced.
+    pub fn recover(&self) -> Result<()> {
+        if let Ok(records) = fs::read_dir(self.target_base.join(JOURNAL_DIR)) {
+            for entry in records.flatten() {
+                let path = entry.path();
+                let record = fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|content| serde_json::from_str::<JournalRecord>(&content).ok());
+
+                match record {
+                    Some(record) => {
+                        log::warn!(
+                            "Recovering interrupted sync of {} ({:?})",
+                            record.module,
+                            record.stage
+                        );
+                        self.settle(&record.module, record.stage)
+                            .with_context(|| format!("Failed to recover {}", record.module))?;
+                    }
+                    None => log::warn!("Dropping unreadable sync journal {}", path.display()),
+                }
+                let _ = fs::remove_file(&path);
+            }
+        }
+
+        self.sweep_leftovers()
+    }
+
+    fn settle(&self, id: &str, stage: SyncStage) -> Result<()> {
+        let dst = self.target_base.join(id);
+        let tmp = self.tmp_path(id);
+        let backup = self.backup_path(id);
+
+        match stage {
+            // The new copy may be incomplete; the stored one was never touched.
+            SyncStage::Copying => remove_dir(&tmp)?,
+            // The new copy is complete, so finish the swap wherever it stopped.
+            SyncStage::Copied => {
+                if tmp.exists() {
+                    if dst.exists() {
+                        remove_dir(&backup)?;
+                        fs::rename(&dst, &backup)?;
+                    }
+                    fs::rename(&tmp, &dst)?;
+                } else if !dst.exists() && backup.exists() {
+                    fs::rename(&backup, &dst)?;
+                }
+                remove_dir(&backup)?;
+            }
+            SyncStage::Committed => remove_dir(&backup)?,
+            // Half patched, but without a manifest the next sync copies it in full.
+            SyncStage::Patching => {}
+        }
+
+        Ok(())
+    }
+
+    // Temporary copies from before the journal, or whose record was lost: a backup whose
+    // module copy is gone is put back, anything else is stale.
+    fn sweep_leftovers(&self) -> Result<()> {
+        for entry in fs::read_dir(&self.target_base)?.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            if let Some(id) = name.strip_prefix(TMP_PREFIX) {
+                log::info!("Removing leftover sync copy of {}", id);
+                remove_dir(&entry.path())?;
+            } else if let Some(id) = name.strip_prefix(BACKUP_PREFIX) {
+                let dst = self.target_base.join(id);
+                if dst.exists() {
+                    log::info!("Removing leftover sync backup of {}", id);
+                    remove_dir(&entry.path())?;
+                } else {
+                    log::info!("Restoring leftover sync backup of {}", id);
+                    fs::rename(entry.path(), dst)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn remove_dir(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_dir_all(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // Creates each directory with a `v` file naming its version.
+    fn layout(dirs: &[(&str, &str)]) -> TempDir {
+        let base = TempDir::new().unwrap();
+        for (name, version) in dirs {
+            let dir = base.path().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("v"), version).unwrap();
+        }
+        base
+    }
+
+    fn version(base: &TempDir, name: &str) -> Option<String> {
+        fs::read_to_string(base.path().join(name).join("v")).ok()
+    }
+
+    fn recover(base: &TempDir, stage: SyncStage) {
+        let journal = Journal::new(base.path());
+        journal.record("mod", stage).unwrap();
+        journal.recover().unwrap();
+
+        assert!(!journal.record_path("mod").exists());
+        assert_eq!(version(base, ".tmp_mod"), None);
+        assert_eq!(version(base, ".backup_mod"), None);
+    }
+
+    #[test]
+    fn copying_drops_the_partial_copy() {
+        let base = layout(&[("mod", "old"), (".tmp_mod", "partial")]);
+        recover(&base, SyncStage::Copying);
+        assert_eq!(version(&base, "mod").as_deref(), Some("old"));
+    }
+
+    #[test]
+    fn copied_finishes_the_swap_wherever_it_stopped() {
+        // before the old copy was moved aside
+        let base = layout(&[("mod", "old"), (".tmp_mod", "new")]);
+        recover(&base, SyncStage::Copied);
+        assert_eq!(version(&base, "mod").as_deref(), Some("new"));
+
+        // between the two renames
+        let base = layout(&[(".backup_mod", "old"), (".tmp_mod", "new")]);
+        recover(&base, SyncStage::Copied);
+        assert_eq!(version(&base, "mod").as_deref(), Some("new"));
+
+        // after the new copy was moved in
+        let base = layout(&[("mod", "new"), (".backup_mod", "old")]);
+        recover(&base, SyncStage::Copied);
+        assert_eq!(version(&base, "mod").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn copied_without_a_new_copy_restores_the_backup() {
+        let base = layout(&[(".backup_mod", "old")]);
+        recover(&base, SyncStage::Copied);
+        assert_eq!(version(&base, "mod").as_deref(), Some("old"));
+    }
+
+    #[test]
+    fn committed_only_drops_the_backup() {
+        let base = layout(&[("mod", "new"), (".backup_mod", "old")]);
+        recover(&base, SyncStage::Committed);
+        assert_eq!(version(&base, "mod").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn patching_leaves_the_copy_for_a_full_sync() {
+        let base = layout(&[("mod", "half")]);
+        recover(&base, SyncStage::Patching);
+        assert_eq!(version(&base, "mod").as_deref(), Some("half"));
+    }
+
+    #[test]
+    fn sweeps_leftovers_without_a_record() {
+        let base = layout(&[
+            (".tmp_a", "partial"),
+            ("b", "current"),
+            (".backup_b", "old"),
+            (".backup_c", "only"),
+        ]);
+        let journal_dir = base.path().join(JOURNAL_DIR);
+        fs::create_dir_all(&journal_dir).unwrap();
+        fs::write(journal_dir.join("broken.json"), "{").unwrap();
+
+        Journal::new(base.path()).recover().unwrap();
+
+        assert_eq!(fs::read_dir(&journal_dir).unwrap().count(), 0);
+        assert_eq!(version(&base, ".tmp_a"), None);
+        assert_eq!(version(&base, "b").as_deref(), Some("current"));
+        assert_eq!(version(&base, ".backup_b"), None);
+        assert_eq!(version(&base, "c").as_deref(), Some("only"));
+        assert_eq!(version(&base, ".backup_c"), None);
+    }
+}
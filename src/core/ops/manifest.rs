@@ -0,0 +1,684 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, Metadata},
+    io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::{conf::config::ErofsConfig, core::inventory::Module, utils};
+
+// Bump whenever the staged layout or the image build options change.
+const DIGEST_FORMAT: &str = "hybrid-mount-erofs-v3";
+const HASH_CACHE_VERSION: u32 = 1;
+// Bump whenever sync lays out a module copy differently, so old copies are redone in full.
+const MODULE_MANIFEST_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Special,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // moves on every write and metadata change, even when size and mtime are put back
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+    // hash of the names and values, None when there are none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    // only recorded once the contents had to be compared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl ManifestEntry {
+    fn from_path(path: &Path) -> Result<Self> {
+        let meta = path.symlink_metadata()?;
+        let file_type = meta.file_type();
+
+        let kind = if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else {
+            EntryKind::Special
+        };
+
+        Ok(Self {
+            kind,
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            mode: meta.permissions().mode(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            xattrs: hash_xattrs(path),
+            target: if kind == EntryKind::Symlink {
+                Some(fs::read_link(path)?)
+            } else {
+                None
+            },
+            hash: None,
+        })
+    }
+
+    // Directory times move with their children, so only files compare them.
+    fn same_as(&self, other: &Self) -> bool {
+        let same_shape = self.kind == other.kind
+            && self.mode == other.mode
+            && self.uid == other.uid
+            && self.gid == other.gid
+            && self.xattrs == other.xattrs
+            && self.target == other.target;
+
+        match self.kind {
+            EntryKind::Dir => same_shape,
+            _ => {
+                same_shape
+                    && self.size == other.size
+                    && self.mtime == other.mtime
+                    && self.mtime_nsec == other.mtime_nsec
+                    && self.ctime == other.ctime
+                    && self.ctime_nsec == other.ctime_nsec
+            }
+        }
+    }
+
+    // A touched file whose contents may still be the same: everything but its times matches.
+    pub fn only_mtime_differs(&self, other: &Self) -> bool {
+        self.kind == EntryKind::File
+            && other.kind == EntryKind::File
+            && self.mode == other.mode
+            && self.uid == other.uid
+            && self.gid == other.gid
+            && self.xattrs == other.xattrs
+            && self.size == other.size
+    }
+}
+
+// What a module copy in storage was synced from, kept next to the copies so the next sync
+// only touches entries that changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModuleManifest {
+    pub version: u32,
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ManifestDiff {
+    pub added: Vec<PathBuf>,
+    pub changed: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl ModuleManifest {
+    pub fn scan(root: &Path) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+
+        for entry in WalkDir::new(root).min_depth(1) {
+            let entry = entry?;
+            let relative = entry.path().strip_prefix(root)?.to_path_buf();
+            let manifest_entry = ManifestEntry::from_path(entry.path())
+                .with_context(|| format!("Failed to stat {}", entry.path().display()))?;
+            entries.insert(relative, manifest_entry);
+        }
+
+        Ok(Self {
+            version: MODULE_MANIFEST_VERSION,
+            entries,
+        })
+    }
+
+    // None when there is no manifest or it was written by another layout version.
+    pub fn load(path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        let manifest: Self = serde_json::from_str(&content).ok()?;
+        (manifest.version == MODULE_MANIFEST_VERSION).then_some(manifest)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            utils::ensure_dir_exists(parent)?;
+        }
+        let json = serde_json::to_string(self).context("Failed to serialize module manifest")?;
+        utils::atomic_write(path, json)
+    }
+
+    // Keeps the hashes recorded for entries that have not changed since.
+    pub fn inherit_hashes(&mut self, previous: &Self) {
+        for (path, entry) in &mut self.entries {
+            if let Some(old) = previous.entries.get(path)
+                && old.same_as(entry)
+            {
+                entry.hash = old.hash.clone();
+            }
+        }
+    }
+
+    // Entries of `newer` to add or replace and entries of `self` to delete, in path order.
+    pub fn diff(&self, newer: &Self) -> ManifestDiff {
+        let mut diff = ManifestDiff::default();
+
+        for (path, entry) in &newer.entries {
+            match self.entries.get(path) {
+                None => diff.added.push(path.clone()),
+                Some(old) if !old.same_as(entry) => diff.changed.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+
+        diff.removed = self
+            .entries
+            .keys()
+            .filter(|path| !newer.entries.contains_key(*path))
+            .cloned()
+            .collect();
+
+        diff
+    }
+}
+
+fn hash_xattrs(path: &Path) -> Option<String> {
+    let xattrs = utils::list_xattrs(path);
+    if xattrs.is_empty() {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    for (name, value) in xattrs {
+        hasher.update(name.as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Content hashes from an earlier digest, kept next to the image. A file whose size, times and
+// inode have not moved since is not read again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HashCache {
+    version: u32,
+    files: BTreeMap<PathBuf, CachedHash>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CachedHash {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ctime: i64,
+    ctime_nsec: i64,
+    ino: u64,
+    hash: String,
+}
+
+impl CachedHash {
+    fn matches(&self, meta: &Metadata) -> bool {
+        self.size == meta.len()
+            && self.mtime == meta.mtime()
+            && self.mtime_nsec == meta.mtime_nsec()
+            && self.ctime == meta.ctime()
+            && self.ctime_nsec == meta.ctime_nsec()
+            && self.ino == meta.ino()
+    }
+}
+
+impl HashCache {
+    // Empty when there is no cache or it was written by another digest format.
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Self>(&content).ok())
+            .filter(|cache| cache.version == HASH_CACHE_VERSION)
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self).context("Failed to serialize hash cache")?;
+        utils::atomic_write(path, json)
+    }
+
+    fn hash(&self, path: &Path, meta: &Metadata) -> Result<CachedHash> {
+        if let Some(cached) = self.files.get(path)
+            && cached.matches(meta)
+        {
+            return Ok(cached.clone());
+        }
+
+        Ok(CachedHash {
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            ino: meta.ino(),
+            hash: hash_file(path)?,
+        })
+    }
+}
+
+// Hash of every module's files (contents, metadata and xattrs) and resolved rules plus the
+// image build options and the builder, used to tell whether a cached image still matches the
+// inventory. `cache` supplies the content hashes and is refreshed with the current files.
+pub fn inventory_digest(
+    modules: &[Module],
+    options: &ErofsConfig,
+    builder: &str,
+    cache: &mut HashCache,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(DIGEST_FORMAT.as_bytes());
+
+    let options = serde_json::to_string(options).context("Failed to serialize EROFS options")?;
+    hasher.update(options.as_bytes());
+    hasher.update(b"\0builder\0");
+    hasher.update(builder.as_bytes());
+
+    let mut sorted: Vec<&Module> = modules.iter().collect();
+    sorted.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut files = BTreeMap::new();
+    for module in sorted {
+        hasher.update(b"\0module\0");
+        hasher.update(module.id.as_bytes());
+
+        let rules = serde_json::to_value(&module.rules)
+            .map(sort_keys)
+            .and_then(|rules| serde_json::to_string(&rules))
+            .context("Failed to serialize rules")?;
+        hasher.update(rules.as_bytes());
+        hasher.update(format!("{:?}", module.rules.effective_default()).as_bytes());
+
+        let mut partition_defaults: Vec<_> = module.rules.partition_defaults.iter().collect();
+        partition_defaults.sort_by(|a, b| a.0.cmp(b.0));
+        hasher.update(format!("{:?}", partition_defaults).as_bytes());
+
+        hash_tree(&mut hasher, &module.source_path, cache, &mut files)
+            .with_context(|| format!("Failed to fingerprint module {}", module.id))?;
+    }
+
+    cache.version = HASH_CACHE_VERSION;
+    cache.files = files;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Rules hold HashMaps, whose order changes from run to run.
+fn sort_keys(value: Json) -> Json {
+    match value {
+        Json::Object(map) => {
+            let mut entries: Vec<(String, Json)> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Json::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, sort_keys(v)))
+                    .collect(),
+            )
+        }
+        Json::Array(items) => Json::Array(items.into_iter().map(sort_keys).collect()),
+        other => other,
+    }
+}
+
+fn hash_tree(
+    hasher: &mut Sha256,
+    root: &Path,
+    cache: &HashCache,
+    files: &mut BTreeMap<PathBuf, CachedHash>,
+) -> Result<()> {
+    let entries = WalkDir::new(root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .collect::<walkdir::Result<Vec<_>>>()?;
+
+    let fingerprints = entries
+        .par_iter()
+        .map(|entry| fingerprint_entry(root, entry.path(), cache))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (entry, (fingerprint, content)) in entries.iter().zip(fingerprints) {
+        hasher.update(fingerprint);
+        if let Some(content) = content {
+            files.insert(entry.path().to_path_buf(), content);
+        }
+    }
+
+    Ok(())
+}
+
+// Contents count as well: a file rewritten with the same size and mtime still changes the image.
+fn fingerprint_entry(
+    root: &Path,
+    path: &Path,
+    cache: &HashCache,
+) -> Result<(Vec<u8>, Option<CachedHash>)> {
+    let meta = path.symlink_metadata()?;
+    let relative = path.strip_prefix(root)?;
+
+    let mut out = b"\0".to_vec();
+    out.extend_from_slice(relative.as_os_str().as_encoded_bytes());
+    out.extend_from_slice(
+        format!(
+            "|{}|{}|{}:{}|{}.{}",
+            meta.permissions().mode(),
+            meta.len(),
+            meta.uid(),
+            meta.gid(),
+            meta.mtime(),
+            meta.mtime_nsec()
+        )
+        .as_bytes(),
+    );
+
+    let mut content = None;
+    if meta.file_type().is_symlink() {
+        out.extend_from_slice(fs::read_link(path)?.as_os_str().as_encoded_bytes());
+    } else if meta.file_type().is_file() {
+        let hashed = cache.hash(path, &meta)?;
+        out.push(b'|');
+        out.extend_from_slice(hashed.hash.as_bytes());
+        content = Some(hashed);
+    }
+
+    for (name, value) in utils::list_xattrs(path) {
+        out.push(b'|');
+        out.extend_from_slice(name.as_encoded_bytes());
+        out.push(b'=');
+        out.extend_from_slice(&value);
+    }
+
+    Ok((out, content))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::FileTimes, time::SystemTime};
+
+    use extattr::{Flags as XattrFlags, lsetxattr};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::core::inventory::MountMode;
+
+    fn module(root: &Path) -> Module {
+        Module {
+            id: "mod".to_string(),
+            source_path: root.to_path_buf(),
+            rules: Default::default(),
+        }
+    }
+
+    fn digest(module: &Module) -> String {
+        let modules = std::slice::from_ref(module);
+        inventory_digest(
+            modules,
+            &ErofsConfig::default(),
+            "built-in",
+            &mut HashCache::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn digest_ignores_rule_map_order() {
+        let dir = TempDir::new().unwrap();
+        let patterns: Vec<String> = (0..32).map(|i| format!("system/dir{i}")).collect();
+        let mut forward = module(dir.path());
+        let mut backward = module(dir.path());
+        for pattern in &patterns {
+            forward
+                .rules
+                .paths
+                .insert(pattern.clone(), MountMode::Magic);
+        }
+        for pattern in patterns.iter().rev() {
+            backward
+                .rules
+                .paths
+                .insert(pattern.clone(), MountMode::Magic);
+        }
+
+        assert_eq!(digest(&forward), digest(&backward));
+    }
+
+    #[test]
+    fn digest_follows_the_resolved_default() {
+        let dir = TempDir::new().unwrap();
+        let overlay = module(dir.path());
+        let mut magic = module(dir.path());
+        magic.rules.global_default = MountMode::Magic;
+
+        assert_ne!(digest(&overlay), digest(&magic));
+    }
+
+    #[test]
+    fn digest_covers_file_contents() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("system/etc/hosts");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "aaaa").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        let pin = || {
+            File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_times(FileTimes::new().set_modified(mtime))
+                .unwrap()
+        };
+        pin();
+        let module = module(dir.path());
+        let before = digest(&module);
+        assert_eq!(before, digest(&module));
+
+        fs::write(&file, "bbbb").unwrap();
+        pin();
+
+        assert_ne!(before, digest(&module));
+    }
+
+    #[test]
+    fn digest_follows_the_builder() {
+        let dir = TempDir::new().unwrap();
+        let modules = [module(dir.path())];
+        let options = ErofsConfig::default();
+        let mut cache = HashCache::default();
+
+        let builtin = inventory_digest(&modules, &options, "built-in", &mut cache).unwrap();
+        let mkfs = inventory_digest(&modules, &options, "mkfs.erofs 1.8", &mut cache).unwrap();
+
+        assert_ne!(builtin, mkfs);
+    }
+
+    #[test]
+    fn unchanged_files_are_not_hashed_again() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("system/etc/hosts");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "aaaa").unwrap();
+        let modules = [module(dir.path())];
+        let options = ErofsConfig::default();
+        let cache_file = dir.path().join("image.files.json");
+
+        let mut cache = HashCache::default();
+        let fresh = inventory_digest(&modules, &options, "built-in", &mut cache).unwrap();
+        cache.save(&cache_file).unwrap();
+
+        // a cached hash that no longer matches the contents is trusted while stat agrees
+        let mut cache = HashCache::load(&cache_file);
+        cache.files.get_mut(&file).unwrap().hash = "stale".to_string();
+        let reused = inventory_digest(&modules, &options, "built-in", &mut cache).unwrap();
+        assert_ne!(fresh, reused);
+        assert_eq!(cache.files[&file].hash, "stale");
+
+        // once the file is touched its contents are read again
+        tick();
+        fs::write(&file, "aaaa").unwrap();
+        let mut cache = HashCache::load(&cache_file);
+        cache.files.get_mut(&file).unwrap().hash = "stale".to_string();
+        let rehashed = inventory_digest(&modules, &options, "built-in", &mut cache).unwrap();
+        assert_eq!(rehashed, digest(&modules[0]));
+        assert_eq!(cache.files[&file].hash, hash_file(&file).unwrap());
+    }
+
+    #[test]
+    fn hash_cache_drops_files_that_are_gone() {
+        let dir = TempDir::new().unwrap();
+        let gone = dir.path().join("gone");
+        fs::write(&gone, "bye").unwrap();
+        let modules = [module(dir.path())];
+        let mut cache = HashCache::default();
+        inventory_digest(&modules, &ErofsConfig::default(), "built-in", &mut cache).unwrap();
+        assert!(cache.files.contains_key(&gone));
+
+        fs::remove_file(&gone).unwrap();
+        inventory_digest(&modules, &ErofsConfig::default(), "built-in", &mut cache).unwrap();
+
+        assert!(cache.files.is_empty());
+    }
+
+    fn set_mtime(path: &Path, secs: u64) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_times(
+                FileTimes::new()
+                    .set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)),
+            )
+            .unwrap();
+    }
+
+    // Leaves the coarse timestamp clock some room, so a later change gets a new ctime.
+    fn tick() {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+
+    fn tree() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("system/etc")).unwrap();
+        fs::write(dir.path().join("system/etc/hosts"), "127.0.0.1 localhost\n").unwrap();
+        fs::write(dir.path().join("system/etc/gone"), "bye").unwrap();
+        set_mtime(&dir.path().join("system/etc/hosts"), 1_000_000);
+        dir
+    }
+
+    #[test]
+    fn diff_reports_added_changed_and_removed_entries() {
+        let dir = tree();
+        let root = dir.path();
+        let before = ModuleManifest::scan(root).unwrap();
+
+        tick();
+        fs::write(root.join("system/etc/hosts"), "::1 localhost\n").unwrap();
+        fs::write(root.join("system/etc/new"), "hi").unwrap();
+        fs::remove_file(root.join("system/etc/gone")).unwrap();
+
+        let diff = before.diff(&ModuleManifest::scan(root).unwrap());
+        assert_eq!(diff.added, [PathBuf::from("system/etc/new")]);
+        assert_eq!(diff.changed, [PathBuf::from("system/etc/hosts")]);
+        assert_eq!(diff.removed, [PathBuf::from("system/etc/gone")]);
+    }
+
+    #[test]
+    fn rewrite_with_restored_size_and_mtime_is_a_change() {
+        let dir = tree();
+        let hosts = dir.path().join("system/etc/hosts");
+        let before = ModuleManifest::scan(dir.path()).unwrap();
+
+        tick();
+        fs::write(&hosts, "127.0.0.1 otherhost\n").unwrap();
+        set_mtime(&hosts, 1_000_000);
+
+        let after = ModuleManifest::scan(dir.path()).unwrap();
+        let path = PathBuf::from("system/etc/hosts");
+        assert_eq!(before.diff(&after).changed, std::slice::from_ref(&path));
+        // only the times moved, so sync compares the contents before copying
+        assert!(before.entries[&path].only_mtime_differs(&after.entries[&path]));
+    }
+
+    #[test]
+    fn xattr_change_is_not_just_a_touch() {
+        let dir = tree();
+        let hosts = dir.path().join("system/etc/hosts");
+        let before = ModuleManifest::scan(dir.path()).unwrap();
+
+        if lsetxattr(&hosts, "user.hybrid", b"1", XattrFlags::empty()).is_err() {
+            // the filesystem keeps no user xattrs
+            return;
+        }
+
+        let after = ModuleManifest::scan(dir.path()).unwrap();
+        let path = PathBuf::from("system/etc/hosts");
+        assert_eq!(before.diff(&after).changed, std::slice::from_ref(&path));
+        assert!(!before.entries[&path].only_mtime_differs(&after.entries[&path]));
+    }
+
+    #[test]
+    fn directories_ignore_their_times() {
+        let dir = tree();
+        let before = ModuleManifest::scan(dir.path()).unwrap();
+
+        tick();
+        fs::write(dir.path().join("system/etc/new"), "hi").unwrap();
+
+        let diff = before.diff(&ModuleManifest::scan(dir.path()).unwrap());
+        assert_eq!(diff.added, [PathBuf::from("system/etc/new")]);
+        assert!(diff.changed.is_empty());
+    }
+
+    #[test]
+    fn unchanged_entries_keep_their_hashes() {
+        let dir = tree();
+        let mut before = ModuleManifest::scan(dir.path()).unwrap();
+        for path in ["system/etc/hosts", "system/etc/gone"] {
+            before.entries.get_mut(Path::new(path)).unwrap().hash = Some(path.to_string());
+        }
+
+        tick();
+        fs::write(dir.path().join("system/etc/gone"), "back").unwrap();
+
+        let mut after = ModuleManifest::scan(dir.path()).unwrap();
+        after.inherit_hashes(&before);
+        let hash = |path: &str| after.entries[Path::new(path)].hash.clone();
+        assert_eq!(
+            hash("system/etc/hosts").as_deref(),
+            Some("system/etc/hosts")
+        );
+        assert_eq!(hash("system/etc/gone"), None);
+    }
+}
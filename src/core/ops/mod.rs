@@ -1,3 +1,6 @@
+pub mod dedup;
 pub mod executor;
+pub mod journal;
+pub mod manifest;
 pub mod planner;
 pub mod sync;
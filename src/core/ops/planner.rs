@@ -11,8 +11,10 @@ use walkdir::WalkDir;
 
 use crate::{
     conf::config,
-    core::inventory::{Module, MountMode},
-    defs, utils,
+    core::inventory::{self, Module, MountMode},
+    defs,
+    sys::caps,
+    utils,
 };
 
 #[derive(Debug, Clone)]
@@ -27,6 +29,10 @@ pub struct MountPlan {
     pub overlay_ops: Vec<OverlayOperation>,
     pub overlay_module_ids: Vec<String>,
     pub magic_module_ids: Vec<String>,
+    // module id -> subtrees (relative to the module root) that go through magic mount
+    pub magic_roots: HashMap<String, Vec<PathBuf>>,
+    // module ids from the highest precedence down
+    pub module_order: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -34,10 +40,12 @@ pub struct ConflictEntry {
     pub partition: String,
     pub relative_path: String,
     pub contending_modules: Vec<String>,
+    pub winner: String,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub enum DiagnosticLevel {
+    Info,
     Warning,
     Critical,
 }
@@ -111,9 +119,11 @@ impl MountPlan {
 
                 for (rel_path, modules) in file_map {
                     if modules.len() > 1 {
+                        // Lowerdirs are ordered by precedence, so the first layer wins.
                         local_conflicts.push(ConflictEntry {
                             partition: op.partition_name.clone(),
                             relative_path: rel_path,
+                            winner: modules[0].clone(),
                             contending_modules: modules,
                         });
                     }
@@ -143,23 +153,64 @@ struct ProcessingItem {
     module_source: PathBuf,
     system_target: PathBuf,
     partition_label: String,
+    relative: PathBuf,
+}
+
+// A rule like `system/**/foo.so` could apply below any directory of system, so the module's
+// own entries decide whether one actually resolves to another mode.
+fn has_nested_entries(module: &Module, source: &Path, relative: &Path, mode: &MountMode) -> bool {
+    if !module
+        .rules
+        .has_nested_rules(&relative.to_string_lossy(), mode)
+    {
+        return false;
+    }
+
+    WalkDir::new(source)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(Result::ok)
+        .any(|entry| {
+            entry.path().strip_prefix(source).is_ok_and(|sub| {
+                module.rules.get_mode(&relative.join(sub).to_string_lossy()) != *mode
+            })
+        })
 }
 
 pub fn generate(
     config: &config::Config,
     modules: &[Module],
     storage_root: &Path,
+) -> Result<MountPlan> {
+    // Overlay rules still split the tree as usual, the leaves just go to magic mount instead.
+    plan_mounts(
+        config,
+        modules,
+        storage_root,
+        caps::probe().kernel.overlayfs,
+    )
+}
+
+fn plan_mounts(
+    config: &config::Config,
+    modules: &[Module],
+    storage_root: &Path,
+    overlay_supported: bool,
 ) -> Result<MountPlan> {
     let mut plan = MountPlan::default();
 
     let mut overlay_groups: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
 
     let mut overlay_ids = HashSet::new();
-    let mut magic_ids = HashSet::new();
+    let mut magic_roots: HashMap<String, Vec<PathBuf>> = HashMap::new();
 
     let sensitive_partitions: HashSet<&str> = defs::SENSITIVE_PARTITIONS.iter().cloned().collect();
 
-    for module in modules {
+    let mut ordered: Vec<&Module> = modules.iter().collect();
+    ordered.sort_by(|a, b| inventory::precedence(a, b));
+    plan.module_order = ordered.iter().map(|m| m.id.clone()).collect();
+
+    for module in ordered {
         let mut content_path = storage_root.join(&module.id);
         if !content_path.exists() {
             content_path = module.source_path.clone();
@@ -168,6 +219,13 @@ pub fn generate(
             continue;
         }
 
+        let mut add_magic_root = |relative: PathBuf| {
+            magic_roots
+                .entry(module.id.clone())
+                .or_default()
+                .push(relative);
+        };
+
         if let Ok(entries) = fs::read_dir(&content_path) {
             for entry in entries.flatten() {
                 let path = entry.path();
@@ -183,22 +241,12 @@ pub fn generate(
                     continue;
                 }
 
-                let mode = module.rules.get_mode(&dir_name);
-                if matches!(mode, MountMode::Magic) {
-                    magic_ids.insert(module.id.clone());
-                    continue;
-                }
-                if matches!(mode, MountMode::Ignore) {
-                    continue;
-                }
-
-                overlay_ids.insert(module.id.clone());
-
                 let mut queue = VecDeque::new();
                 queue.push_back(ProcessingItem {
                     module_source: path.clone(),
                     system_target: PathBuf::from("/").join(&dir_name),
                     partition_label: dir_name.clone(),
+                    relative: PathBuf::from(&dir_name),
                 });
 
                 while let Some(item) = queue.pop_front() {
@@ -206,8 +254,28 @@ pub fn generate(
                         module_source,
                         system_target,
                         partition_label,
+                        relative,
                     } = item;
 
+                    let relative_str = relative.to_string_lossy();
+                    let mode = module.rules.get_mode(&relative_str);
+                    let nested = has_nested_entries(module, &module_source, &relative, &mode);
+
+                    if !nested {
+                        match mode {
+                            MountMode::Ignore => continue,
+                            MountMode::Magic => {
+                                add_magic_root(relative);
+                                continue;
+                            }
+                            MountMode::Overlay if !overlay_supported => {
+                                add_magic_root(relative);
+                                continue;
+                            }
+                            MountMode::Overlay => {}
+                        }
+                    }
+
                     if !system_target.exists() {
                         continue;
                     }
@@ -240,26 +308,41 @@ pub fn generate(
                         .map(|s| s.to_string_lossy())
                         .unwrap_or_default();
 
-                    let should_split = sensitive_partitions.contains(target_name.as_ref())
+                    let should_split = nested
+                        || sensitive_partitions.contains(target_name.as_ref())
                         || target_name == "system";
 
                     if should_split {
                         if let Ok(sub_entries) = fs::read_dir(&module_source) {
                             for sub_entry in sub_entries.flatten() {
                                 let sub_path = sub_entry.path();
+                                let sub_name = sub_entry.file_name();
+                                let sub_relative = relative.join(&sub_name);
+
                                 if !sub_path.is_dir() {
+                                    // Single files cannot be overlaid on their own, so files
+                                    // beside a nested rule fall back to magic mount.
+                                    if nested
+                                        && module.rules.get_mode(&sub_relative.to_string_lossy())
+                                            != MountMode::Ignore
+                                    {
+                                        add_magic_root(sub_relative);
+                                    }
                                     continue;
                                 }
-                                let sub_name = sub_entry.file_name();
 
                                 queue.push_back(ProcessingItem {
                                     module_source: sub_path,
                                     system_target: canonical_target.join(sub_name),
                                     partition_label: partition_label.clone(),
+                                    relative: sub_relative,
                                 });
                             }
                         }
+                    } else if !overlay_supported {
+                        add_magic_root(relative);
                     } else {
+                        overlay_ids.insert(module.id.clone());
                         overlay_groups
                             .entry(canonical_target)
                             .or_default()
@@ -291,9 +374,132 @@ pub fn generate(
     }
 
     plan.overlay_module_ids = overlay_ids.into_iter().collect();
-    plan.magic_module_ids = magic_ids.into_iter().collect();
+    plan.magic_module_ids = magic_roots.keys().cloned().collect();
+    plan.magic_roots = magic_roots;
     plan.overlay_module_ids.sort();
     plan.magic_module_ids.sort();
 
     Ok(plan)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn module(root: &Path, rules: &[(&str, MountMode)]) -> Module {
+        let mut module = Module {
+            id: "test".to_string(),
+            source_path: root.to_path_buf(),
+            rules: Default::default(),
+        };
+        for (pattern, mode) in rules {
+            module.rules.paths.insert(pattern.to_string(), mode.clone());
+        }
+        module
+    }
+
+    #[test]
+    fn double_star_rule_splits_only_directories_holding_a_match() {
+        let dir = TempDir::new().unwrap();
+        for file in [
+            "system/lib64/hw/libfoo.so",
+            "system/bin/sh",
+            "system/etc/hosts",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let module = module(dir.path(), &[("system/**/libfoo.so", MountMode::Magic)]);
+        let nested = |relative: &str| {
+            has_nested_entries(
+                &module,
+                &dir.path().join(relative),
+                Path::new(relative),
+                &MountMode::Overlay,
+            )
+        };
+
+        assert!(nested("system"));
+        assert!(nested("system/lib64"));
+        assert!(nested("system/lib64/hw"));
+        assert!(!nested("system/bin"));
+        assert!(!nested("system/etc"));
+    }
+
+    #[test]
+    fn rule_for_a_missing_path_does_not_split() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("system/bin")).unwrap();
+        fs::write(dir.path().join("system/bin/sh"), "").unwrap();
+        let module = module(dir.path(), &[("system/lib/libfoo.so", MountMode::Magic)]);
+
+        assert!(!has_nested_entries(
+            &module,
+            &dir.path().join("system"),
+            Path::new("system"),
+            &MountMode::Overlay,
+        ));
+    }
+
+    // Modules named by id under one storage root, each holding `tmp/shared` and a module.prop.
+    // /tmp stands in for a partition, since it exists wherever the tests run.
+    fn stored_modules(priorities: &[(&str, Option<i32>)]) -> (TempDir, Vec<Module>) {
+        let storage = TempDir::new().unwrap();
+        let modules = priorities
+            .iter()
+            .map(|(id, priority)| {
+                let root = storage.path().join(id);
+                fs::create_dir_all(root.join("tmp")).unwrap();
+                fs::write(root.join("tmp/shared"), id).unwrap();
+                fs::write(root.join("module.prop"), format!("id={id}\n")).unwrap();
+
+                let mut module = module(&root, &[]);
+                module.id = id.to_string();
+                module.rules.priority = *priority;
+                module
+            })
+            .collect();
+        (storage, modules)
+    }
+
+    fn plan(storage: &TempDir, modules: &[Module]) -> MountPlan {
+        let config = config::Config {
+            partitions: vec!["tmp".to_string()],
+            ..Default::default()
+        };
+        plan_mounts(&config, modules, storage.path(), true).unwrap()
+    }
+
+    #[test]
+    fn lowerdirs_follow_module_precedence() {
+        let (storage, modules) =
+            stored_modules(&[("low", None), ("high", Some(10)), ("mid", Some(1))]);
+        let plan = plan(&storage, &modules);
+
+        assert_eq!(plan.module_order, ["high", "mid", "low"]);
+        assert_eq!(plan.overlay_ops.len(), 1);
+        let tmp = Path::new("/tmp").canonicalize().unwrap();
+        assert_eq!(plan.overlay_ops[0].target, tmp.to_string_lossy());
+        assert_eq!(
+            plan.overlay_ops[0].lowerdirs,
+            ["high", "mid", "low"].map(|id| storage.path().join(id).join("tmp"))
+        );
+    }
+
+    #[test]
+    fn conflict_winner_is_the_higher_priority_module() {
+        let (storage, modules) = stored_modules(&[("a", None), ("b", Some(-1)), ("c", Some(3))]);
+        let report = plan(&storage, &modules).analyze();
+
+        assert_eq!(report.conflicts.len(), 1);
+        let conflict = &report.conflicts[0];
+        assert_eq!(conflict.relative_path, "shared");
+        assert_eq!(conflict.contending_modules, ["c", "a", "b"]);
+        assert_eq!(conflict.winner, "c");
+    }
+}
@@ -1,80 +1,528 @@
-use std::{collections::HashSet, fs, path::Path};
-
-use anyhow::Result;
+use std::{
+    collections::{BTreeSet, HashSet},
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
 use rayon::prelude::*;
+use serde::Serialize;
 use walkdir::WalkDir;
 
-use crate::{core::inventory::Module, defs, utils};
+use super::{
+    dedup::{self, DedupStats},
+    journal::{Journal, SyncStage},
+    manifest::{EntryKind, ModuleManifest, hash_file},
+};
+use crate::{
+    core::{inventory::Module, storage},
+    defs, utils,
+};
+
+// Manifests of the stored module copies, one `<id>.json` each.
+const MANIFEST_DIR: &str = ".manifests";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncAction {
+    Copy,
+    Update,
+    Remove,
+    Prune,
+    Skip,
+}
 
-pub fn perform_sync(modules: &[Module], target_base: &Path) -> Result<()> {
+// What a sync does, or would do, with one module or with an orphan in the storage root.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncChange {
+    pub module: String,
+    pub action: SyncAction,
+    pub reason: String,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+enum ModulePlan {
+    Skip,
+    Remove,
+    Full {
+        reason: String,
+        source: ModuleManifest,
+    },
+    Incremental {
+        source: ModuleManifest,
+        updates: Vec<PathBuf>,
+        removed: Vec<PathBuf>,
+    },
+}
+
+pub fn perform_sync(modules: &[Module], target_base: &Path) -> Result<DedupStats> {
     log::info!("Starting smart module sync to {}", target_base.display());
 
+    let journal = Journal::new(target_base);
+    if let Err(e) = journal.recover() {
+        log::error!("Failed to recover interrupted sync: {:#}", e);
+    }
+
     prune_orphaned_modules(modules, target_base)?;
 
     modules.par_iter().for_each(|module| {
         let dst = target_base.join(&module.id);
-        let dst_backup = target_base.join(format!(".backup_{}", module.id));
-
-        let has_content = defs::BUILTIN_PARTITIONS.iter().any(|p| {
-            let part_path = module.source_path.join(p);
+        let manifest_path = manifest_path(target_base, &module.id);
 
-            part_path.exists() && has_files_recursive(&part_path)
-        });
-
-        if has_content && should_sync(&module.source_path, &dst) {
-            log::info!("Syncing module: {} (Updated/New)", module.id);
-
-            let tmp_dst = target_base.join(format!(".tmp_{}", module.id));
-
-            if tmp_dst.exists() {
-                let _ = fs::remove_dir_all(&tmp_dst);
-            }
-
-            if let Err(e) = utils::sync_dir(&module.source_path, &tmp_dst, true) {
-                log::error!("Failed to sync module {}: {}", module.id, e);
-                let _ = fs::remove_dir_all(&tmp_dst);
+        let plan = match plan_module(module, Some(target_base)) {
+            Ok(plan) => plan,
+            Err(e) => {
+                log::error!("Failed to scan module {}: {:#}", module.id, e);
                 return;
             }
+        };
 
-            if let Err(e) = utils::prune_empty_dirs(&tmp_dst) {
-                log::warn!("Failed to prune empty dirs for {}: {}", module.id, e);
+        let (synced, source) = match plan {
+            ModulePlan::Skip => {
+                log::debug!("Skipping module: {}", module.id);
+                let _ = fs::remove_file(&manifest_path);
+                return;
             }
-
-            if let Err(e) = apply_overlay_opaque_flags(&tmp_dst) {
-                log::warn!(
-                    "Failed to apply overlay opaque xattrs for {}: {}",
-                    module.id,
-                    e
+            ModulePlan::Remove => {
+                // Storage can persist across boots, so drop copies of modules that emptied out.
+                log::info!(
+                    "Removing stale storage for module without content: {}",
+                    module.id
                 );
-            }
-
-            let mut backup_created = false;
-            if dst.exists() {
-                if let Err(e) = fs::rename(&dst, &dst_backup) {
-                    log::error!("Failed to backup existing module {}: {}", module.id, e);
-                    let _ = fs::remove_dir_all(&tmp_dst);
-                    return;
+                if let Err(e) = fs::remove_dir_all(&dst) {
+                    log::warn!("Failed to remove stale storage for {}: {}", module.id, e);
                 }
-                backup_created = true;
+                let _ = fs::remove_file(&manifest_path);
+                return;
+            }
+            ModulePlan::Full { reason, source } => {
+                log::info!("Syncing module: {} ({})", module.id, reason);
+                (sync_full(module, &dst, &journal, &manifest_path), source)
+            }
+            // Still saved below, touched files that turned out identical keep their hash.
+            ModulePlan::Incremental {
+                source,
+                updates,
+                removed,
+            } if updates.is_empty() && removed.is_empty() => {
+                log::debug!("Skipping module: {}", module.id);
+                (Ok(()), source)
+            }
+            ModulePlan::Incremental {
+                source,
+                updates,
+                removed,
+            } => {
+                let synced =
+                    match apply_changes(module, &dst, &journal, &manifest_path, &updates, &removed)
+                    {
+                        Ok(()) => {
+                            log::info!(
+                                "Updated {} entries of module: {}",
+                                updates.len() + removed.len(),
+                                module.id
+                            );
+                            Ok(())
+                        }
+                        Err(e) => {
+                            log::info!("Resyncing module {} in full: {:#}", module.id, e);
+                            sync_full(module, &dst, &journal, &manifest_path)
+                        }
+                    };
+                (synced, source)
             }
+        };
 
-            if let Err(e) = fs::rename(&tmp_dst, &dst) {
-                log::error!("Failed to commit atomic sync for {}: {}", module.id, e);
-                if backup_created {
-                    let _ = fs::rename(&dst_backup, &dst);
+        match synced {
+            Ok(()) => {
+                if let Err(e) = source.save(&manifest_path) {
+                    log::warn!("Failed to save sync manifest for {}: {:#}", module.id, e);
                 }
-                let _ = fs::remove_dir_all(&tmp_dst);
-                return;
             }
+            Err(e) => log::error!("Failed to sync module {}: {:#}", module.id, e),
+        }
+    });
+
+    let dedup = dedup::deduplicate(modules, target_base).unwrap_or_else(|e| {
+        log::warn!("Failed to deduplicate module files: {:#}", e);
+        DedupStats::default()
+    });
+    if dedup.files > 0 {
+        log::info!(
+            "{} module files share data with an identical copy, saving {}",
+            dedup.files,
+            storage::format_size(dedup.bytes)
+        );
+    }
+
+    Ok(dedup)
+}
+
+// Reports what perform_sync would do to `target_base` without touching it. None stands for
+// storage that starts out empty on every boot.
+pub fn dry_run(modules: &[Module], target_base: Option<&Path>) -> Result<Vec<SyncChange>> {
+    let mut changes = Vec::new();
+
+    if let Some(target_base) = target_base {
+        for path in orphaned_entries(modules, target_base)? {
+            let (entries, bytes) = tree_usage(&path);
+            changes.push(SyncChange {
+                module: path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                action: SyncAction::Prune,
+                reason: "module is no longer installed".to_string(),
+                entries,
+                bytes,
+            });
+        }
+    }
+
+    let planned: Vec<Result<SyncChange>> = modules
+        .par_iter()
+        .map(|module| {
+            let plan = plan_module(module, target_base)
+                .with_context(|| format!("Failed to scan module {}", module.id))?;
+            Ok(describe_plan(module, target_base, plan))
+        })
+        .collect();
+
+    for change in planned {
+        changes.push(change?);
+    }
 
-            if backup_created && let Err(e) = fs::remove_dir_all(&dst_backup) {
-                log::warn!("Failed to clean up backup for {}: {}", module.id, e);
+    Ok(changes)
+}
+
+fn describe_plan(module: &Module, target_base: Option<&Path>, plan: ModulePlan) -> SyncChange {
+    let change = |action, reason: &str, entries, bytes| SyncChange {
+        module: module.id.clone(),
+        action,
+        reason: reason.to_string(),
+        entries,
+        bytes,
+    };
+
+    match plan {
+        ModulePlan::Skip => change(SyncAction::Skip, "module has no partition content", 0, 0),
+        ModulePlan::Remove => {
+            let stored = target_base.map(|base| base.join(&module.id));
+            let (entries, bytes) = stored.map(|dst| tree_usage(&dst)).unwrap_or_default();
+            change(
+                SyncAction::Remove,
+                "module has no partition content left",
+                entries,
+                bytes,
+            )
+        }
+        ModulePlan::Full { reason, source } => {
+            let paths: Vec<&PathBuf> = source.entries.keys().collect();
+            change(
+                SyncAction::Copy,
+                &reason,
+                paths.len(),
+                file_bytes(&source, paths),
+            )
+        }
+        ModulePlan::Incremental {
+            source,
+            updates,
+            removed,
+        } => {
+            if updates.is_empty() && removed.is_empty() {
+                return change(SyncAction::Skip, "up to date", 0, 0);
             }
+            let reason = format!(
+                "{} to add or replace, {} to delete",
+                updates.len(),
+                removed.len()
+            );
+            change(
+                SyncAction::Update,
+                &reason,
+                updates.len() + removed.len(),
+                file_bytes(&source, updates.iter().collect()),
+            )
+        }
+    }
+}
+
+// Bytes of regular files among `paths` that would have to be copied.
+fn file_bytes(source: &ModuleManifest, paths: Vec<&PathBuf>) -> u64 {
+    paths
+        .into_iter()
+        .filter_map(|path| source.entries.get(path))
+        .filter(|entry| entry.kind == EntryKind::File)
+        .map(|entry| entry.size)
+        .sum()
+}
+
+fn tree_usage(path: &Path) -> (usize, u64) {
+    WalkDir::new(path)
+        .into_iter()
+        .flatten()
+        .fold((0, 0), |(entries, bytes), entry| {
+            let size = entry
+                .metadata()
+                .ok()
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+                .unwrap_or(0);
+            (entries + 1, bytes + size)
+        })
+}
+
+fn has_content(module: &Module) -> bool {
+    defs::BUILTIN_PARTITIONS.iter().any(|p| {
+        let part_path = module.source_path.join(p);
+
+        part_path.exists() && has_files_recursive(&part_path)
+    })
+}
+
+// Decides how the stored copy of a module is brought up to date, reading but never writing
+// storage. None for `target_base` means nothing is stored yet.
+fn plan_module(module: &Module, target_base: Option<&Path>) -> Result<ModulePlan> {
+    let dst = target_base.map(|base| base.join(&module.id));
+    let stored = dst.as_ref().filter(|dst| dst.exists());
+
+    if !has_content(module) {
+        return Ok(if stored.is_some() {
+            ModulePlan::Remove
+        } else {
+            ModulePlan::Skip
+        });
+    }
+
+    let mut source = ModuleManifest::scan(&module.source_path)?;
+
+    let (Some(target_base), Some(dst)) = (target_base, stored) else {
+        let reason = if target_base.is_some() {
+            "not in storage yet"
         } else {
-            log::debug!("Skipping module: {}", module.id);
+            "storage is rebuilt on every boot"
+        };
+        return Ok(ModulePlan::Full {
+            reason: reason.to_string(),
+            source,
+        });
+    };
+
+    let Some(previous) = ModuleManifest::load(&manifest_path(target_base, &module.id)) else {
+        return Ok(ModulePlan::Full {
+            reason: "no usable sync manifest".to_string(),
+            source,
+        });
+    };
+
+    match diff_module(module, dst, &previous, &mut source) {
+        Ok((updates, removed)) => Ok(ModulePlan::Incremental {
+            source,
+            updates,
+            removed,
+        }),
+        Err(e) => Ok(ModulePlan::Full {
+            reason: format!("{:#}", e),
+            source,
+        }),
+    }
+}
+
+fn manifest_path(target_base: &Path, id: &str) -> PathBuf {
+    target_base.join(MANIFEST_DIR).join(format!("{}.json", id))
+}
+
+// Copies the whole module into a temporary directory and swaps it in. Every step is
+// journaled, so a run that dies halfway is settled by the next one.
+fn sync_full(module: &Module, dst: &Path, journal: &Journal, manifest_path: &Path) -> Result<()> {
+    let dst_backup = journal.backup_path(&module.id);
+    let tmp_dst = journal.tmp_path(&module.id);
+
+    journal.record(&module.id, SyncStage::Copying)?;
+
+    // The old manifest no longer describes the copy once the swap starts.
+    if let Err(e) = fs::remove_file(manifest_path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        journal.clear(&module.id);
+        return Err(e).context("Failed to invalidate sync manifest");
+    }
+
+    if tmp_dst.exists() {
+        let _ = fs::remove_dir_all(&tmp_dst);
+    }
+
+    if let Err(e) = utils::sync_dir(&module.source_path, &tmp_dst, true) {
+        let _ = fs::remove_dir_all(&tmp_dst);
+        journal.clear(&module.id);
+        return Err(e);
+    }
+
+    if let Err(e) = utils::prune_empty_dirs(&tmp_dst) {
+        log::warn!("Failed to prune empty dirs for {}: {}", module.id, e);
+    }
+
+    if let Err(e) = apply_overlay_opaque_flags(&tmp_dst) {
+        log::warn!(
+            "Failed to apply overlay opaque xattrs for {}: {}",
+            module.id,
+            e
+        );
+    }
+
+    journal.record(&module.id, SyncStage::Copied)?;
+
+    let mut backup_created = false;
+    if dst.exists() {
+        if let Err(e) = fs::rename(dst, &dst_backup) {
+            let _ = fs::remove_dir_all(&tmp_dst);
+            journal.clear(&module.id);
+            return Err(e).context("Failed to backup existing module");
+        }
+        backup_created = true;
+    }
+
+    if let Err(e) = fs::rename(&tmp_dst, dst) {
+        if backup_created {
+            let _ = fs::rename(&dst_backup, dst);
+        }
+        let _ = fs::remove_dir_all(&tmp_dst);
+        journal.clear(&module.id);
+        return Err(e).context("Failed to commit atomic sync");
+    }
+
+    if backup_created {
+        journal.record(&module.id, SyncStage::Committed)?;
+        if let Err(e) = fs::remove_dir_all(&dst_backup) {
+            log::warn!("Failed to clean up backup for {}: {}", module.id, e);
+        }
+    }
+
+    journal.clear(&module.id);
+    Ok(())
+}
+
+// Entries to add or replace and entries to delete so the stored copy matches the source. An
+// error means the copy should be redone in full.
+fn diff_module(
+    module: &Module,
+    dst: &Path,
+    previous: &ModuleManifest,
+    source: &mut ModuleManifest,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let diff = previous.diff(source);
+    let src = &module.source_path;
+    source.inherit_hashes(previous);
+
+    let mut changed = Vec::new();
+    for path in diff.changed {
+        let (old, new) = (&previous.entries[&path], &source.entries[&path]);
+        if old.only_mtime_differs(new) {
+            let (src_hash, dst_hash) = (hash_file(&src.join(&path))?, hash_file(&dst.join(&path)));
+            if dst_hash.is_ok_and(|h| h == src_hash) {
+                if let Some(entry) = source.entries.get_mut(&path) {
+                    entry.hash = Some(src_hash);
+                }
+                continue;
+            }
         }
+        changed.push(path);
+    }
+
+    // Copies that went missing from storage are restored as well. Empty directories are
+    // pruned after every sync, so they are expected to be absent.
+    let missing = source.entries.iter().filter(|(path, entry)| {
+        entry.kind != EntryKind::Dir
+            && previous.entries.contains_key(*path)
+            && dst.join(path).symlink_metadata().is_err()
     });
+    changed.extend(missing.map(|(path, _)| path.clone()));
+
+    let mut updates: Vec<PathBuf> = diff.added.into_iter().chain(changed).collect();
+    updates.sort();
+    updates.dedup();
+
+    // Opaque xattrs are only set by a full copy.
+    let touches_marker = updates
+        .iter()
+        .chain(&diff.removed)
+        .any(|path| path.file_name() == Some(OsStr::new(defs::REPLACE_DIR_FILE_NAME)));
+    if touches_marker {
+        bail!("{} markers changed", defs::REPLACE_DIR_FILE_NAME);
+    }
+
+    Ok((updates, diff.removed))
+}
+
+fn apply_changes(
+    module: &Module,
+    dst: &Path,
+    journal: &Journal,
+    manifest_path: &Path,
+    updates: &[PathBuf],
+    removed: &[PathBuf],
+) -> Result<()> {
+    let src = &module.source_path;
+
+    // Without a manifest the next sync starts over, so a crash halfway through is harmless.
+    journal.record(&module.id, SyncStage::Patching)?;
+    fs::remove_file(manifest_path).context("Failed to invalidate sync manifest")?;
+
+    // Children sort after their parents, so removing in reverse never leaves a parent behind.
+    for path in removed.iter().rev() {
+        let target = dst.join(path);
+        let result = match target.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&target),
+            Ok(_) => fs::remove_file(&target),
+            Err(_) => continue,
+        };
+        result.with_context(|| format!("Failed to remove {}", target.display()))?;
+    }
+
+    for path in updates {
+        let target = dst.join(path);
+        if let Some(parent) = target.parent() {
+            utils::ensure_dir_exists(parent)?;
+        }
+        utils::copy_entry(&src.join(path), &target)
+            .with_context(|| format!("Failed to copy {}", path.display()))?;
+    }
+
+    if let Err(e) = utils::prune_empty_dirs(dst) {
+        log::warn!("Failed to prune empty dirs for {}: {}", module.id, e);
+    }
+
+    // Adding and deleting children bumped the mtime of their directories, deepest first so
+    // restoring a directory doesn't disturb one already restored.
+    let dirs: BTreeSet<&Path> = updates
+        .iter()
+        .chain(removed)
+        .filter_map(|path| path.parent())
+        .chain(
+            updates
+                .iter()
+                .map(PathBuf::as_path)
+                .filter(|path| src.join(path).is_dir()),
+        )
+        .collect();
+    for dir in dirs.into_iter().rev() {
+        let (src_dir, dst_dir) = (src.join(dir), dst.join(dir));
+        if src_dir.is_dir()
+            && dst_dir.is_dir()
+            && let Err(e) = utils::copy_metadata(&src_dir, &dst_dir)
+        {
+            log::warn!(
+                "Failed to preserve metadata of {}: {:#}",
+                dst_dir.display(),
+                e
+            );
+        }
+    }
 
+    journal.clear(&module.id);
     Ok(())
 }
 
@@ -91,59 +539,64 @@ fn apply_overlay_opaque_flags(root: &Path) -> Result<()> {
     Ok(())
 }
 
-fn prune_orphaned_modules(modules: &[Module], target_base: &Path) -> Result<()> {
+// Top-level entries of the storage root that belong to no installed module.
+fn orphaned_entries(modules: &[Module], target_base: &Path) -> Result<Vec<PathBuf>> {
     if !target_base.exists() {
-        return Ok(());
+        return Ok(Vec::new());
     }
 
     let active_ids: HashSet<&str> = modules.iter().map(|m| m.id.as_str()).collect();
 
-    let entries: Vec<_> = fs::read_dir(target_base)?.filter_map(|e| e.ok()).collect();
+    let orphans = fs::read_dir(target_base)?
+        .filter_map(|e| e.ok())
+        .filter(|entry| {
+            let name_os = entry.file_name();
 
-    entries.par_iter().for_each(|entry| {
-        let path = entry.path();
+            let name = name_os.to_string_lossy();
 
-        let name_os = entry.file_name();
+            name != "lost+found"
+                && name != "hybrid-mount"
+                && !name.starts_with('.')
+                && !active_ids.contains(name.as_ref())
+        })
+        .map(|entry| entry.path())
+        .collect();
 
-        let name = name_os.to_string_lossy();
+    Ok(orphans)
+}
 
-        if name != "lost+found"
-            && name != "hybrid-mount"
-            && !name.starts_with('.')
-            && !active_ids.contains(name.as_ref())
-        {
-            log::info!("Pruning orphaned module storage: {}", name);
+fn prune_orphaned_modules(modules: &[Module], target_base: &Path) -> Result<()> {
+    let orphans = orphaned_entries(modules, target_base)?;
 
-            if path.is_dir() {
-                if let Err(e) = fs::remove_dir_all(&path) {
-                    log::warn!("Failed to remove orphan dir {}: {}", name, e);
-                }
-            } else if let Err(e) = fs::remove_file(&path) {
-                log::warn!("Failed to remove orphan file {}: {}", name, e);
+    orphans.par_iter().for_each(|path| {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+
+        log::info!("Pruning orphaned module storage: {}", name);
+
+        if path.is_dir() {
+            if let Err(e) = fs::remove_dir_all(path) {
+                log::warn!("Failed to remove orphan dir {}: {}", name, e);
             }
+        } else if let Err(e) = fs::remove_file(path) {
+            log::warn!("Failed to remove orphan file {}: {}", name, e);
         }
     });
 
-    Ok(())
-}
-
-fn should_sync(src: &Path, dst: &Path) -> bool {
-    if !dst.exists() {
-        return true;
-    }
-
-    let src_prop = src.join("module.prop");
-
-    let dst_prop = dst.join("module.prop");
-
-    if !src_prop.exists() || !dst_prop.exists() {
-        return true;
+    let active_ids: HashSet<&str> = modules.iter().map(|m| m.id.as_str()).collect();
+    if let Ok(manifests) = fs::read_dir(target_base.join(MANIFEST_DIR)) {
+        for entry in manifests.flatten() {
+            let path = entry.path();
+            let id = path.file_stem().map(|s| s.to_string_lossy());
+            if !id.is_some_and(|id| active_ids.contains(id.as_ref())) {
+                let _ = fs::remove_file(&path);
+            }
+        }
     }
 
-    match (fs::read(&src_prop), fs::read(&dst_prop)) {
-        (Ok(s), Ok(d)) => s != d,
-        _ => true,
-    }
+    Ok(())
 }
 
 fn has_files_recursive(path: &Path) -> bool {
@@ -157,3 +610,99 @@ fn has_files_recursive(path: &Path) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, os::unix::fs::MetadataExt};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn module(sources: &Path, id: &str) -> Module {
+        Module {
+            id: id.to_string(),
+            source_path: sources.join(id),
+            rules: Default::default(),
+        }
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    // Every entry below `root` with its contents, mode and change time.
+    fn snapshot(root: &Path) -> BTreeMap<PathBuf, (Vec<u8>, u32, i64, i64)> {
+        WalkDir::new(root)
+            .into_iter()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let meta = entry.path().symlink_metadata().unwrap();
+                let content = if meta.is_file() {
+                    fs::read(entry.path()).unwrap()
+                } else {
+                    Vec::new()
+                };
+                let key = (content, meta.mode(), meta.ctime(), meta.ctime_nsec());
+                (entry.path().to_path_buf(), key)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dry_run_reports_every_change_without_writing() {
+        let sources = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        let src = sources.path();
+        write(&src.join("same/system/etc/a"), "a");
+        write(&src.join("changed/system/etc/b"), "b");
+        write(&src.join("emptied/system/etc/c"), "c");
+        write(&src.join("dropped/system/etc/d"), "d");
+        let synced = ["same", "changed", "emptied", "dropped"].map(|id| module(src, id));
+        perform_sync(&synced, target.path()).unwrap();
+
+        write(&src.join("changed/system/etc/b"), "bigger");
+        write(&src.join("changed/system/etc/new"), "new");
+        fs::remove_dir_all(src.join("emptied/system")).unwrap();
+        write(&src.join("added/vendor/etc/e"), "e");
+        write(&src.join("bare/module.prop"), "id=bare\n");
+        let modules = ["same", "changed", "emptied", "added", "bare"].map(|id| module(src, id));
+
+        let before = snapshot(target.path());
+        let changes = dry_run(&modules, Some(target.path())).unwrap();
+        assert_eq!(before, snapshot(target.path()));
+
+        let summary: Vec<(&str, SyncAction, usize, u64)> = changes
+            .iter()
+            .map(|c| (c.module.as_str(), c.action, c.entries, c.bytes))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("dropped", SyncAction::Prune, 4, 1),
+                ("same", SyncAction::Skip, 0, 0),
+                ("changed", SyncAction::Update, 2, 9),
+                ("emptied", SyncAction::Remove, 4, 1),
+                ("added", SyncAction::Copy, 3, 1),
+                ("bare", SyncAction::Skip, 0, 0),
+            ]
+        );
+        assert_eq!(changes[0].reason, "module is no longer installed");
+        assert_eq!(changes[4].reason, "not in storage yet");
+    }
+
+    #[test]
+    fn dry_run_without_storage_copies_everything() {
+        let sources = TempDir::new().unwrap();
+        write(&sources.path().join("mod/system/etc/a"), "abc");
+        let modules = [module(sources.path(), "mod")];
+
+        let changes = dry_run(&modules, None).unwrap();
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].action, SyncAction::Copy);
+        assert_eq!(changes[0].reason, "storage is rebuilt on every boot");
+        assert_eq!((changes[0].entries, changes[0].bytes), (3, 3));
+    }
+}
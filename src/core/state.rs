@@ -7,14 +7,28 @@ use std::{
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::{defs, utils::fs::xattr};
+use crate::{
+    core::{
+        ops::dedup::DedupStats,
+        storage::{StorageAttempt, StorageKind},
+    },
+    defs,
+    sys::{
+        caps::{self, Caps},
+        tmpfs_xattr::XattrProbeMethod,
+    },
+};
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct RuntimeState {
     pub timestamp: u64,
     pub pid: u32,
-    pub storage_mode: String,
+    pub storage_mode: StorageKind,
     pub mount_point: PathBuf,
+    #[serde(default)]
+    pub storage_attempts: Vec<StorageAttempt>,
+    #[serde(default)]
+    pub dedup: DedupStats,
     pub overlay_modules: Vec<String>,
     pub magic_modules: Vec<String>,
     #[serde(default)]
@@ -23,13 +37,19 @@ pub struct RuntimeState {
     pub zygisksu_enforce: bool,
     #[serde(default)]
     pub tmpfs_xattr_supported: bool,
+    #[serde(default)]
+    pub tmpfs_xattr_method: XattrProbeMethod,
+    #[serde(default)]
+    pub caps: Caps,
 }
 
 impl RuntimeState {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        storage_mode: String,
+        storage_mode: StorageKind,
         mount_point: PathBuf,
+        storage_attempts: Vec<StorageAttempt>,
+        dedup: DedupStats,
         overlay_modules: Vec<String>,
         magic_modules: Vec<String>,
         active_mounts: Vec<String>,
@@ -44,18 +64,23 @@ impl RuntimeState {
         let pid = std::process::id();
 
         let zygisksu_enforce = crate::utils::check_zygisksu_enforce_status();
-        let tmpfs_xattr_supported = xattr::is_overlay_xattr_supported().unwrap_or(false);
+        let caps = caps::probe().clone();
+        let tmpfs_xattr = caps.kernel.tmpfs_xattr;
 
         Self {
             timestamp,
             pid,
             storage_mode,
             mount_point,
+            storage_attempts,
+            dedup,
             overlay_modules,
             magic_modules,
             active_mounts,
             zygisksu_enforce,
-            tmpfs_xattr_supported,
+            tmpfs_xattr_supported: tmpfs_xattr.supported,
+            tmpfs_xattr_method: tmpfs_xattr.method,
+            caps,
         }
     }
 
@@ -0,0 +1,302 @@
+// LZ4 block encoder for the built-in EROFS writer. EROFS stores each compressed cluster in a
+// fixed number of blocks, so instead of compressing a fixed amount of input it packs as much
+// input as fits in the output budget, like LZ4_compress_destSize.
+//
+// lz4 and lz4hc write the same block format and only differ in how hard they look for
+// matches, so both go through the same encoder with a different search depth.
+
+const MIN_MATCH: usize = 4;
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const MAX_DISTANCE: usize = 65535;
+const HASH_BITS: u32 = 12;
+
+struct Sequence {
+    literal_start: usize,
+    literal_len: usize,
+    offset: usize,
+    match_len: usize,
+}
+
+impl Sequence {
+    fn end(&self) -> usize {
+        self.literal_start + self.literal_len + self.match_len
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + extra_len(self.literal_len)
+            + self.literal_len
+            + 2
+            + extra_len(self.match_len - MIN_MATCH)
+    }
+}
+
+// Returns how many bytes of `src` were consumed and the compressed block, which is never
+// longer than `budget`. `depth` is how many earlier positions are tried for each match.
+pub fn compress_dest_size(src: &[u8], budget: usize, depth: usize) -> (usize, Vec<u8>) {
+    let sequences = find_sequences(src, depth.max(1));
+
+    // (consumed, sequences kept, trailing literals)
+    let mut best = (0, 0, 0);
+    let mut cost = 0;
+    let mut end = 0;
+
+    for kept in 0..=sequences.len() {
+        let mut min_tail = 0;
+        if kept > 0 {
+            let last = &sequences[kept - 1];
+            cost += last.encoded_len();
+            end = last.end();
+            // The stream has to end in literals, and the last match must start 12 bytes
+            // before the end.
+            min_tail = LAST_LITERALS.max(MF_LIMIT.saturating_sub(last.match_len));
+        }
+
+        if cost >= budget {
+            break;
+        }
+
+        let room = budget - cost;
+        let mut tail = (src.len() - end).min(room - 1);
+        while tail > 0 && 1 + extra_len(tail) + tail > room {
+            tail -= 1;
+        }
+
+        if tail >= min_tail && end + tail > best.0 {
+            best = (end + tail, kept, tail);
+        }
+    }
+
+    let (consumed, kept, tail) = best;
+    if consumed == 0 {
+        return (0, Vec::new());
+    }
+
+    let mut out = Vec::with_capacity(budget);
+    for seq in &sequences[..kept] {
+        push_token(&mut out, seq.literal_len, Some(seq.match_len - MIN_MATCH));
+        out.extend_from_slice(&src[seq.literal_start..seq.literal_start + seq.literal_len]);
+        out.extend_from_slice(&(seq.offset as u16).to_le_bytes());
+        push_extra(&mut out, seq.match_len - MIN_MATCH);
+    }
+
+    let tail_start = consumed - tail;
+    push_token(&mut out, tail, None);
+    out.extend_from_slice(&src[tail_start..consumed]);
+
+    (consumed, out)
+}
+
+// Greedy hash-chain matcher. With a depth of 1 it is the single-probe strategy of the
+// reference fast compressor, deeper searches keep the longest match they find.
+fn find_sequences(src: &[u8], depth: usize) -> Vec<Sequence> {
+    let mut sequences = Vec::new();
+    if src.len() <= MF_LIMIT {
+        return sequences;
+    }
+
+    let match_limit = src.len() - LAST_LITERALS;
+    let search_end = src.len() - MF_LIMIT;
+    // positions are stored off by one so that zero means empty
+    let mut table = vec![0u32; 1 << HASH_BITS];
+    let mut chain = vec![0u32; search_end + 1];
+    let mut inserted = 0;
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos <= search_end {
+        while inserted <= pos {
+            let slot = hash(read_u32(src, inserted));
+            chain[inserted] = table[slot];
+            table[slot] = inserted as u32 + 1;
+            inserted += 1;
+        }
+
+        let word = read_u32(src, pos);
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = chain[pos] as usize;
+
+        for _ in 0..depth {
+            if candidate == 0 || pos - (candidate - 1) > MAX_DISTANCE {
+                break;
+            }
+
+            let start = candidate - 1;
+            if read_u32(src, start) == word {
+                let mut len = MIN_MATCH;
+                while pos + len < match_limit && src[start + len] == src[pos + len] {
+                    len += 1;
+                }
+                if best.is_none_or(|(_, best_len)| len > best_len) {
+                    best = Some((pos - start, len));
+                }
+            }
+
+            candidate = chain[start] as usize;
+        }
+
+        let Some((offset, len)) = best else {
+            pos += 1;
+            continue;
+        };
+
+        sequences.push(Sequence {
+            literal_start: anchor,
+            literal_len: pos - anchor,
+            offset,
+            match_len: len,
+        });
+
+        pos += len;
+        anchor = pos;
+    }
+
+    sequences
+}
+
+fn read_u32(src: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([src[pos], src[pos + 1], src[pos + 2], src[pos + 3]])
+}
+
+fn hash(word: u32) -> usize {
+    (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn extra_len(len: usize) -> usize {
+    if len < 15 { 0 } else { (len - 15) / 255 + 1 }
+}
+
+fn push_token(out: &mut Vec<u8>, literal_len: usize, match_len: Option<usize>) {
+    let literal_nibble = literal_len.min(15) as u8;
+    let match_nibble = match_len.unwrap_or(0).min(15) as u8;
+    out.push((literal_nibble << 4) | match_nibble);
+    push_extra(out, literal_len);
+}
+
+fn push_extra(out: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+
+    let mut rest = len - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    // Reference block decoder, following the format rather than the encoder.
+    pub fn decompress(block: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        let mut pos = 0;
+
+        loop {
+            let token = block[pos];
+            pos += 1;
+
+            let literal_len = read_len(block, &mut pos, (token >> 4) as usize);
+            out.extend_from_slice(&block[pos..pos + literal_len]);
+            pos += literal_len;
+            if pos == block.len() {
+                return out;
+            }
+
+            let offset = u16::from_le_bytes([block[pos], block[pos + 1]]) as usize;
+            pos += 2;
+            assert!(offset > 0 && offset <= out.len(), "bad offset {offset}");
+
+            let match_len = read_len(block, &mut pos, (token & 0xf) as usize) + MIN_MATCH;
+            let start = out.len() - offset;
+            for i in 0..match_len {
+                out.push(out[start + i]);
+            }
+        }
+    }
+
+    fn read_len(block: &[u8], pos: &mut usize, nibble: usize) -> usize {
+        let mut len = nibble;
+        if nibble == 15 {
+            loop {
+                let byte = block[*pos];
+                *pos += 1;
+                len += byte as usize;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        len
+    }
+
+    pub fn sample(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        let words: [&[u8]; 6] = [
+            b"system ",
+            b"vendor ",
+            b"lib64/",
+            b"overlay ",
+            b"\n",
+            b"0x1f ",
+        ];
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            out.extend_from_slice(words[(state % words.len() as u64) as usize]);
+        }
+        out.truncate(len);
+        out
+    }
+
+    pub fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn roundtrip(src: &[u8], budget: usize, depth: usize) -> usize {
+        let (consumed, block) = compress_dest_size(src, budget, depth);
+        assert!(block.len() <= budget);
+        assert_eq!(decompress(&block), &src[..consumed]);
+        consumed
+    }
+
+    #[test]
+    fn decodes_to_the_consumed_input() {
+        for depth in [1, 256] {
+            let text = sample(64 * 1024, 7);
+            assert!(roundtrip(&text, 4096, depth) > 4096);
+
+            let zeros = vec![0u8; 300 * 1024];
+            assert!(roundtrip(&zeros, 4096, depth) > 200 * 1024);
+
+            let short = b"hello, erofs";
+            assert_eq!(roundtrip(short, 4096, depth), short.len());
+        }
+    }
+
+    #[test]
+    fn stops_at_the_budget_on_incompressible_input() {
+        let data = noise(16 * 1024);
+        let consumed = roundtrip(&data, 4096, 1);
+        assert!(consumed > 0 && consumed < 4096);
+    }
+
+    #[test]
+    fn deeper_search_packs_more_input() {
+        let text = sample(64 * 1024, 11);
+        assert!(roundtrip(&text, 4096, 256) >= roundtrip(&text, 4096, 1));
+    }
+}
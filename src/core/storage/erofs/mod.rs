@@ -0,0 +1,298 @@
+mod lz4;
+mod writer;
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use rustix::mount::{UnmountFlags, unmount as umount};
+
+use super::{
+    SizeEstimate, StorageBackend, StorageKind, create_magic_workspace, ensure_loop_control,
+    finish_staging, hide, make_private, mount_image_ro, prepare_staging,
+};
+use crate::{
+    conf::config::{Config, ErofsConfig, TmpfsConfig},
+    core::{
+        inventory::Module,
+        ops::{dedup::DedupStats, manifest, sync},
+    },
+    defs,
+    sys::{caps, mkfs_erofs, mount::is_mounted},
+    utils::{self, lsetfilecon},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErofsBuilder {
+    Mkfs,
+    Builtin,
+}
+
+impl ErofsBuilder {
+    // Part of the image digest, so switching builders or upgrading mkfs.erofs rebuilds the image.
+    fn tag(self) -> String {
+        match (self, &caps::probe().tools.mkfs_erofs) {
+            (Self::Mkfs, Some(info)) => format!("mkfs.erofs {}", info.version),
+            (Self::Mkfs, None) => "mkfs.erofs".to_string(),
+            (Self::Builtin, _) => "built-in".to_string(),
+        }
+    }
+}
+
+// Modules are synced into a staging tmpfs, then packed into a read-only image that is
+// mounted at the final target on commit.
+pub struct ErofsBackend {
+    staging: PathBuf,
+    target: PathBuf,
+    image: PathBuf,
+    options: ErofsConfig,
+    mount_source: String,
+    tmpfs: TmpfsConfig,
+    estimate: SizeEstimate,
+    disable_umount: bool,
+    builder: Option<ErofsBuilder>,
+    // inventory digest the image is built from, and whether the cached image matches it
+    cache_key: Option<String>,
+    cache_hit: bool,
+    dedup: DedupStats,
+    committed: bool,
+}
+
+impl ErofsBackend {
+    pub fn new(mnt_base: &Path, img_path: &Path, config: &Config, estimate: SizeEstimate) -> Self {
+        Self {
+            staging: Path::new(defs::RUN_DIR).join("erofs_staging"),
+            target: mnt_base.to_path_buf(),
+            image: img_path.with_extension("erofs"),
+            options: config.erofs.clone(),
+            mount_source: config.mountsource.clone(),
+            tmpfs: config.tmpfs.clone(),
+            estimate,
+            disable_umount: config.disable_umount,
+            builder: None,
+            cache_key: None,
+            cache_hit: false,
+            dedup: DedupStats::default(),
+            committed: false,
+        }
+    }
+
+    // Returns true when the cached image was built from the same inventory, in which case
+    // staging and packing can be skipped.
+    fn use_cached_image(&mut self, key: String) -> bool {
+        let cached_key =
+            fs::read_to_string(sidecar_path(&self.image, "sha256")).unwrap_or_default();
+
+        self.cache_hit = self.image.is_file() && cached_key.trim() == key;
+        self.cache_key = Some(key);
+        self.cache_hit
+    }
+}
+
+impl StorageBackend for ErofsBackend {
+    fn kind(&self) -> StorageKind {
+        StorageKind::Erofs
+    }
+
+    fn mount_point(&self) -> &Path {
+        if self.committed {
+            &self.target
+        } else {
+            &self.staging
+        }
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(caps::probe().kernel.erofs, "Kernel does not support EROFS");
+        ensure_loop_control()?;
+
+        // Checked before staging, so an unusable builder falls back instead of failing at
+        // commit.
+        self.builder = Some(erofs_builder(&self.options).context("Cannot build an EROFS image")?);
+
+        self.estimate
+            .check_tmpfs(&self.tmpfs)
+            .context("Modules do not fit in the staging tmpfs")?;
+
+        Ok(())
+    }
+
+    fn populate(&mut self, modules: &[Module]) -> Result<DedupStats> {
+        let builder = self.builder.unwrap_or(ErofsBuilder::Mkfs);
+        let hashes_path = sidecar_path(&self.image, "files.json");
+        let mut hashes = manifest::HashCache::load(&hashes_path);
+
+        let digest =
+            manifest::inventory_digest(modules, &self.options, &builder.tag(), &mut hashes);
+        let cache_hit = match digest {
+            Ok(digest) => {
+                if let Err(e) = hashes.save(&hashes_path) {
+                    log::warn!("Failed to save EROFS content hashes: {:#}", e);
+                }
+                self.use_cached_image(digest)
+            }
+            Err(e) => {
+                log::warn!("Failed to fingerprint modules, rebuilding EROFS: {:#}", e);
+                false
+            }
+        };
+
+        // The dedup stats of the build that produced the image still describe it.
+        if cache_hit {
+            self.dedup = fs::read_to_string(sidecar_path(&self.image, "dedup.json"))
+                .ok()
+                .and_then(|content| serde_json::from_str(&content).ok())
+                .unwrap_or_default();
+            return Ok(self.dedup);
+        }
+
+        prepare_staging(
+            &self.staging,
+            &self.mount_source,
+            &self.tmpfs,
+            self.disable_umount,
+        )?;
+
+        self.dedup = sync::perform_sync(modules, &self.staging)?;
+        create_magic_workspace(modules, &self.staging);
+
+        Ok(self.dedup)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let digest_path = sidecar_path(&self.image, "sha256");
+        let dedup_path = sidecar_path(&self.image, "dedup.json");
+
+        if self.cache_hit {
+            log::info!(
+                "EROFS image is up to date, reusing {}",
+                self.image.display()
+            );
+        } else {
+            // Never leave a digest that could vouch for a half-written image.
+            let _ = fs::remove_file(&digest_path);
+            let _ = fs::remove_file(&dedup_path);
+            if self.image.exists() {
+                fs::remove_file(&self.image).context("Failed to remove stale EROFS image")?;
+            }
+
+            let builder = self.builder.unwrap_or(ErofsBuilder::Mkfs);
+            create_image(builder, &self.staging, &self.image, &self.options)
+                .context("Failed to pack EROFS image")?;
+
+            finish_staging(&self.staging);
+        }
+
+        if let Err(e) = mount_image_ro(&self.image, &self.target, "erofs") {
+            let _ = fs::remove_file(&digest_path);
+            return Err(e).context("Failed to mount finalized EROFS image");
+        }
+
+        if !self.cache_hit
+            && let Some(key) = &self.cache_key
+        {
+            let recorded = serde_json::to_string(&self.dedup)
+                .context("Failed to serialize dedup stats")
+                .and_then(|stats| utils::atomic_write(&dedup_path, stats))
+                .and_then(|()| utils::atomic_write(&digest_path, key));
+            if let Err(e) = recorded {
+                log::warn!("Failed to record EROFS image digest: {:#}", e);
+            }
+        }
+
+        make_private(&self.target);
+        hide(&self.target, self.disable_umount);
+
+        self.committed = true;
+        Ok(())
+    }
+
+    fn teardown(&mut self) {
+        if is_mounted(&self.staging) {
+            let _ = umount(&self.staging, UnmountFlags::DETACH);
+        }
+        let _ = fs::remove_dir_all(&self.staging);
+
+        if self.committed && is_mounted(&self.target) {
+            let _ = umount(&self.target, UnmountFlags::DETACH);
+        }
+        self.committed = false;
+    }
+
+    fn describe(&self) -> String {
+        match self.builder {
+            Some(ErofsBuilder::Builtin) => "EROFS (built-in writer)".to_string(),
+            _ => "EROFS".to_string(),
+        }
+    }
+}
+
+// Prefers mkfs.erofs and falls back to the built-in writer. Errors when neither can honour
+// the options.
+pub fn erofs_builder(erofs: &ErofsConfig) -> Result<ErofsBuilder> {
+    let mkfs_error = match &caps::probe().tools.mkfs_erofs {
+        Some(info) => match info.check(erofs) {
+            Ok(()) => return Ok(ErofsBuilder::Mkfs),
+            Err(e) => e,
+        },
+        None => anyhow!("mkfs.erofs not found"),
+    };
+
+    writer::check(erofs)
+        .map(|()| ErofsBuilder::Builtin)
+        .map_err(|e| anyhow!("{:#}, and {:#}", mkfs_error, e))
+}
+
+// Files kept next to the image, e.g. `modules.erofs.sha256`.
+fn sidecar_path(image_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = image_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn create_image(
+    builder: ErofsBuilder,
+    src_dir: &Path,
+    image_path: &Path,
+    options: &ErofsConfig,
+) -> Result<()> {
+    match builder {
+        ErofsBuilder::Mkfs => run_mkfs_erofs(src_dir, image_path, options)?,
+        ErofsBuilder::Builtin => {
+            log::info!("Packing EROFS image with the built-in writer");
+            writer::build_image(src_dir, image_path, options)?;
+        }
+    }
+
+    let _ = fs::set_permissions(image_path, fs::Permissions::from_mode(0o644));
+    lsetfilecon(image_path, "u:object_r:ksu_file:s0")?;
+    Ok(())
+}
+
+fn run_mkfs_erofs(src_dir: &Path, image_path: &Path, options: &ErofsConfig) -> Result<()> {
+    let args = mkfs_erofs::build_args(options);
+    log::info!("Packing EROFS image with options: {}", args.join(" "));
+
+    let output = Command::new(mkfs_erofs::command())
+        .args(&args)
+        .arg(image_path)
+        .arg(src_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .context("Failed to execute mkfs.erofs")?;
+
+    if !output.status.success() {
+        bail!(
+            "Failed to create EROFS image: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1122 @@
+// Built-in EROFS writer, used when mkfs.erofs is not available. It writes extended inodes
+// with inline xattrs, tail-packed directories and symlinks, and either plain or lz4 data.
+// lz4hc writes the same format and only searches harder for matches.
+//
+// Image layout: block 0 holds the superblock, file data follows from block 1, then the
+// metadata area (inodes, inline data, compression indexes), then the directory blocks that
+// did not fit inline.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use extattr::{lgetxattr, llistxattr};
+
+use super::lz4;
+use crate::conf::config::{ErofsCompressor, ErofsConfig};
+
+const BLOCK_SIZE: usize = 4096;
+const BLOCK_BITS: u8 = 12;
+const SUPER_OFFSET: u64 = 1024;
+const MAGIC: u32 = 0xE0F5_E1E2;
+const FEATURE_INCOMPAT_ZERO_PADDING: u32 = 0x1;
+
+const INODE_SLOT: usize = 32;
+const INODE_SIZE: usize = 64;
+const XATTR_HEADER_SIZE: usize = 12;
+const DIRENT_SIZE: usize = 12;
+// map header plus the 8 reserved bytes of the legacy (full) index format
+const MAP_HEADER_SIZE: usize = 16;
+const INDEX_SIZE: usize = 8;
+
+const LAYOUT_FLAT_PLAIN: u16 = 0;
+const LAYOUT_COMPRESSED_FULL: u16 = 1;
+const LAYOUT_FLAT_INLINE: u16 = 2;
+
+const LCLUSTER_PLAIN: u16 = 0;
+const LCLUSTER_HEAD: u16 = 1;
+const LCLUSTER_NONHEAD: u16 = 2;
+
+// Upper bound on the input packed into one compressed block.
+const MAX_CLUSTER_INPUT: usize = 16 * BLOCK_SIZE;
+// what mkfs.erofs uses when lz4hc has no level
+const LZ4HC_DEFAULT_LEVEL: u32 = 9;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_FIFO: u8 = 5;
+const FT_SOCK: u8 = 6;
+const FT_SYMLINK: u8 = 7;
+
+// Name prefixes EROFS can index. Anything else can't be stored and is skipped.
+const XATTR_PREFIXES: &[(&str, u8)] = &[
+    ("system.posix_acl_access", 2),
+    ("system.posix_acl_default", 3),
+    ("user.", 1),
+    ("trusted.", 4),
+    ("security.", 6),
+];
+
+enum Kind {
+    Dir(Vec<(Vec<u8>, usize)>),
+    File,
+    Symlink(Vec<u8>),
+    Special(u32),
+}
+
+struct Node {
+    path: PathBuf,
+    meta: fs::Metadata,
+    kind: Kind,
+    parent: usize,
+    xattrs: Vec<u8>,
+    size: u64,
+    layout: u16,
+    // raw block address, block count or device number, depending on the layout
+    raw: u32,
+    // data stored after the xattrs: an inline tail or the compression indexes
+    trailer: Vec<u8>,
+    nid: u64,
+}
+
+impl Node {
+    fn new(path: PathBuf, parent: usize) -> Result<Self> {
+        let meta = path
+            .symlink_metadata()
+            .with_context(|| format!("Failed to stat {}", path.display()))?;
+        let file_type = meta.file_type();
+
+        let kind = if file_type.is_dir() {
+            Kind::Dir(Vec::new())
+        } else if file_type.is_file() {
+            Kind::File
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&path)
+                .with_context(|| format!("Failed to read link {}", path.display()))?;
+            Kind::Symlink(target.as_os_str().as_bytes().to_vec())
+        } else {
+            Kind::Special(encode_dev(meta.rdev()))
+        };
+
+        let xattrs = read_xattrs(&path)?;
+
+        Ok(Self {
+            path,
+            meta,
+            kind,
+            parent,
+            xattrs,
+            size: 0,
+            layout: LAYOUT_FLAT_PLAIN,
+            raw: 0,
+            trailer: Vec::new(),
+            nid: 0,
+        })
+    }
+
+    fn file_type(&self) -> u8 {
+        let file_type = self.meta.file_type();
+        match &self.kind {
+            Kind::Dir(_) => FT_DIR,
+            Kind::File => FT_REG_FILE,
+            Kind::Symlink(_) => FT_SYMLINK,
+            Kind::Special(_) if file_type.is_char_device() => FT_CHRDEV,
+            Kind::Special(_) if file_type.is_block_device() => FT_BLKDEV,
+            Kind::Special(_) if file_type.is_fifo() => FT_FIFO,
+            Kind::Special(_) => FT_SOCK,
+        }
+    }
+
+    fn record_len(&self) -> usize {
+        INODE_SIZE + self.xattrs.len() + self.trailer.len()
+    }
+
+    // Whether a tail of `len` bytes fits in the same block as the inode.
+    fn can_inline(&self, len: usize) -> bool {
+        len > 0 && INODE_SIZE + self.xattrs.len() + len <= BLOCK_SIZE
+    }
+}
+
+pub fn check(options: &ErofsConfig) -> Result<()> {
+    if matches!(
+        options.compressor,
+        ErofsCompressor::Lzma | ErofsCompressor::Deflate
+    ) {
+        bail!(
+            "the built-in EROFS writer does not support {:?} compression",
+            options.compressor
+        );
+    }
+
+    if options.dedupe || options.fragments {
+        bail!("the built-in EROFS writer does not support dedupe or fragments");
+    }
+
+    if let Some(size) = options.pcluster_size
+        && size as usize != BLOCK_SIZE
+    {
+        bail!("the built-in EROFS writer only supports a pcluster_size of {BLOCK_SIZE}");
+    }
+
+    Ok(())
+}
+
+pub fn build_image(src_dir: &Path, image_path: &Path, options: &ErofsConfig) -> Result<()> {
+    check(options)?;
+
+    let mut nodes = collect(src_dir)?;
+    let depth = search_depth(options);
+
+    let file = File::create(image_path)
+        .with_context(|| format!("Failed to create {}", image_path.display()))?;
+    let mut image = Image {
+        out: BufWriter::new(file),
+        blocks: 0,
+    };
+
+    // block 0 is filled in with the superblock at the end
+    image.write_blocks(&[0; BLOCK_SIZE])?;
+
+    let mut compressed_any = false;
+    for node in nodes.iter_mut() {
+        compressed_any |= write_data(&mut image, node, depth)?;
+    }
+
+    // nids are not known yet, but they don't change the size of a directory
+    for i in 0..nodes.len() {
+        if matches!(nodes[i].kind, Kind::Dir(_)) {
+            let size = pack_dirents(&dir_entries(&nodes, i))?.len();
+            let node = &mut nodes[i];
+            node.size = size as u64;
+
+            let tail = size % BLOCK_SIZE;
+            if node.can_inline(tail) {
+                node.layout = LAYOUT_FLAT_INLINE;
+                node.trailer = vec![0; tail];
+            }
+        }
+    }
+
+    let meta_blkaddr = image.blocks;
+    let meta_len = assign_nids(&mut nodes);
+    let meta_blocks = meta_len.div_ceil(BLOCK_SIZE);
+
+    // directory blocks go after the metadata, now that child nids are known
+    let mut dir_blocks = Vec::new();
+    let mut next_block = meta_blkaddr + meta_blocks as u32;
+    for i in 0..nodes.len() {
+        if !matches!(nodes[i].kind, Kind::Dir(_)) {
+            continue;
+        }
+
+        let content = pack_dirents(&dir_entries(&nodes, i))?;
+        let node = &mut nodes[i];
+        let inline_len = node.trailer.len();
+        let block_len = content.len() - inline_len;
+
+        node.trailer.copy_from_slice(&content[block_len..]);
+        if block_len > 0 {
+            node.raw = next_block;
+            next_block += block_len.div_ceil(BLOCK_SIZE) as u32;
+            dir_blocks.push(content[..block_len].to_vec());
+        }
+    }
+
+    let mut meta = vec![0u8; meta_blocks * BLOCK_SIZE];
+    for (i, node) in nodes.iter().enumerate() {
+        let start = node.nid as usize * INODE_SLOT;
+        let record = encode_inode(node, i as u32 + 1, options, &nodes)?;
+        meta[start..start + record.len()].copy_from_slice(&record);
+    }
+    image.write_blocks(&meta)?;
+
+    for block in &dir_blocks {
+        image.write_blocks(block)?;
+    }
+
+    let root_nid = u16::try_from(nodes[0].nid).context("root inode is out of range")?;
+    let superblock = encode_superblock(
+        root_nid,
+        nodes.len() as u64,
+        image.blocks,
+        meta_blkaddr,
+        compressed_any,
+        options,
+    );
+
+    image.finish(&superblock)
+}
+
+struct Image {
+    out: BufWriter<File>,
+    blocks: u32,
+}
+
+impl Image {
+    // Writes `data` at the current end, padded to a whole block, and returns its address.
+    fn write_blocks(&mut self, data: &[u8]) -> Result<u32> {
+        let start = self.blocks;
+        self.out.write_all(data)?;
+
+        let padding = data.len().next_multiple_of(BLOCK_SIZE) - data.len();
+        self.out.write_all(&vec![0; padding])?;
+
+        self.blocks += (data.len() + padding).div_ceil(BLOCK_SIZE) as u32;
+        Ok(start)
+    }
+
+    fn copy_blocks(&mut self, reader: &mut dyn Read, len: u64) -> Result<u32> {
+        let start = self.blocks;
+        let copied = io::copy(&mut reader.take(len), &mut self.out)?;
+        ensure!(copied == len, "file changed while packing");
+
+        let padding = len.next_multiple_of(BLOCK_SIZE as u64) - len;
+        self.out.write_all(&vec![0; padding as usize])?;
+
+        self.blocks += (len + padding).div_ceil(BLOCK_SIZE as u64) as u32;
+        Ok(start)
+    }
+
+    fn rewind_to(&mut self, block: u32) -> Result<()> {
+        self.out
+            .seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+        self.blocks = block;
+        Ok(())
+    }
+
+    fn finish(mut self, superblock: &[u8]) -> Result<()> {
+        let total = self.blocks as u64 * BLOCK_SIZE as u64;
+
+        self.out.seek(SeekFrom::Start(SUPER_OFFSET))?;
+        self.out.write_all(superblock)?;
+
+        let file = self
+            .out
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to flush EROFS image")?;
+        file.set_len(total)?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+}
+
+// Breadth-first, so the root gets the first inode and a small nid.
+fn collect(root: &Path) -> Result<Vec<Node>> {
+    let mut nodes = vec![Node::new(root.to_path_buf(), 0)?];
+    ensure!(
+        matches!(nodes[0].kind, Kind::Dir(_)),
+        "{} is not a directory",
+        root.display()
+    );
+
+    let mut i = 0;
+    while i < nodes.len() {
+        if matches!(nodes[i].kind, Kind::Dir(_)) {
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(&nodes[i].path)
+                .with_context(|| format!("Failed to read {}", nodes[i].path.display()))?
+            {
+                let entry = entry?;
+                entries.push((entry.file_name().as_bytes().to_vec(), entry.path()));
+            }
+            entries.sort();
+
+            let mut children = Vec::with_capacity(entries.len());
+            for (name, path) in entries {
+                children.push((name, nodes.len()));
+                nodes.push(Node::new(path, i)?);
+            }
+            nodes[i].kind = Kind::Dir(children);
+        }
+        i += 1;
+    }
+
+    Ok(nodes)
+}
+
+// How many candidates the lz4 matcher tries per position, or None to store files plain.
+// lz4hc levels double the depth per level, so the default of 9 searches 256 candidates
+// like the reference lz4hc does.
+fn search_depth(options: &ErofsConfig) -> Option<usize> {
+    match options.compressor {
+        ErofsCompressor::Lz4 => Some(1),
+        ErofsCompressor::Lz4hc => {
+            let level = match options.level {
+                None | Some(0) => LZ4HC_DEFAULT_LEVEL,
+                Some(level) => level.min(12),
+            };
+            Some(1 << (level - 1))
+        }
+        _ => None,
+    }
+}
+
+// Writes the data blocks of files and symlinks. Returns whether the file was compressed.
+fn write_data(image: &mut Image, node: &mut Node, depth: Option<usize>) -> Result<bool> {
+    match &node.kind {
+        Kind::File => {
+            node.size = node.meta.len();
+            let mut file = File::open(&node.path)
+                .with_context(|| format!("Failed to open {}", node.path.display()))?;
+
+            if let Some(depth) = depth
+                && node.size > BLOCK_SIZE as u64
+            {
+                let start = image.blocks;
+                let (blocks, indexes) = write_compressed(image, &mut file, node.size, depth)
+                    .with_context(|| format!("Failed to compress {}", node.path.display()))?;
+
+                // keep the plain copy unless compression saves at least a block
+                if (blocks as u64) < node.size.div_ceil(BLOCK_SIZE as u64) {
+                    node.layout = LAYOUT_COMPRESSED_FULL;
+                    node.raw = blocks;
+                    node.trailer = compressed_trailer(node.xattrs.len(), &indexes);
+                    return Ok(true);
+                }
+
+                image.rewind_to(start)?;
+                file.seek(SeekFrom::Start(0))?;
+            }
+
+            write_flat(image, node, &mut file)
+                .with_context(|| format!("Failed to pack {}", node.path.display()))?;
+        }
+        Kind::Symlink(target) => {
+            let target = target.clone();
+            node.size = target.len() as u64;
+            write_flat(image, node, &mut target.as_slice())?;
+        }
+        Kind::Dir(_) => {}
+        Kind::Special(rdev) => node.raw = *rdev,
+    }
+
+    Ok(false)
+}
+
+fn write_flat(image: &mut Image, node: &mut Node, reader: &mut dyn Read) -> Result<()> {
+    let size = node.size as usize;
+    let tail_len = size % BLOCK_SIZE;
+    let inline = node.can_inline(tail_len);
+    let block_len = if inline { size - tail_len } else { size };
+
+    if block_len > 0 {
+        node.raw = image.copy_blocks(reader, block_len as u64)?;
+    }
+
+    if inline {
+        let mut tail = vec![0u8; tail_len];
+        reader
+            .read_exact(&mut tail)
+            .context("file changed while packing")?;
+        node.layout = LAYOUT_FLAT_INLINE;
+        node.trailer = tail;
+    }
+
+    Ok(())
+}
+
+// Packs the file into one-block clusters. A cluster is lz4 when that holds more than a block
+// of input, and a raw block otherwise. Every cluster starts in a new logical block, which is
+// what the one-index-per-block format requires.
+fn write_compressed(
+    image: &mut Image,
+    file: &mut File,
+    size: u64,
+    depth: usize,
+) -> Result<(u32, Vec<u8>)> {
+    let mut heads: Vec<(u64, u32, u16)> = Vec::new();
+    let mut buf: Vec<u8> = Vec::with_capacity(MAX_CLUSTER_INPUT);
+    let mut pos = 0u64;
+
+    while pos < size {
+        let want = (size - pos).min(MAX_CLUSTER_INPUT as u64) as usize;
+        if buf.len() < want {
+            let start = buf.len();
+            buf.resize(want, 0);
+            file.read_exact(&mut buf[start..])
+                .context("file changed while packing")?;
+        }
+
+        let (consumed, compressed) = compress_cluster(&buf[..want], depth);
+        let consumed = if consumed > BLOCK_SIZE {
+            let mut block = vec![0u8; BLOCK_SIZE];
+            // zero padding in front lets the kernel find where the stream starts
+            block[BLOCK_SIZE - compressed.len()..].copy_from_slice(&compressed);
+            heads.push((pos, image.write_blocks(&block)?, LCLUSTER_HEAD));
+            consumed
+        } else {
+            // The kernel maps a raw cluster up to the end of the logical block it reaches,
+            // even past EOF, and rejects it if that is longer than a block. So the last one
+            // must not run into another logical block.
+            let mut take = want.min(BLOCK_SIZE);
+            if pos + take as u64 == size {
+                take = take.min(BLOCK_SIZE - (pos % BLOCK_SIZE as u64) as usize);
+            }
+            heads.push((pos, image.write_blocks(&buf[..take])?, LCLUSTER_PLAIN));
+            take
+        };
+
+        buf.drain(..consumed);
+        pos += consumed as u64;
+    }
+
+    Ok((heads.len() as u32, encode_indexes(&heads, size)))
+}
+
+// Starts small and widens the window while everything still fits, to avoid scanning far
+// more input than one block can hold.
+fn compress_cluster(input: &[u8], depth: usize) -> (usize, Vec<u8>) {
+    let mut window = input.len().min(4 * BLOCK_SIZE);
+    loop {
+        let (consumed, compressed) = lz4::compress_dest_size(&input[..window], BLOCK_SIZE, depth);
+        if consumed < window || window == input.len() {
+            return (consumed, compressed);
+        }
+        window = (window * 2).min(input.len());
+    }
+}
+
+fn encode_indexes(heads: &[(u64, u32, u16)], size: u64) -> Vec<u8> {
+    let lclusters = size.div_ceil(BLOCK_SIZE as u64) as usize;
+    let head_lcns: Vec<usize> = heads
+        .iter()
+        .map(|(pos, _, _)| (*pos / BLOCK_SIZE as u64) as usize)
+        .collect();
+
+    let mut out = Vec::with_capacity(lclusters * INDEX_SIZE);
+    let mut head = 0;
+    for lcn in 0..lclusters {
+        if head + 1 < heads.len() && head_lcns[head + 1] == lcn {
+            head += 1;
+        }
+
+        let (pos, blkaddr, kind) = heads[head];
+        if head_lcns[head] == lcn {
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&((pos % BLOCK_SIZE as u64) as u16).to_le_bytes());
+            out.extend_from_slice(&blkaddr.to_le_bytes());
+        } else {
+            let next = head_lcns.get(head + 1).copied().unwrap_or(lclusters);
+            out.extend_from_slice(&LCLUSTER_NONHEAD.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&((lcn - head_lcns[head]) as u16).to_le_bytes());
+            out.extend_from_slice(&((next - lcn) as u16).to_le_bytes());
+        }
+    }
+
+    out
+}
+
+// The map header sits at the first 8-byte boundary after the xattrs. All zero means lz4 with
+// block-sized logical clusters.
+fn compressed_trailer(xattr_len: usize, indexes: &[u8]) -> Vec<u8> {
+    let end = INODE_SIZE + xattr_len;
+    let padding = end.next_multiple_of(8) - end;
+
+    let mut trailer = vec![0u8; padding + MAP_HEADER_SIZE];
+    trailer.extend_from_slice(indexes);
+    trailer
+}
+
+// Inode records are 32-byte aligned and must not straddle a block, unless a record is larger
+// than a block, in which case it starts on a fresh one. The first slot stays empty so the
+// root never ends up with inode number 0.
+fn assign_nids(nodes: &mut [Node]) -> usize {
+    let mut cursor = INODE_SLOT;
+    for node in nodes.iter_mut() {
+        let len = node.record_len();
+        if cursor % BLOCK_SIZE + len > BLOCK_SIZE {
+            cursor = cursor.next_multiple_of(BLOCK_SIZE);
+        }
+
+        node.nid = (cursor / INODE_SLOT) as u64;
+        cursor = (cursor + len).next_multiple_of(INODE_SLOT);
+    }
+
+    cursor
+}
+
+fn dir_entries(nodes: &[Node], i: usize) -> Vec<(&[u8], u64, u8)> {
+    let Kind::Dir(children) = &nodes[i].kind else {
+        return Vec::new();
+    };
+
+    let mut entries: Vec<(&[u8], u64, u8)> = vec![
+        (b".", nodes[i].nid, FT_DIR),
+        (b"..", nodes[nodes[i].parent].nid, FT_DIR),
+    ];
+    entries.extend(children.iter().map(|(name, child)| {
+        (
+            name.as_slice(),
+            nodes[*child].nid,
+            nodes[*child].file_type(),
+        )
+    }));
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    entries
+}
+
+// Dirents sit at the front of each block and the names follow them. The last name in a
+// block runs to the end of the directory or to the first zero byte.
+fn pack_dirents(entries: &[(&[u8], u64, u8)]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut start = 0;
+
+    while start < entries.len() {
+        let mut end = start;
+        let mut used = 0;
+        while end < entries.len() && used + DIRENT_SIZE + entries[end].0.len() <= BLOCK_SIZE {
+            used += DIRENT_SIZE + entries[end].0.len();
+            end += 1;
+        }
+        ensure!(end > start, "directory entry name is too long");
+
+        let block = &entries[start..end];
+        let mut name_off = block.len() * DIRENT_SIZE;
+        let mut names = Vec::new();
+
+        if !out.is_empty() {
+            out.resize(out.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+
+        for (name, nid, file_type) in block {
+            out.extend_from_slice(&nid.to_le_bytes());
+            out.extend_from_slice(&(name_off as u16).to_le_bytes());
+            out.push(*file_type);
+            out.push(0);
+            names.extend_from_slice(name);
+            name_off += name.len();
+        }
+        out.extend_from_slice(&names);
+
+        start = end;
+    }
+
+    Ok(out)
+}
+
+fn encode_inode(node: &Node, ino: u32, options: &ErofsConfig, nodes: &[Node]) -> Result<Vec<u8>> {
+    let meta = &node.meta;
+    let nlink = match &node.kind {
+        Kind::Dir(children) => {
+            2 + children
+                .iter()
+                .filter(|(_, child)| matches!(nodes[*child].kind, Kind::Dir(_)))
+                .count() as u32
+        }
+        _ => 1,
+    };
+
+    let (mtime, mtime_nsec) = match options.timestamp {
+        Some(timestamp) => (timestamp, 0),
+        None => (meta.mtime().max(0) as u64, meta.mtime_nsec() as u32),
+    };
+
+    let xattr_icount = if node.xattrs.is_empty() {
+        0
+    } else {
+        u16::try_from((node.xattrs.len() - XATTR_HEADER_SIZE) / 4 + 1)
+            .with_context(|| format!("too many xattrs on {}", node.path.display()))?
+    };
+
+    let mut out = Vec::with_capacity(node.record_len());
+    out.extend_from_slice(&(1 | (node.layout << 1)).to_le_bytes());
+    out.extend_from_slice(&xattr_icount.to_le_bytes());
+    out.extend_from_slice(&(meta.mode() as u16).to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(&node.size.to_le_bytes());
+    out.extend_from_slice(&node.raw.to_le_bytes());
+    out.extend_from_slice(&ino.to_le_bytes());
+    out.extend_from_slice(&meta.uid().to_le_bytes());
+    out.extend_from_slice(&meta.gid().to_le_bytes());
+    out.extend_from_slice(&mtime.to_le_bytes());
+    out.extend_from_slice(&mtime_nsec.to_le_bytes());
+    out.extend_from_slice(&nlink.to_le_bytes());
+    out.extend_from_slice(&[0; 16]);
+
+    out.extend_from_slice(&node.xattrs);
+    out.extend_from_slice(&node.trailer);
+
+    Ok(out)
+}
+
+fn encode_superblock(
+    root_nid: u16,
+    inodes: u64,
+    blocks: u32,
+    meta_blkaddr: u32,
+    compressed: bool,
+    options: &ErofsConfig,
+) -> Vec<u8> {
+    let build_time = options.timestamp.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    });
+    let feature_incompat = if compressed {
+        FEATURE_INCOMPAT_ZERO_PADDING
+    } else {
+        0
+    };
+
+    let mut sb = Vec::with_capacity(128);
+    sb.extend_from_slice(&MAGIC.to_le_bytes());
+    sb.extend_from_slice(&0u32.to_le_bytes()); // checksum, unused without the compat flag
+    sb.extend_from_slice(&0u32.to_le_bytes()); // feature_compat
+    sb.push(BLOCK_BITS);
+    sb.push(0); // sb_extslots
+    sb.extend_from_slice(&root_nid.to_le_bytes());
+    sb.extend_from_slice(&inodes.to_le_bytes());
+    sb.extend_from_slice(&build_time.to_le_bytes());
+    sb.extend_from_slice(&0u32.to_le_bytes()); // build_time_nsec
+    sb.extend_from_slice(&blocks.to_le_bytes());
+    sb.extend_from_slice(&meta_blkaddr.to_le_bytes());
+    sb.extend_from_slice(&0u32.to_le_bytes()); // xattr_blkaddr
+    sb.extend_from_slice(&[0; 16]); // uuid
+    sb.extend_from_slice(&[0; 16]); // volume_name
+    sb.extend_from_slice(&feature_incompat.to_le_bytes());
+    sb.resize(128, 0);
+
+    sb
+}
+
+fn read_xattrs(path: &Path) -> Result<Vec<u8>> {
+    let Ok(names) = llistxattr(path) else {
+        return Ok(Vec::new());
+    };
+
+    let mut entries = Vec::new();
+    for name in names {
+        let raw = name.as_bytes();
+        let Some((index, suffix)) = XATTR_PREFIXES.iter().find_map(|(prefix, index)| {
+            raw.strip_prefix(prefix.as_bytes())
+                .map(|suffix| (*index, suffix.to_vec()))
+        }) else {
+            log::debug!(
+                "Skipping xattr {} on {}",
+                name.to_string_lossy(),
+                path.display()
+            );
+            continue;
+        };
+
+        let value = lgetxattr(path, &name).with_context(|| {
+            format!(
+                "Failed to read xattr {} on {}",
+                name.to_string_lossy(),
+                path.display()
+            )
+        })?;
+        ensure!(
+            suffix.len() <= u8::MAX as usize && value.len() <= u16::MAX as usize,
+            "xattr {} on {} is too large",
+            name.to_string_lossy(),
+            path.display()
+        );
+        entries.push((index, suffix, value));
+    }
+
+    if entries.is_empty() {
+        return Ok(Vec::new());
+    }
+    entries.sort();
+
+    let mut out = vec![0u8; XATTR_HEADER_SIZE];
+    for (index, name, value) in entries {
+        out.push(name.len() as u8);
+        out.push(index);
+        out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        out.extend_from_slice(&name);
+        out.extend_from_slice(&value);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    Ok(out)
+}
+
+fn encode_dev(rdev: u64) -> u32 {
+    let major = rustix::fs::major(rdev);
+    let minor = rustix::fs::minor(rdev);
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        ffi::OsStr,
+        os::unix::fs::{PermissionsExt, symlink},
+        process::Command,
+    };
+
+    use extattr::{Flags as XattrFlags, lsetxattr};
+    use tempfile::TempDir;
+    use walkdir::WalkDir;
+
+    use super::{super::lz4::tests as lz4, *};
+
+    #[derive(Debug, PartialEq)]
+    enum Entry {
+        Dir,
+        File(Vec<u8>),
+        Symlink(Vec<u8>),
+    }
+
+    // (mode, user xattrs, content) for every path below the root
+    type Tree = BTreeMap<PathBuf, (u16, Vec<(Vec<u8>, Vec<u8>)>, Entry)>;
+
+    struct Inode {
+        offset: usize,
+        layout: u16,
+        mode: u16,
+        size: usize,
+        raw: usize,
+        xattr_len: usize,
+    }
+
+    // Reads back the subset of EROFS the writer produces.
+    struct Reader {
+        img: Vec<u8>,
+        meta: usize,
+    }
+
+    impl Reader {
+        fn new(path: &Path) -> Self {
+            let img = fs::read(path).unwrap();
+            let sb = &img[SUPER_OFFSET as usize..];
+            assert_eq!(u32_at(sb, 0), MAGIC);
+            assert_eq!(sb[12], BLOCK_BITS);
+            assert_eq!(u32_at(sb, 36) as usize * BLOCK_SIZE, img.len());
+
+            let meta = u32_at(sb, 40) as usize * BLOCK_SIZE;
+            Self { img, meta }
+        }
+
+        fn root(&self) -> u64 {
+            u16::from_le_bytes([self.img[1038], self.img[1039]]) as u64
+        }
+
+        fn inode(&self, nid: u64) -> Inode {
+            let offset = self.meta + nid as usize * INODE_SLOT;
+            let raw = &self.img[offset..];
+            let format = u16::from_le_bytes([raw[0], raw[1]]);
+            assert_eq!(format & 1, 1, "expected an extended inode");
+
+            let icount = u16::from_le_bytes([raw[2], raw[3]]) as usize;
+            Inode {
+                offset,
+                layout: format >> 1,
+                mode: u16::from_le_bytes([raw[4], raw[5]]),
+                size: u64::from_le_bytes(raw[8..16].try_into().unwrap()) as usize,
+                raw: u32_at(raw, 16) as usize,
+                xattr_len: if icount == 0 {
+                    0
+                } else {
+                    XATTR_HEADER_SIZE + (icount - 1) * 4
+                },
+            }
+        }
+
+        fn data(&self, inode: &Inode) -> Vec<u8> {
+            let inline = inode.offset + INODE_SIZE + inode.xattr_len;
+            match inode.layout {
+                LAYOUT_FLAT_PLAIN => self.blocks(inode.raw, inode.size).to_vec(),
+                LAYOUT_FLAT_INLINE => {
+                    let tail = inode.size % BLOCK_SIZE;
+                    let mut out = self.blocks(inode.raw, inode.size - tail).to_vec();
+                    out.extend_from_slice(&self.img[inline..inline + tail]);
+                    out
+                }
+                LAYOUT_COMPRESSED_FULL => self.decompress(inode, inline),
+                layout => panic!("unexpected layout {layout}"),
+            }
+        }
+
+        fn decompress(&self, inode: &Inode, inline: usize) -> Vec<u8> {
+            let indexes = inline.next_multiple_of(8) + MAP_HEADER_SIZE;
+            let mut heads = Vec::new();
+            for lcn in 0..inode.size.div_ceil(BLOCK_SIZE) {
+                let index = &self.img[indexes + lcn * INDEX_SIZE..];
+                let kind = u16::from_le_bytes([index[0], index[1]]);
+                if kind != LCLUSTER_NONHEAD {
+                    let clusterofs = u16::from_le_bytes([index[2], index[3]]) as usize;
+                    heads.push((
+                        lcn * BLOCK_SIZE + clusterofs,
+                        u32_at(index, 4) as usize,
+                        kind,
+                    ));
+                }
+            }
+
+            let mut out = Vec::new();
+            for (i, (pos, blkaddr, kind)) in heads.iter().enumerate() {
+                assert_eq!(out.len(), *pos);
+                let end = heads.get(i + 1).map_or(inode.size, |next| next.0);
+                let block = self.blocks(*blkaddr, BLOCK_SIZE);
+
+                if *kind == LCLUSTER_PLAIN {
+                    out.extend_from_slice(&block[..end - pos]);
+                } else {
+                    let start = block.iter().position(|b| *b != 0).unwrap();
+                    assert_eq!(lz4::decompress(&block[start..]).len(), end - pos);
+                    out.extend(lz4::decompress(&block[start..]));
+                }
+            }
+            out
+        }
+
+        fn blocks(&self, blkaddr: usize, len: usize) -> &[u8] {
+            &self.img[blkaddr * BLOCK_SIZE..blkaddr * BLOCK_SIZE + len]
+        }
+
+        fn user_xattrs(&self, inode: &Inode) -> Vec<(Vec<u8>, Vec<u8>)> {
+            let mut out = Vec::new();
+            let start = inode.offset + INODE_SIZE;
+            let mut pos = start + XATTR_HEADER_SIZE;
+            while pos < start + inode.xattr_len {
+                let (name_len, index) = (self.img[pos] as usize, self.img[pos + 1]);
+                let value_len = u16::from_le_bytes([self.img[pos + 2], self.img[pos + 3]]) as usize;
+                let name = &self.img[pos + 4..pos + 4 + name_len];
+                let value = &self.img[pos + 4 + name_len..pos + 4 + name_len + value_len];
+                if index == 1 {
+                    out.push(([b"user.", name].concat(), value.to_vec()));
+                }
+                pos = (pos + 4 + name_len + value_len).next_multiple_of(4);
+            }
+            out
+        }
+
+        fn dirents(&self, inode: &Inode) -> Vec<(Vec<u8>, u64)> {
+            let data = self.data(inode);
+            let mut out = Vec::new();
+            for block in data.chunks(BLOCK_SIZE) {
+                let count = u16::from_le_bytes([block[8], block[9]]) as usize / DIRENT_SIZE;
+                for i in 0..count {
+                    let dirent = &block[i * DIRENT_SIZE..];
+                    let nid = u64::from_le_bytes(dirent[..8].try_into().unwrap());
+                    let name_off = u16::from_le_bytes([dirent[8], dirent[9]]) as usize;
+                    let name_end = if i + 1 < count {
+                        u16::from_le_bytes([dirent[20], dirent[21]]) as usize
+                    } else {
+                        block[name_off..]
+                            .iter()
+                            .position(|b| *b == 0)
+                            .map_or(block.len(), |len| name_off + len)
+                    };
+                    out.push((block[name_off..name_end].to_vec(), nid));
+                }
+            }
+            out
+        }
+
+        fn lookup(&self, path: &str) -> Inode {
+            let nid = path.split('/').fold(self.root(), |nid, name| {
+                self.dirents(&self.inode(nid))
+                    .into_iter()
+                    .find(|(entry, _)| entry == name.as_bytes())
+                    .unwrap_or_else(|| panic!("{path} not found"))
+                    .1
+            });
+            self.inode(nid)
+        }
+
+        fn tree(&self) -> Tree {
+            let mut tree = Tree::new();
+            self.walk(self.root(), self.root(), PathBuf::new(), &mut tree);
+            tree
+        }
+
+        fn walk(&self, nid: u64, parent: u64, path: PathBuf, tree: &mut Tree) {
+            let inode = self.inode(nid);
+            let entry = match inode.mode as u32 & libc::S_IFMT {
+                libc::S_IFDIR => {
+                    for (name, child) in self.dirents(&inode) {
+                        match name.as_slice() {
+                            b"." => assert_eq!(child, nid),
+                            b".." => assert_eq!(child, parent),
+                            _ => self.walk(child, nid, path.join(OsStr::from_bytes(&name)), tree),
+                        }
+                    }
+                    Entry::Dir
+                }
+                libc::S_IFLNK => Entry::Symlink(self.data(&inode)),
+                _ => Entry::File(self.data(&inode)),
+            };
+
+            if !path.as_os_str().is_empty() {
+                tree.insert(path, (inode.mode & 0o7777, self.user_xattrs(&inode), entry));
+            }
+        }
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    fn source_tree(root: &Path) -> Tree {
+        WalkDir::new(root)
+            .min_depth(1)
+            .into_iter()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let path = entry.path();
+                let meta = entry.path().symlink_metadata().unwrap();
+                let content = if meta.is_dir() {
+                    Entry::Dir
+                } else if meta.file_type().is_symlink() {
+                    Entry::Symlink(fs::read_link(path).unwrap().as_os_str().as_bytes().to_vec())
+                } else {
+                    Entry::File(fs::read(path).unwrap())
+                };
+                let xattrs = crate::utils::list_xattrs(path)
+                    .into_iter()
+                    .filter(|(name, _)| name.as_bytes().starts_with(b"user."))
+                    .map(|(name, value)| (name.as_bytes().to_vec(), value))
+                    .collect();
+
+                (
+                    path.strip_prefix(root).unwrap().to_path_buf(),
+                    (meta.mode() as u16 & 0o7777, xattrs, content),
+                )
+            })
+            .collect()
+    }
+
+    fn sample_tree() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("system/lib64")).unwrap();
+        fs::create_dir(root.join("empty")).unwrap();
+
+        fs::write(root.join("system/build.prop"), "ro.hybrid=1\n").unwrap();
+        fs::write(root.join("system/empty"), "").unwrap();
+        fs::write(root.join("system/lib64/text.so"), lz4::sample(100_000, 3)).unwrap();
+        fs::write(
+            root.join("system/lib64/zeros.so"),
+            vec![0u8; 3 * BLOCK_SIZE],
+        )
+        .unwrap();
+        fs::write(root.join("system/noise.bin"), lz4::noise(9000)).unwrap();
+        symlink("lib64/text.so", root.join("system/link")).unwrap();
+
+        // enough entries to spill the directory out of its inode block
+        for i in 0..300 {
+            fs::write(
+                root.join(format!("empty/entry-with-a-long-name-{i:04}")),
+                "",
+            )
+            .unwrap();
+        }
+
+        let script = root.join("system/run.sh");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o750)).unwrap();
+        let _ = lsetxattr(&script, "user.hybrid", b"marked", XattrFlags::empty());
+
+        dir
+    }
+
+    fn build(src: &Path, compressor: ErofsCompressor, level: Option<u32>) -> (TempDir, PathBuf) {
+        let out = tempfile::tempdir().unwrap();
+        let image = out.path().join("modules.erofs");
+        let options = ErofsConfig {
+            compressor,
+            level,
+            ..Default::default()
+        };
+        build_image(src, &image, &options).unwrap();
+        (out, image)
+    }
+
+    #[test]
+    fn images_read_back_as_the_source_tree() {
+        let src = sample_tree();
+        let expected = source_tree(src.path());
+
+        for (compressor, level) in [
+            (ErofsCompressor::None, None),
+            (ErofsCompressor::Lz4, None),
+            (ErofsCompressor::Lz4hc, None),
+            (ErofsCompressor::Lz4hc, Some(12)),
+        ] {
+            let (_out, image) = build(src.path(), compressor.clone(), level);
+            assert_eq!(
+                Reader::new(&image).tree(),
+                expected,
+                "{compressor:?} {level:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn compresses_only_when_it_saves_blocks() {
+        let src = sample_tree();
+        let (_out, image) = build(src.path(), ErofsCompressor::Lz4hc, None);
+        let reader = Reader::new(&image);
+        let layout = |path| reader.lookup(path).layout;
+
+        assert_eq!(layout("system/lib64/text.so"), LAYOUT_COMPRESSED_FULL);
+        assert_eq!(layout("system/lib64/zeros.so"), LAYOUT_COMPRESSED_FULL);
+        assert_eq!(layout("system/noise.bin"), LAYOUT_FLAT_INLINE);
+        assert_eq!(layout("system/build.prop"), LAYOUT_FLAT_INLINE);
+        assert_eq!(layout("empty"), LAYOUT_FLAT_INLINE);
+    }
+
+    #[test]
+    fn lz4hc_levels_set_the_search_depth() {
+        let depth = |compressor, level| {
+            search_depth(&ErofsConfig {
+                compressor,
+                level,
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(depth(ErofsCompressor::None, None), None);
+        assert_eq!(depth(ErofsCompressor::Lz4, None), Some(1));
+        assert_eq!(depth(ErofsCompressor::Lz4hc, None), Some(256));
+        assert_eq!(depth(ErofsCompressor::Lz4hc, Some(0)), Some(256));
+        assert_eq!(depth(ErofsCompressor::Lz4hc, Some(1)), Some(1));
+        assert_eq!(depth(ErofsCompressor::Lz4hc, Some(12)), Some(2048));
+    }
+
+    // Needs root and a kernel with EROFS and loop devices.
+    #[test]
+    #[ignore]
+    fn kernel_mounts_the_image() {
+        let src = sample_tree();
+        let expected = source_tree(src.path());
+
+        for compressor in [ErofsCompressor::None, ErofsCompressor::Lz4hc] {
+            let (out, image) = build(src.path(), compressor.clone(), None);
+            let target = out.path().join("mnt");
+            fs::create_dir(&target).unwrap();
+
+            let status = Command::new("mount")
+                .args(["-t", "erofs", "-o", "loop,ro"])
+                .arg(&image)
+                .arg(&target)
+                .status()
+                .unwrap();
+            assert!(status.success(), "mount failed for {compressor:?}");
+
+            let mounted = source_tree(&target);
+            let _ = Command::new("umount").arg(&target).status();
+            assert_eq!(mounted, expected, "{compressor:?}");
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::path::Path;
+
+use anyhow::{Result, ensure};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use extattr::{lgetxattr, llistxattr};
+use procfs::{Current, Meminfo};
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::conf::config::TmpfsConfig;
+
+const EXT4_BLOCK: u64 = 1024;
+const EXT4_INODE_SIZE: u64 = 256;
+// What is left of a 256-byte inode for xattrs once the fixed fields are in place.
+const EXT4_INLINE_XATTR: u64 = 96;
+// Symlink targets shorter than this live in the inode itself.
+const EXT4_FAST_SYMLINK: u64 = 60;
+const PAGE_SIZE: u64 = 4096;
+const MIN_EXT4_SIZE: u64 = 64 * 1024 * 1024;
+
+// One pass over the module sources, used to size the ext4 image and to check tmpfs limits.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SizeEstimate {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub bytes: u64,
+    pub xattr_bytes: u64,
+    // 1 KiB blocks as the ext4 image allocates them, including directory and xattr blocks
+    pub ext4_blocks: u64,
+    // pages a tmpfs copy would use
+    pub tmpfs_pages: u64,
+}
+
+impl SizeEstimate {
+    pub fn scan(root: &Path) -> Result<Self> {
+        let mut estimate = Self::default();
+        let mut dirent_bytes = 0;
+
+        for entry in WalkDir::new(root).min_depth(1) {
+            let entry = entry?;
+            let meta = entry.path().symlink_metadata()?;
+            let file_type = meta.file_type();
+
+            // name plus the 8-byte header, padded to 4 bytes
+            dirent_bytes += (8 + entry.file_name().len() as u64).div_ceil(4) * 4;
+
+            if file_type.is_dir() {
+                estimate.dirs += 1;
+            } else if file_type.is_symlink() {
+                estimate.symlinks += 1;
+                if meta.len() >= EXT4_FAST_SYMLINK {
+                    estimate.ext4_blocks += 1;
+                    estimate.tmpfs_pages += 1;
+                }
+            } else {
+                estimate.files += 1;
+                estimate.bytes += meta.len();
+                estimate.ext4_blocks += meta.len().div_ceil(EXT4_BLOCK);
+                estimate.tmpfs_pages += meta.len().div_ceil(PAGE_SIZE);
+                // an inode maps 4 extents of 32 MiB, larger files need extent tree blocks
+                estimate.ext4_blocks += meta.len() / (128 << 20);
+            }
+
+            let xattrs = xattr_size(entry.path());
+            estimate.xattr_bytes += xattrs;
+            if xattrs > EXT4_INLINE_XATTR {
+                estimate.ext4_blocks += 1;
+            }
+        }
+
+        // every directory takes at least one block, entries spill into more
+        estimate.ext4_blocks += estimate.dirs + 1 + dirent_bytes.div_ceil(EXT4_BLOCK);
+
+        Ok(estimate)
+    }
+
+    pub fn inodes(&self) -> u64 {
+        self.files + self.dirs + self.symlinks + 1
+    }
+
+    // Inode count for mkfs.ext4, with room for the backup copies sync makes while updating.
+    pub fn ext4_inodes(&self) -> u64 {
+        (self.inodes() * 3 / 2).max(16384)
+    }
+
+    pub fn ext4_image_size(&self) -> u64 {
+        let data = self.ext4_blocks * EXT4_BLOCK;
+        let inode_tables = self.ext4_inodes() * EXT4_INODE_SIZE;
+        // a quarter on top for updates in place, group metadata and fragmentation
+        let size = (data + inode_tables) / 4 * 5;
+        let size = size + ext4_journal_blocks(size / EXT4_BLOCK) * EXT4_BLOCK;
+
+        size.max(MIN_EXT4_SIZE)
+    }
+
+    pub fn tmpfs_bytes(&self) -> u64 {
+        self.tmpfs_pages * PAGE_SIZE
+    }
+
+    // Errors when the modules can't fit in a tmpfs with these limits, or in the free RAM.
+    pub fn check_tmpfs(&self, limits: &TmpfsConfig) -> Result<()> {
+        let mem = Meminfo::current().ok();
+        let mem_total = mem.as_ref().map(|m| m.mem_total).unwrap_or(0);
+        let mem_available = mem.and_then(|m| m.mem_available);
+
+        self.check_tmpfs_with(limits, mem_total, mem_available)
+    }
+
+    fn check_tmpfs_with(
+        &self,
+        limits: &TmpfsConfig,
+        mem_total: u64,
+        mem_available: Option<u64>,
+    ) -> Result<()> {
+        let needed = self.tmpfs_bytes();
+
+        if let Some(size) = limits.size_bytes(mem_total)? {
+            ensure!(
+                needed <= size,
+                "Modules need about {} but tmpfs size is limited to {}",
+                format_size(needed),
+                format_size(size)
+            );
+        }
+
+        if let Some(nr_inodes) = limits.nr_inodes.filter(|n| *n > 0) {
+            ensure!(
+                self.inodes() <= nr_inodes,
+                "Modules need {} inodes but tmpfs nr_inodes is {}",
+                self.inodes(),
+                nr_inodes
+            );
+        }
+
+        if let Some(available) = mem_available {
+            ensure!(
+                needed <= available,
+                "Modules need about {} but only {} of RAM is available",
+                format_size(needed),
+                format_size(available)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+// mke2fs' default journal size for a filesystem of `blocks` 1 KiB blocks.
+fn ext4_journal_blocks(blocks: u64) -> u64 {
+    match blocks {
+        0..2048 => 0,
+        2048..32768 => 1024,
+        32768..262144 => 4096,
+        262144..524288 => 8192,
+        524288..4194304 => 16384,
+        4194304..8388608 => 32768,
+        8388608..16777216 => 65536,
+        _ => 131072,
+    }
+}
+
+// Names and values, plus the 16-byte entry header ext4 stores for each.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn xattr_size(path: &Path) -> u64 {
+    let Ok(names) = llistxattr(path) else {
+        return 0;
+    };
+
+    names
+        .iter()
+        .map(|name| {
+            let value = lgetxattr(path, name).map(|v| v.len()).unwrap_or(0);
+            (16 + name.len() + value) as u64
+        })
+        .sum()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn xattr_size(_path: &Path) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    #[test]
+    fn scan_counts_entries_blocks_and_pages() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("system/etc")).unwrap();
+        fs::write(root.join("system/etc/empty"), "").unwrap();
+        fs::write(root.join("system/etc/small"), [0; 100]).unwrap();
+        fs::write(root.join("system/etc/page"), [0; 5000]).unwrap();
+        symlink("small", root.join("system/etc/short")).unwrap();
+        symlink("x".repeat(60), root.join("system/etc/long")).unwrap();
+
+        let estimate = SizeEstimate::scan(root).unwrap();
+
+        assert_eq!(
+            (
+                estimate.files,
+                estimate.dirs,
+                estimate.symlinks,
+                estimate.bytes
+            ),
+            (3, 2, 2, 5100)
+        );
+        assert_eq!(estimate.inodes(), 8);
+        // 1 + 5 file blocks, 1 long symlink block, 3 directory blocks and 1 dirent block,
+        // unless the host labels every entry with xattrs that need blocks of their own
+        if estimate.xattr_bytes == 0 {
+            assert_eq!(estimate.ext4_blocks, 11);
+        }
+        // 1 + 2 file pages and 1 long symlink page
+        assert_eq!(estimate.tmpfs_pages, 4);
+        assert_eq!(estimate.tmpfs_bytes(), 4 * 4096);
+    }
+
+    #[test]
+    fn ext4_size_has_a_floor_and_room_to_grow() {
+        let small = SizeEstimate {
+            files: 10,
+            ext4_blocks: 100,
+            ..Default::default()
+        };
+        assert_eq!(small.ext4_inodes(), 16384);
+        assert_eq!(small.ext4_image_size(), MIN_EXT4_SIZE);
+
+        let large = SizeEstimate {
+            files: 100_000,
+            dirs: 10_000,
+            ext4_blocks: 512 * 1024,
+            ..Default::default()
+        };
+        assert_eq!(large.ext4_inodes(), 165_001);
+        let data = 512 * MIB + 165_001 * EXT4_INODE_SIZE;
+        let padded = data / 4 * 5;
+        assert_eq!(large.ext4_image_size(), padded + 16384 * EXT4_BLOCK);
+    }
+
+    #[test]
+    fn journal_follows_mke2fs_defaults() {
+        let cases = [
+            (0, 0),
+            (2047, 0),
+            (2048, 1024),
+            (32767, 1024),
+            (32768, 4096),
+            (262_144, 8192),
+            (524_288, 16384),
+            (4_194_304, 32768),
+            (8_388_608, 65536),
+            (16_777_216, 131_072),
+            (u64::MAX, 131_072),
+        ];
+        for (blocks, journal) in cases {
+            assert_eq!(ext4_journal_blocks(blocks), journal, "{blocks}");
+        }
+    }
+
+    #[test]
+    fn tmpfs_check_honours_size_inodes_and_free_ram() {
+        let estimate = SizeEstimate {
+            files: 9,
+            tmpfs_pages: 256,
+            ..Default::default()
+        };
+        let limits = |size: &str, nr_inodes| TmpfsConfig {
+            size: Some(size.to_string()).filter(|s| !s.is_empty()),
+            nr_inodes,
+        };
+
+        assert!(
+            estimate
+                .check_tmpfs_with(&limits("", None), 0, None)
+                .is_ok()
+        );
+        assert!(
+            estimate
+                .check_tmpfs_with(&limits("1m", Some(10)), 0, None)
+                .is_ok()
+        );
+        // 0 means unlimited for both
+        assert!(
+            estimate
+                .check_tmpfs_with(&limits("0", Some(0)), 0, None)
+                .is_ok()
+        );
+        assert!(
+            estimate
+                .check_tmpfs_with(&limits("50%", None), 2 * MIB, None)
+                .is_ok()
+        );
+
+        let err = |limits: &TmpfsConfig, mem_total, available| {
+            let result = estimate.check_tmpfs_with(limits, mem_total, available);
+            format!("{:#}", result.unwrap_err())
+        };
+        assert!(err(&limits("1020k", None), 0, None).contains("limited to 1.0 MiB"));
+        assert!(err(&limits("25%", None), 2 * MIB, None).contains("limited to 0.5 MiB"));
+        assert!(err(&limits("", Some(9)), 0, None).contains("need 10 inodes"));
+        assert!(err(&limits("", None), 0, Some(MIB - 1)).contains("of RAM is available"));
+        assert!(err(&limits("lots", None), 0, None).contains("invalid tmpfs size"));
+    }
+}
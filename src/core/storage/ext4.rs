@@ -0,0 +1,308 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use jwalk::WalkDir;
+use rustix::{
+    fs::statvfs,
+    mount::{UnmountFlags, unmount as umount},
+};
+
+use super::{SizeEstimate, StorageBackend, StorageKind, ensure_loop_control, hide, make_private};
+use crate::{
+    conf::config::Config,
+    core::{
+        inventory::Module,
+        ops::{dedup::DedupStats, sync},
+    },
+    mount::overlayfs::utils as overlay_utils,
+    sys::{caps, mount::is_mounted, nuke},
+    utils::{self, ensure_dir_exists},
+};
+
+const DEFAULT_SELINUX_CONTEXT: &str = "u:object_r:system_file:s0";
+
+pub struct Ext4Backend {
+    target: PathBuf,
+    image: PathBuf,
+    estimate: SizeEstimate,
+    disable_umount: bool,
+}
+
+impl Ext4Backend {
+    pub fn new(mnt_base: &Path, img_path: &Path, config: &Config, estimate: SizeEstimate) -> Self {
+        Self {
+            target: mnt_base.to_path_buf(),
+            image: img_path.to_path_buf(),
+            estimate,
+            disable_umount: config.disable_umount,
+        }
+    }
+}
+
+impl StorageBackend for Ext4Backend {
+    fn kind(&self) -> StorageKind {
+        StorageKind::Ext4
+    }
+
+    fn mount_point(&self) -> &Path {
+        &self.target
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        ensure_loop_control()?;
+        ensure!(
+            caps::probe().tools.mkfs_ext4.is_some() || self.image.exists(),
+            "mkfs.ext4 not found and there is no modules.img to reuse"
+        );
+
+        setup_ext4_image(&self.target, &self.image, &self.estimate)?;
+
+        make_private(&self.target);
+        hide(&self.target, self.disable_umount);
+
+        Ok(())
+    }
+
+    fn populate(&mut self, modules: &[Module]) -> Result<DedupStats> {
+        sync::perform_sync(modules, &self.target)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn teardown(&mut self) {
+        if is_mounted(&self.target) {
+            let _ = umount(&self.target, UnmountFlags::DETACH);
+        }
+    }
+
+    fn describe(&self) -> String {
+        "EXT4".to_string()
+    }
+}
+
+fn check_image<P>(img: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = img.as_ref();
+    let path_str = path.to_str().context("Invalid path string")?;
+    let result = Command::new("e2fsck")
+        .args(["-yf", path_str])
+        .status()
+        .with_context(|| format!("Failed to exec e2fsck {}", path.display()))?;
+    let code = result.code();
+
+    log::info!("e2fsck exit code: {}", code.unwrap_or(-1));
+    Ok(())
+}
+
+fn setup_ext4_image(target: &Path, img_path: &Path, estimate: &SizeEstimate) -> Result<()> {
+    let grow_size = estimate.ext4_image_size();
+    let inodes = estimate.ext4_inodes();
+
+    // The image survives reboots so that sync only has to copy modules that changed.
+    let mut reused = false;
+    if img_path.exists() {
+        match reuse_ext4_image(img_path, grow_size) {
+            Ok(()) => reused = true,
+            Err(e) => {
+                log::warn!("Existing modules.img is unusable, recreating: {:#}", e);
+                let _ = fs::remove_file(img_path);
+            }
+        }
+    }
+
+    if !reused {
+        create_ext4_image(img_path, grow_size, inodes)?;
+    }
+
+    utils::lsetfilecon(img_path, "u:object_r:ksu_file:s0").ok();
+
+    ensure_dir_exists(target)?;
+    let mounted = mount_ext4_image(img_path, target);
+    if !reused {
+        mounted?;
+    } else {
+        // Growing keeps the old inode ratio, so a reused image can run out of inodes first.
+        let problem = match mounted {
+            Err(e) => Some(format!("{:#}", e)),
+            Ok(()) => inode_shortage(target, estimate),
+        };
+
+        if let Some(problem) = problem {
+            log::warn!("Reused modules.img is unusable, recreating: {}", problem);
+            if is_mounted(target) {
+                let _ = umount(target, UnmountFlags::DETACH);
+            }
+            fs::remove_file(img_path).context("Failed to remove broken modules.img")?;
+            create_ext4_image(img_path, grow_size, inodes)?;
+            utils::lsetfilecon(img_path, "u:object_r:ksu_file:s0").ok();
+            mount_ext4_image(img_path, target)?;
+            reused = false;
+        }
+    }
+
+    nuke::nuke_path(img_path);
+
+    // Synced files carry their own contexts, so a reused image only needs its root labelled.
+    if reused {
+        let _ = utils::lsetfilecon(target, DEFAULT_SELINUX_CONTEXT);
+    } else {
+        for dir_entry in WalkDir::new(target).parallelism(jwalk::Parallelism::Serial) {
+            if let Some(path) = dir_entry.ok().map(|dir_entry| dir_entry.path()) {
+                let _ = utils::lsetfilecon(&path, DEFAULT_SELINUX_CONTEXT);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn create_ext4_image(img_path: &Path, size: u64, inodes: u64) -> Result<()> {
+    fs::File::create(img_path)
+        .context("Failed to create ext4 image file")?
+        .set_len(size)
+        .context("Failed to extend ext4 image")?;
+
+    let result = Command::new("mkfs.ext4")
+        .arg("-b")
+        .arg("1024")
+        .arg("-N")
+        .arg(inodes.to_string())
+        .arg(img_path)
+        .stdout(std::process::Stdio::piped())
+        .output()?;
+
+    ensure!(
+        result.status.success(),
+        "Failed to format ext4 image: {}",
+        String::from_utf8(result.stderr)?
+    );
+
+    check_image(img_path)
+}
+
+fn reuse_ext4_image(img_path: &Path, size: u64) -> Result<()> {
+    crate::sys::mount::repair_image(img_path)?;
+
+    let current = fs::metadata(img_path)?.len();
+    let Some(size) = grown_size(current, size) else {
+        log::info!("Reusing modules.img ({} bytes)", current);
+        return Ok(());
+    };
+
+    ensure!(
+        caps::probe().tools.resize2fs.is_some(),
+        "resize2fs not found, cannot grow it to {} bytes",
+        size
+    );
+
+    log::info!("Growing modules.img from {} to {} bytes", current, size);
+
+    fs::OpenOptions::new()
+        .write(true)
+        .open(img_path)
+        .context("Failed to open ext4 image")?
+        .set_len(size)
+        .context("Failed to extend ext4 image")?;
+
+    let result = Command::new("resize2fs")
+        .arg(img_path)
+        .stdout(Stdio::piped())
+        .output()
+        .context("Failed to execute resize2fs")?;
+
+    ensure!(
+        result.status.success(),
+        "Failed to resize ext4 image: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+
+    Ok(())
+}
+
+fn mount_ext4_image(img_path: &Path, target: &Path) -> Result<()> {
+    if overlay_utils::AutoMountExt4::try_new(img_path, target, false).is_err() {
+        if crate::sys::mount::repair_image(img_path).is_ok() {
+            overlay_utils::AutoMountExt4::try_new(img_path, target, false)
+                .context("Failed to mount modules.img after repair")
+                .map(|_| ())?;
+        } else {
+            bail!("Failed to repair modules.img");
+        }
+    }
+
+    Ok(())
+}
+
+// Images only ever grow, so one sized for larger modules keeps its size.
+fn grown_size(current: u64, wanted: u64) -> Option<u64> {
+    (current < wanted).then_some(wanted)
+}
+
+fn inode_shortage(target: &Path, estimate: &SizeEstimate) -> Option<String> {
+    let stat = statvfs(target).ok()?;
+    missing_inodes(stat.f_files, estimate)
+}
+
+fn missing_inodes(total: u64, estimate: &SizeEstimate) -> Option<String> {
+    (total < estimate.inodes()).then(|| {
+        format!(
+            "it has {} inodes, modules need {}",
+            total,
+            estimate.inodes()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate(files: u64, ext4_blocks: u64) -> SizeEstimate {
+        SizeEstimate {
+            files,
+            dirs: files / 10,
+            ext4_blocks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn images_grow_to_the_estimate_but_never_shrink() {
+        let small = estimate(100, 1000).ext4_image_size();
+        let large = estimate(200_000, 4 << 20).ext4_image_size();
+        assert!(large > small);
+
+        assert_eq!(grown_size(small, large), Some(large));
+        assert_eq!(grown_size(large, large), None);
+        assert_eq!(grown_size(large, small), None);
+        assert_eq!(grown_size(0, small), Some(small));
+    }
+
+    #[test]
+    fn reused_images_need_an_inode_per_entry() {
+        let modules = estimate(1000, 0);
+        assert_eq!(modules.inodes(), 1101);
+
+        assert_eq!(missing_inodes(1101, &modules), None);
+        assert_eq!(missing_inodes(u64::MAX, &modules), None);
+        assert_eq!(
+            missing_inodes(1100, &modules).as_deref(),
+            Some("it has 1100 inodes, modules need 1101")
+        );
+    }
+
+    #[test]
+    fn new_images_leave_room_for_sync_backups() {
+        let modules = estimate(100_000, 0);
+        assert_eq!(modules.ext4_inodes(), modules.inodes() * 3 / 2);
+        assert_eq!(missing_inodes(modules.ext4_inodes(), &modules), None);
+    }
+}
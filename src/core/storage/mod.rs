@@ -0,0 +1,509 @@
+mod erofs;
+mod estimate;
+mod ext4;
+mod squashfs;
+mod tmpfs;
+
+use std::{collections::VecDeque, fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use loopdev::LoopControl;
+use rustix::mount::{
+    MountFlags, MountPropagationFlags, UnmountFlags, mount, mount_change, unmount as umount,
+};
+use serde::{Deserialize, Serialize};
+
+use self::{
+    erofs::ErofsBackend, ext4::Ext4Backend, squashfs::SquashfsBackend, tmpfs::TmpfsBackend,
+};
+pub use self::{
+    erofs::erofs_builder,
+    estimate::{SizeEstimate, format_size},
+};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::mount::umount_mgr::send_umountable;
+use crate::{
+    conf::config::{Config, OverlayMode, TmpfsConfig},
+    core::{
+        inventory::{Module, MountMode},
+        ops::dedup::DedupStats,
+    },
+    sys::{caps, mount::is_mounted},
+    utils::{ensure_dir_exists, lsetfilecon},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    Tmpfs,
+    #[default]
+    Ext4,
+    Erofs,
+    Squashfs,
+}
+
+impl StorageKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            StorageKind::Tmpfs => "Tmpfs",
+            StorageKind::Ext4 => "Ext4",
+            StorageKind::Erofs => "EROFS",
+            StorageKind::Squashfs => "SquashFS",
+        }
+    }
+
+    // Image backends are mounted read-only once committed.
+    pub fn is_read_only(self) -> bool {
+        matches!(self, StorageKind::Erofs | StorageKind::Squashfs)
+    }
+
+    fn backend(
+        self,
+        mnt_base: &Path,
+        img_path: &Path,
+        config: &Config,
+        estimate: SizeEstimate,
+    ) -> Box<dyn StorageBackend> {
+        match self {
+            StorageKind::Tmpfs => Box::new(TmpfsBackend::new(mnt_base, config, estimate)),
+            StorageKind::Ext4 => Box::new(Ext4Backend::new(mnt_base, img_path, config, estimate)),
+            StorageKind::Erofs => Box::new(ErofsBackend::new(mnt_base, img_path, config, estimate)),
+            StorageKind::Squashfs => {
+                Box::new(SquashfsBackend::new(mnt_base, img_path, config, estimate))
+            }
+        }
+    }
+}
+
+impl From<&OverlayMode> for StorageKind {
+    fn from(mode: &OverlayMode) -> Self {
+        match mode {
+            OverlayMode::Tmpfs => StorageKind::Tmpfs,
+            OverlayMode::Ext4 => StorageKind::Ext4,
+            OverlayMode::Erofs => StorageKind::Erofs,
+            OverlayMode::Squashfs => StorageKind::Squashfs,
+        }
+    }
+}
+
+// Outcome of trying one backend of the chain; the selected backend is the one without error.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageAttempt {
+    pub backend: StorageKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct Storage {
+    pub backend: Box<dyn StorageBackend>,
+    pub attempts: Vec<StorageAttempt>,
+    pub dedup: DedupStats,
+    // the rest of the chain, in case the selected backend fails to populate or commit
+    fallbacks: VecDeque<Box<dyn StorageBackend>>,
+}
+
+impl Storage {
+    // Prepares the first backend of the chain that works.
+    fn select(mut chain: VecDeque<Box<dyn StorageBackend>>) -> Result<Self> {
+        let mut attempts = Vec::new();
+        let backend = prepare_next(&mut chain, &mut attempts)?;
+
+        Ok(Self {
+            backend,
+            attempts,
+            dedup: DedupStats::default(),
+            fallbacks: chain,
+        })
+    }
+
+    // Syncs the modules and commits the result. A backend that fails either step is torn
+    // down and the rest of the chain is tried from prepare on.
+    pub fn populate(&mut self, modules: &[Module]) -> Result<()> {
+        loop {
+            let backend = &mut self.backend;
+            let err = match backend
+                .populate(modules)
+                .and_then(|dedup| backend.commit().map(|()| dedup))
+            {
+                Ok(dedup) => {
+                    self.dedup = dedup;
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            log::warn!("{} storage failed: {:#}", backend.kind().label(), err);
+            backend.teardown();
+            if let Some(attempt) = self.attempts.last_mut() {
+                attempt.error = Some(format!("{:#}", err));
+            }
+
+            self.backend = prepare_next(&mut self.fallbacks, &mut self.attempts)?;
+            log::info!(
+                ">> Storage Backend: falling back to [{}]",
+                self.backend.describe()
+            );
+        }
+    }
+}
+
+// Lifecycle of the storage that module files are synced into: prepare mounts it, populate
+// syncs the modules, commit makes the result final, and teardown undoes whatever was mounted.
+pub trait StorageBackend {
+    fn kind(&self) -> StorageKind;
+
+    // Where modules live; may change on commit for backends that stage first.
+    fn mount_point(&self) -> &Path;
+
+    fn prepare(&mut self) -> Result<()>;
+
+    fn populate(&mut self, modules: &[Module]) -> Result<DedupStats>;
+
+    fn commit(&mut self) -> Result<()>;
+
+    fn teardown(&mut self);
+
+    fn describe(&self) -> String;
+}
+
+pub fn setup(mnt_base: &Path, img_path: &Path, config: &Config) -> Result<Storage> {
+    if is_mounted(mnt_base) {
+        let _ = umount(mnt_base, UnmountFlags::DETACH);
+    }
+
+    let estimate = SizeEstimate::scan(&config.moduledir).unwrap_or_else(|e| {
+        log::warn!("Failed to estimate module size: {:#}", e);
+        SizeEstimate::default()
+    });
+    log::debug!("Module size estimate: {:?}", estimate);
+
+    let chain = config
+        .effective_storage_chain()
+        .iter()
+        .map(|mode| StorageKind::from(mode).backend(mnt_base, img_path, config, estimate))
+        .collect();
+
+    Storage::select(chain)
+}
+
+// Pops backends off the chain until one prepares, recording every attempt.
+fn prepare_next(
+    chain: &mut VecDeque<Box<dyn StorageBackend>>,
+    attempts: &mut Vec<StorageAttempt>,
+) -> Result<Box<dyn StorageBackend>> {
+    while let Some(mut backend) = chain.pop_front() {
+        let kind = backend.kind();
+        match backend.prepare() {
+            Ok(()) => {
+                attempts.push(StorageAttempt {
+                    backend: kind,
+                    error: None,
+                });
+                return Ok(backend);
+            }
+            Err(e) => {
+                log::warn!("{} storage unavailable: {:#}", kind.label(), e);
+                backend.teardown();
+                attempts.push(StorageAttempt {
+                    backend: kind,
+                    error: Some(format!("{:#}", e)),
+                });
+            }
+        }
+    }
+
+    let reasons: Vec<String> = attempts
+        .iter()
+        .map(|a| {
+            format!(
+                "{}: {}",
+                a.backend.label(),
+                a.error.as_deref().unwrap_or_default()
+            )
+        })
+        .collect();
+    bail!("No storage backend available ({})", reasons.join("; "))
+}
+
+fn make_private(path: &Path) {
+    if let Err(e) = mount_change(path, MountPropagationFlags::PRIVATE) {
+        log::warn!("Failed to make storage private: {}", e);
+    }
+}
+
+fn hide(path: &Path, disable_umount: bool) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if !disable_umount {
+        let _ = send_umountable(path);
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = (path, disable_umount);
+}
+
+// Image backends sync into a tmpfs first and pack it on commit.
+fn prepare_staging(
+    staging: &Path,
+    mount_source: &str,
+    limits: &TmpfsConfig,
+    disable_umount: bool,
+) -> Result<()> {
+    if is_mounted(staging) {
+        let _ = umount(staging, UnmountFlags::DETACH);
+    }
+    if staging.exists() {
+        let _ = fs::remove_dir_all(staging);
+    }
+    ensure_dir_exists(staging)?;
+
+    crate::sys::mount::mount_tmpfs_with(staging, mount_source, &limits.mount_options())?;
+
+    make_private(staging);
+    hide(staging, disable_umount);
+
+    Ok(())
+}
+
+fn finish_staging(staging: &Path) {
+    if let Err(e) = umount(staging, UnmountFlags::DETACH) {
+        log::warn!("Failed to unmount staging tmpfs: {}", e);
+    }
+
+    if let Err(e) = fs::remove_dir(staging) {
+        log::debug!("Failed to remove staging dir: {}", e);
+    }
+}
+
+// Read-only images can't grow a magic mount workspace later, so it is created before packing.
+fn create_magic_workspace(modules: &[Module], root: &Path) {
+    // Without overlayfs the planner sends every module through magic mount.
+    let needs_magic = !caps::probe().kernel.overlayfs
+        || modules.iter().any(|m| {
+            m.rules.effective_default() == MountMode::Magic
+                || m.rules
+                    .paths
+                    .values()
+                    .chain(m.rules.partition_defaults.values())
+                    .any(|v| *v == MountMode::Magic)
+        });
+
+    if needs_magic {
+        let magic_ws = root.join("magic_workspace");
+        if !magic_ws.exists() {
+            let _ = fs::create_dir(magic_ws);
+        }
+    }
+}
+
+// Every image backend attaches a loop device, so all of them need loop-control.
+fn ensure_loop_control() -> Result<()> {
+    if !caps::probe().kernel.loop_control {
+        bail!("/dev/loop-control is missing");
+    }
+    Ok(())
+}
+
+fn mount_image_ro(image_path: &Path, target: &Path, fstype: &str) -> Result<()> {
+    ensure_dir_exists(target)?;
+    lsetfilecon(image_path, "u:object_r:ksu_file:s0").ok();
+
+    let lc = LoopControl::open().context("Failed to open loop control")?;
+    let ld = lc.next_free().context("Failed to find free loop device")?;
+
+    ld.with()
+        .read_only(true)
+        .autoclear(true)
+        .attach(image_path)
+        .context("Failed to attach source to loop device")?;
+
+    let device_path = ld.path().context("Could not get loop device path")?;
+    log::debug!("loop device path: {}", device_path.display());
+
+    mount(
+        &device_path,
+        target,
+        fstype,
+        MountFlags::NOATIME | MountFlags::NODEV | MountFlags::RDONLY,
+        Some(c""),
+    )
+    .context(format!(
+        "Failed to mount {} to {}",
+        device_path.display(),
+        target.display()
+    ))?;
+
+    if fs::read_dir(target)?.next().is_none() {
+        bail!(
+            "{} mount success but directory is empty (Loop device failure?)",
+            fstype
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, path::PathBuf, rc::Rc};
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Stage {
+        Prepare,
+        Populate,
+        Commit,
+    }
+
+    struct Fake {
+        kind: StorageKind,
+        fails_at: Option<Stage>,
+        path: PathBuf,
+        log: Log,
+    }
+
+    impl Fake {
+        fn step(&self, stage: Stage, name: &str) -> Result<()> {
+            self.log
+                .borrow_mut()
+                .push(format!("{} {}", self.kind.label(), name));
+            if self.fails_at == Some(stage) {
+                return Err(anyhow!("{} broke", name));
+            }
+            Ok(())
+        }
+    }
+
+    impl StorageBackend for Fake {
+        fn kind(&self) -> StorageKind {
+            self.kind
+        }
+
+        fn mount_point(&self) -> &Path {
+            &self.path
+        }
+
+        fn prepare(&mut self) -> Result<()> {
+            self.step(Stage::Prepare, "prepare")
+        }
+
+        fn populate(&mut self, _modules: &[Module]) -> Result<DedupStats> {
+            self.step(Stage::Populate, "populate")?;
+            Ok(DedupStats { files: 1, bytes: 2 })
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            self.step(Stage::Commit, "commit")
+        }
+
+        fn teardown(&mut self) {
+            let _ = self.step(Stage::Prepare, "teardown");
+        }
+
+        fn describe(&self) -> String {
+            self.kind.label().to_string()
+        }
+    }
+
+    fn chain(
+        backends: &[(StorageKind, Option<Stage>)],
+    ) -> (VecDeque<Box<dyn StorageBackend>>, Log) {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let chain = backends
+            .iter()
+            .map(|(kind, fails_at)| {
+                Box::new(Fake {
+                    kind: *kind,
+                    fails_at: *fails_at,
+                    path: PathBuf::from("/mnt"),
+                    log: log.clone(),
+                }) as Box<dyn StorageBackend>
+            })
+            .collect();
+        (chain, log)
+    }
+
+    fn errors(storage: &Storage) -> Vec<(StorageKind, Option<String>)> {
+        storage
+            .attempts
+            .iter()
+            .map(|a| (a.backend, a.error.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn commit_failure_moves_on_to_the_next_backend() {
+        let (chain, log) = chain(&[
+            (StorageKind::Erofs, Some(Stage::Commit)),
+            (StorageKind::Tmpfs, None),
+            (StorageKind::Ext4, None),
+        ]);
+
+        let mut storage = Storage::select(chain).unwrap();
+        storage.populate(&[]).unwrap();
+
+        assert_eq!(storage.backend.kind(), StorageKind::Tmpfs);
+        assert_eq!(storage.dedup.files, 1);
+        assert_eq!(
+            errors(&storage),
+            [
+                (StorageKind::Erofs, Some("commit broke".to_string())),
+                (StorageKind::Tmpfs, None),
+            ]
+        );
+        assert_eq!(
+            *log.borrow(),
+            [
+                "EROFS prepare",
+                "EROFS populate",
+                "EROFS commit",
+                "EROFS teardown",
+                "Tmpfs prepare",
+                "Tmpfs populate",
+                "Tmpfs commit",
+            ]
+        );
+    }
+
+    #[test]
+    fn populate_failure_skips_backends_that_cannot_prepare() {
+        let (chain, _) = chain(&[
+            (StorageKind::Ext4, Some(Stage::Populate)),
+            (StorageKind::Tmpfs, Some(Stage::Prepare)),
+            (StorageKind::Erofs, None),
+        ]);
+
+        let mut storage = Storage::select(chain).unwrap();
+        storage.populate(&[]).unwrap();
+
+        assert_eq!(storage.backend.kind(), StorageKind::Erofs);
+        assert_eq!(
+            errors(&storage),
+            [
+                (StorageKind::Ext4, Some("populate broke".to_string())),
+                (StorageKind::Tmpfs, Some("prepare broke".to_string())),
+                (StorageKind::Erofs, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn fails_once_the_chain_runs_out() {
+        let (chain, _) = chain(&[
+            (StorageKind::Tmpfs, Some(Stage::Prepare)),
+            (StorageKind::Ext4, Some(Stage::Commit)),
+        ]);
+
+        let mut storage = Storage::select(chain).unwrap();
+        let err = storage.populate(&[]).unwrap_err().to_string();
+
+        assert_eq!(
+            err,
+            "No storage backend available (Tmpfs: prepare broke; Ext4: commit broke)"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use rustix::mount::{UnmountFlags, unmount as umount};
+
+use super::{
+    SizeEstimate, StorageBackend, StorageKind, create_magic_workspace, ensure_loop_control,
+    finish_staging, hide, make_private, mount_image_ro, prepare_staging,
+};
+use crate::{
+    conf::config::{Config, TmpfsConfig},
+    core::{
+        inventory::Module,
+        ops::{dedup::DedupStats, sync},
+    },
+    defs,
+    sys::{
+        caps::{self, Caps},
+        mksquashfs,
+        mount::is_mounted,
+    },
+    utils::lsetfilecon,
+};
+
+// Staged like EROFS, for kernels that ship squashfs but not EROFS.
+pub struct SquashfsBackend {
+    staging: PathBuf,
+    target: PathBuf,
+    image: PathBuf,
+    mount_source: String,
+    tmpfs: TmpfsConfig,
+    estimate: SizeEstimate,
+    disable_umount: bool,
+    caps: &'static Caps,
+    committed: bool,
+}
+
+impl SquashfsBackend {
+    pub fn new(mnt_base: &Path, img_path: &Path, config: &Config, estimate: SizeEstimate) -> Self {
+        Self {
+            staging: Path::new(defs::RUN_DIR).join("squashfs_staging"),
+            target: mnt_base.to_path_buf(),
+            image: img_path.with_extension("squashfs"),
+            mount_source: config.mountsource.clone(),
+            tmpfs: config.tmpfs.clone(),
+            estimate,
+            disable_umount: config.disable_umount,
+            caps: caps::probe(),
+            committed: false,
+        }
+    }
+}
+
+impl StorageBackend for SquashfsBackend {
+    fn kind(&self) -> StorageKind {
+        StorageKind::Squashfs
+    }
+
+    fn mount_point(&self) -> &Path {
+        if self.committed {
+            &self.target
+        } else {
+            &self.staging
+        }
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            self.caps.kernel.squashfs,
+            "Kernel does not support SquashFS"
+        );
+        ensure!(self.caps.tools.mksquashfs.is_some(), "mksquashfs not found");
+        ensure_loop_control()?;
+
+        self.estimate
+            .check_tmpfs(&self.tmpfs)
+            .context("Modules do not fit in the staging tmpfs")?;
+
+        prepare_staging(
+            &self.staging,
+            &self.mount_source,
+            &self.tmpfs,
+            self.disable_umount,
+        )
+    }
+
+    fn populate(&mut self, modules: &[Module]) -> Result<DedupStats> {
+        let dedup = sync::perform_sync(modules, &self.staging)?;
+        create_magic_workspace(modules, &self.staging);
+
+        Ok(dedup)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.image.exists() {
+            fs::remove_file(&self.image).context("Failed to remove stale SquashFS image")?;
+        }
+
+        run_mksquashfs(&self.staging, &self.image).context("Failed to pack SquashFS image")?;
+        let _ = fs::set_permissions(&self.image, fs::Permissions::from_mode(0o644));
+        lsetfilecon(&self.image, "u:object_r:ksu_file:s0")?;
+
+        finish_staging(&self.staging);
+
+        mount_image_ro(&self.image, &self.target, "squashfs")
+            .context("Failed to mount finalized SquashFS image")?;
+
+        make_private(&self.target);
+        hide(&self.target, self.disable_umount);
+
+        self.committed = true;
+        Ok(())
+    }
+
+    fn teardown(&mut self) {
+        if is_mounted(&self.staging) {
+            let _ = umount(&self.staging, UnmountFlags::DETACH);
+        }
+        let _ = fs::remove_dir_all(&self.staging);
+
+        if self.committed && is_mounted(&self.target) {
+            let _ = umount(&self.target, UnmountFlags::DETACH);
+        }
+        self.committed = false;
+    }
+
+    fn describe(&self) -> String {
+        "SQUASHFS".to_string()
+    }
+}
+
+// mksquashfs takes the source and the image first and its options after them.
+fn mksquashfs_command(src_dir: &Path, image_path: &Path) -> Command {
+    let mut command = Command::new(mksquashfs::command());
+    command
+        .arg(src_dir)
+        .arg(image_path)
+        .args(mksquashfs::build_args());
+    command
+}
+
+fn run_mksquashfs(src_dir: &Path, image_path: &Path) -> Result<()> {
+    log::info!(
+        "Packing SquashFS image with options: {}",
+        mksquashfs::build_args().join(" ")
+    );
+
+    let output = mksquashfs_command(src_dir, image_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .context("Failed to execute mksquashfs")?;
+
+    if !output.status.success() {
+        bail!(
+            "Failed to create SquashFS image: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::core::storage::Storage;
+
+    #[test]
+    fn mksquashfs_gets_source_image_then_options() {
+        let command = mksquashfs_command(Path::new("/stage"), Path::new("/img.squashfs"));
+
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "/stage",
+                "/img.squashfs",
+                "-noappend",
+                "-no-progress",
+                "-xattrs"
+            ]
+        );
+    }
+
+    fn backend(dir: &TempDir, caps: Caps) -> Box<dyn StorageBackend> {
+        let mut backend = SquashfsBackend::new(
+            &dir.path().join("mnt"),
+            &dir.path().join("modules.img"),
+            &Config::default(),
+            SizeEstimate::default(),
+        );
+        backend.staging = dir.path().join("staging");
+        backend.caps = Box::leak(Box::new(caps));
+        Box::new(backend)
+    }
+
+    #[test]
+    fn selection_skips_squashfs_without_mksquashfs() {
+        let dir = TempDir::new().unwrap();
+        let mut no_tool = Caps::default();
+        no_tool.kernel.squashfs = true;
+        let mut no_kernel = Caps::default();
+        no_kernel.tools.mksquashfs = Some("mksquashfs version 4.6".to_string());
+
+        let chain = VecDeque::from([backend(&dir, no_tool), backend(&dir, no_kernel)]);
+        let err = Storage::select(chain).err().unwrap().to_string();
+
+        assert_eq!(
+            err,
+            "No storage backend available (SquashFS: mksquashfs not found; \
+             SquashFS: Kernel does not support SquashFS)"
+        );
+        assert!(!dir.path().join("staging").exists());
+    }
+}
@@ -0,0 +1,88 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, ensure};
+use rustix::mount::{UnmountFlags, unmount as umount};
+
+use super::{SizeEstimate, StorageBackend, StorageKind, hide, make_private};
+use crate::{
+    conf::config::{Config, TmpfsConfig},
+    core::{
+        inventory::Module,
+        ops::{dedup::DedupStats, sync},
+    },
+    sys::{caps, mount::is_mounted},
+};
+
+pub struct TmpfsBackend {
+    target: PathBuf,
+    mount_source: String,
+    limits: TmpfsConfig,
+    estimate: SizeEstimate,
+    disable_umount: bool,
+}
+
+impl TmpfsBackend {
+    pub fn new(mnt_base: &Path, config: &Config, estimate: SizeEstimate) -> Self {
+        Self {
+            target: mnt_base.to_path_buf(),
+            mount_source: config.mountsource.clone(),
+            limits: config.tmpfs.clone(),
+            estimate,
+            disable_umount: config.disable_umount,
+        }
+    }
+}
+
+impl StorageBackend for TmpfsBackend {
+    fn kind(&self) -> StorageKind {
+        StorageKind::Tmpfs
+    }
+
+    fn mount_point(&self) -> &Path {
+        &self.target
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        let xattr = caps::probe().kernel.tmpfs_xattr;
+        ensure!(
+            xattr.supported,
+            "Tmpfs does not support overlay xattrs ({})",
+            xattr.method.name()
+        );
+
+        self.estimate.check_tmpfs(&self.limits)?;
+
+        crate::sys::mount::mount_tmpfs_with(
+            &self.target,
+            &self.mount_source,
+            &self.limits.mount_options(),
+        )?;
+        log::info!(
+            "Tmpfs mounted and supports xattrs ({}).",
+            xattr.method.name()
+        );
+
+        make_private(&self.target);
+        hide(&self.target, self.disable_umount);
+
+        Ok(())
+    }
+
+    fn populate(&mut self, modules: &[Module]) -> Result<DedupStats> {
+        sync::perform_sync(modules, &self.target)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn teardown(&mut self) {
+        if is_mounted(&self.target) {
+            let _ = umount(&self.target, UnmountFlags::DETACH);
+        }
+    }
+
+    fn describe(&self) -> String {
+        "TMPFS".to_string()
+    }
+}
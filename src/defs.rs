@@ -1,3 +1,4 @@
+pub const BASE_DIR: &str = "/data/adb/hybrid-mount";
 pub const MODULES_IMG_FILE: &str = "/data/adb/hybrid-mount/modules.img";
 pub const RUN_DIR: &str = "/data/adb/hybrid-mount/run/";
 pub const STATE_FILE: &str = "/data/adb/hybrid-mount/run/daemon_state.json";
@@ -8,9 +9,27 @@ pub const SYSTEM_RW_DIR: &str = "/data/adb/hybrid-mount/rw";
 pub const MODULE_PROP_FILE: &str = "/data/adb/modules/hybrid-mount/module.prop";
 pub const MODULES_DIR: &str = "/data/adb/modules";
 pub const CONFIG_FILE: &str = "/data/adb/hybrid-mount/config.toml";
+pub const CONFIG_DROP_IN_DIR: &str = "/data/adb/hybrid-mount/config.d";
 pub const MKFS_EROFS_PATH: &str = "/data/adb/metamodule/tools/mkfs.erofs";
+pub const MKSQUASHFS_PATH: &str = "/data/adb/metamodule/tools/mksquashfs";
 pub const POACEAE_MOUNT_POINT: &str = "/data/adb/poaceaefs_mount";
 pub const ZYGISKSU_DENYLIST_FILE: &str = "/data/adb/zygisksu/denylist_enforce";
+pub const KERNEL_RELEASE_FILE: &str = "/proc/sys/kernel/osrelease";
+pub const PROP_FILE_ENV: &str = "HYBRID_MOUNT_PROP_FILE";
+
+pub const BUILD_PROP_FILES: &[&str] = &[
+    "/system/build.prop",
+    "/system_ext/etc/build.prop",
+    "/vendor/build.prop",
+    "/product/etc/build.prop",
+    "/odm/etc/build.prop",
+];
+
+pub const LEGACY_BASE_DIRS: &[&str] = &[
+    "/data/adb/Hybrid-Mount",
+    "/data/adb/meta-hybrid",
+    "/data/adb/Meta-Hybrid",
+];
 
 pub const BUILTIN_PARTITIONS: &[&str] = &[
     "system",
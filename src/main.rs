@@ -72,20 +72,31 @@ fn main() -> Result<()> {
     if let Some(command) = &cli.command {
         match command {
             Commands::GenConfig { output } => cli_handlers::handle_gen_config(output)?,
-            Commands::ShowConfig => cli_handlers::handle_show_config(&cli)?,
-            Commands::SaveConfig { payload } => cli_handlers::handle_save_config(payload)?,
-            Commands::SaveModuleRules { module, payload } => {
-                cli_handlers::handle_save_module_rules(module, payload)?
+            Commands::ShowConfig { sources } => cli_handlers::handle_show_config(&cli, *sources)?,
+            Commands::SaveConfig { input } => cli_handlers::handle_save_config(input)?,
+            Commands::SaveModuleRules { module, input } => {
+                cli_handlers::handle_save_module_rules(module, input)?
             }
+            Commands::CheckConfig { file, input } => {
+                cli_handlers::handle_check_config(&cli, file.as_deref(), input.as_ref())?
+            }
+            Commands::Profile { action } => cli_handlers::handle_profile(&cli, action)?,
             Commands::Modules => cli_handlers::handle_modules(&cli)?,
             Commands::Conflicts => cli_handlers::handle_conflicts(&cli)?,
             Commands::Diagnostics => cli_handlers::handle_diagnostics(&cli)?,
+            Commands::Doctor => cli_handlers::handle_doctor()?,
+            Commands::Sync { dry_run } => cli_handlers::handle_sync(&cli, *dry_run)?,
             Commands::Poaceae { target, action } => cli_handlers::handle_poaceae(target, action)?,
         }
 
         return Ok(());
     }
 
+    utils::init_logging().context("Failed to initialize logging")?;
+
+    // Before the first read, so an upgrade boots with the config from the legacy directory.
+    conf::config::migrate_on_disk();
+
     let mut config = load_final_config(&cli)?;
 
     if utils::check_zygisksu_enforce_status() {
@@ -100,8 +111,6 @@ fn main() -> Result<()> {
         }
     }
 
-    utils::init_logging().context("Failed to initialize logging")?;
-
     let camouflage_name = utils::random_kworker_name();
 
     if let Err(e) = utils::camouflage_process(&camouflage_name) {
@@ -118,6 +127,14 @@ fn main() -> Result<()> {
 
     utils::check_ksu();
 
+    for missing in sys::caps::probe().missing() {
+        log::info!(
+            "Preflight: {} unavailable, {}",
+            missing.name,
+            missing.impact
+        );
+    }
+
     if config.disable_umount {
         log::warn!("!! Umount is DISABLED via config.");
     }
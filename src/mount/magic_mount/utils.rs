@@ -1,7 +1,7 @@
 // Copyright 2026 https://github.com/Tools-cx-app/meta-magic_mount
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::{self, DirEntry, Metadata, create_dir, create_dir_all, read_link},
     os::unix::fs::{MetadataExt, symlink},
     path::{Path, PathBuf},
@@ -108,7 +108,8 @@ where
 pub fn collect_module_files(
     module_dir: &Path,
     extra_partitions: &[String],
-    need_id: HashSet<String>,
+    need_roots: &HashMap<String, Vec<PathBuf>>,
+    module_order: &[String],
 ) -> Result<Option<Node>> {
     let mut root = Node::new_root("");
     let mut system = Node::new_root("system");
@@ -117,7 +118,18 @@ pub fn collect_module_files(
 
     log::debug!("begin collect module files: {}", module_root.display());
 
-    for entry in module_root.read_dir()?.flatten() {
+    // The first module to claim a path keeps it, so walk modules by precedence.
+    let mut entries: Vec<DirEntry> = module_root.read_dir()?.flatten().collect();
+    entries.sort_by_key(|entry| {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let rank = module_order
+            .iter()
+            .position(|id| *id == name)
+            .unwrap_or(usize::MAX);
+        (rank, name)
+    });
+
+    for entry in entries {
         if !entry.file_type()?.is_dir() {
             continue;
         }
@@ -125,10 +137,10 @@ pub fn collect_module_files(
         let id = entry.file_name().to_str().unwrap().to_string();
         log::debug!("processing new module: {id}");
 
-        if !need_id.contains(&id) {
+        let Some(roots) = need_roots.get(&id) else {
             log::debug!("module {id} was blocked.");
             continue;
-        }
+        };
 
         let prop = entry.path().join("module.prop");
         if !prop.exists() {
@@ -176,7 +188,11 @@ pub fn collect_module_files(
                 continue;
             }
 
-            has_file.insert(system.collect_module_files(entry.path().join(&p))?);
+            has_file.insert(system.collect_module_files_filtered(
+                entry.path().join(&p),
+                Path::new(&p),
+                roots,
+            )?);
         }
     }
 
@@ -242,3 +258,45 @@ where
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn owner(module_dir: &Path, order: &[&str]) -> PathBuf {
+        let need_roots = ["mod_a", "mod_b"]
+            .map(|id| (id.to_string(), vec![PathBuf::from("system/etc")]))
+            .into();
+        let order: Vec<String> = order.iter().map(|id| id.to_string()).collect();
+
+        let root = collect_module_files(module_dir, &[], &need_roots, &order)
+            .unwrap()
+            .unwrap();
+        root.children["system"].children["etc"].children["hosts"]
+            .module_path
+            .clone()
+            .unwrap()
+    }
+
+    #[test]
+    fn first_module_by_precedence_claims_a_path() {
+        let dir = TempDir::new().unwrap();
+        for id in ["mod_a", "mod_b"] {
+            let module = dir.path().join(id);
+            create_dir_all(module.join("system/etc")).unwrap();
+            fs::write(module.join("module.prop"), format!("id={id}\n")).unwrap();
+            fs::write(module.join("system/etc/hosts"), id).unwrap();
+        }
+
+        assert_eq!(
+            owner(dir.path(), &["mod_b", "mod_a"]),
+            dir.path().join("mod_b/system/etc/hosts")
+        );
+        assert_eq!(
+            owner(dir.path(), &["mod_a", "mod_b"]),
+            dir.path().join("mod_a/system/etc/hosts")
+        );
+    }
+}
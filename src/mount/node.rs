@@ -83,6 +83,61 @@ impl Node {
         Ok(has_file)
     }
 
+    // Like `collect_module_files`, but only takes entries inside `roots` (paths relative
+    // to the module root). An empty `roots` takes everything.
+    pub fn collect_module_files_filtered<P>(
+        &mut self,
+        module_dir: P,
+        relative: &Path,
+        roots: &[PathBuf],
+    ) -> Result<bool>
+    where
+        P: AsRef<Path>,
+    {
+        if roots.is_empty() || roots.iter().any(|r| relative.starts_with(r)) {
+            return self.collect_module_files(module_dir);
+        }
+
+        let dir = module_dir.as_ref();
+        let mut has_file = false;
+        for entry in dir.read_dir()?.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let child_relative = relative.join(&name);
+
+            let selected = roots.iter().any(|r| child_relative.starts_with(r));
+            let on_path = entry.file_type().is_ok_and(|t| t.is_dir())
+                && roots.iter().any(|r| r.starts_with(&child_relative));
+            if !selected && !on_path {
+                continue;
+            }
+
+            let node = match self.children.entry(name.clone()) {
+                Entry::Occupied(o) => Some(o.into_mut()),
+                Entry::Vacant(v) => Self::new_module(&name, &entry).map(|mut it| {
+                    // Only part of this directory is ours, so it must not replace the original.
+                    it.replace &= selected;
+                    v.insert(it)
+                }),
+            };
+
+            if let Some(node) = node {
+                has_file |= if node.file_type != NodeFileType::Directory {
+                    true
+                } else if selected {
+                    node.collect_module_files(dir.join(&node.name))? || node.replace
+                } else {
+                    node.collect_module_files_filtered(
+                        dir.join(&node.name),
+                        &child_relative,
+                        roots,
+                    )?
+                }
+            }
+        }
+
+        Ok(has_file)
+    }
+
     fn dir_is_replace<P>(path: P) -> bool
     where
         P: AsRef<Path>,
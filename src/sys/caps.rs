@@ -0,0 +1,308 @@
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use rustix::{io::Errno, mount::FsOpenFlags};
+use serde::{Deserialize, Serialize};
+
+use crate::sys::{
+    mkfs_erofs::{self, MkfsErofsInfo},
+    mksquashfs,
+    tmpfs_xattr::{self, TmpfsXattrSupport},
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KernelCaps {
+    pub overlayfs: bool,
+    pub erofs: bool,
+    pub squashfs: bool,
+    pub tmpfs_xattr: TmpfsXattrSupport,
+    pub loop_control: bool,
+    pub fsopen: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolCaps {
+    pub mkfs_ext4: Option<PathBuf>,
+    pub e2fsck: Option<PathBuf>,
+    pub resize2fs: Option<PathBuf>,
+    pub mkfs_erofs: Option<MkfsErofsInfo>,
+    pub mksquashfs: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Caps {
+    pub kernel: KernelCaps,
+    pub tools: ToolCaps,
+}
+
+// Something the probe did not find, and what boot loses without it.
+#[derive(Debug, Clone, Serialize)]
+pub struct MissingCap {
+    pub name: &'static str,
+    pub impact: &'static str,
+}
+
+static CAPS: OnceLock<Caps> = OnceLock::new();
+
+// Probed on first use and kept for the rest of the process.
+pub fn probe() -> &'static Caps {
+    probe_once(&CAPS, || Caps {
+        kernel: probe_kernel(),
+        tools: probe_tools(),
+    })
+}
+
+// Callers racing on the first use wait for a single probe instead of running their own.
+fn probe_once(cache: &OnceLock<Caps>, probe: impl FnOnce() -> Caps) -> &Caps {
+    cache.get_or_init(probe)
+}
+
+impl Caps {
+    pub fn missing(&self) -> Vec<MissingCap> {
+        let kernel = &self.kernel;
+        let tools = &self.tools;
+
+        [
+            (
+                kernel.overlayfs,
+                "overlayfs",
+                "all modules are magic mounted",
+            ),
+            (
+                kernel.loop_control,
+                "/dev/loop-control",
+                "ext4, EROFS and SquashFS storage can't be mounted",
+            ),
+            (
+                kernel.fsopen,
+                "fsopen",
+                "overlays are mounted with the legacy mount API",
+            ),
+            (
+                kernel.tmpfs_xattr.supported,
+                "tmpfs xattrs",
+                "tmpfs storage is unavailable",
+            ),
+            (kernel.erofs, "erofs", "EROFS storage is unavailable"),
+            (
+                kernel.squashfs,
+                "squashfs",
+                "SquashFS storage is unavailable",
+            ),
+            (
+                tools.mkfs_ext4.is_some(),
+                "mkfs.ext4",
+                "a new modules.img can't be created",
+            ),
+            (
+                tools.e2fsck.is_some(),
+                "e2fsck",
+                "modules.img can't be checked or repaired",
+            ),
+            (
+                tools.resize2fs.is_some(),
+                "resize2fs",
+                "modules.img is recreated instead of grown",
+            ),
+            (
+                tools.mkfs_erofs.is_some(),
+                "mkfs.erofs",
+                "EROFS images are built with the built-in writer",
+            ),
+            (
+                tools.mksquashfs.is_some(),
+                "mksquashfs",
+                "SquashFS storage is unavailable",
+            ),
+        ]
+        .into_iter()
+        .filter(|(present, _, _)| !present)
+        .map(|(_, name, impact)| MissingCap { name, impact })
+        .collect()
+    }
+}
+
+fn probe_kernel() -> KernelCaps {
+    let filesystems = fs::read_to_string("/proc/filesystems").unwrap_or_default();
+    let has_fs = |name: &str| has_filesystem(&filesystems, name);
+
+    KernelCaps {
+        overlayfs: has_fs("overlay"),
+        erofs: has_fs("erofs"),
+        squashfs: has_fs("squashfs"),
+        tmpfs_xattr: tmpfs_xattr::support(),
+        loop_control: Path::new("/dev/loop-control").exists(),
+        fsopen: has_fsopen(),
+    }
+}
+
+// `/proc/filesystems` lists one filesystem per line, after an optional `nodev` flag.
+fn has_filesystem(filesystems: &str, name: &str) -> bool {
+    filesystems
+        .lines()
+        .any(|line| line.split_whitespace().last() == Some(name))
+}
+
+// Only ENOSYS means the syscall is missing; EPERM and friends still prove it exists.
+fn has_fsopen() -> bool {
+    match rustix::mount::fsopen("tmpfs", FsOpenFlags::FSOPEN_CLOEXEC) {
+        Ok(_) => true,
+        Err(e) => e != Errno::NOSYS,
+    }
+}
+
+fn probe_tools() -> ToolCaps {
+    ToolCaps {
+        mkfs_ext4: find_tool("mkfs.ext4"),
+        e2fsck: find_tool("e2fsck"),
+        resize2fs: find_tool("resize2fs"),
+        mkfs_erofs: mkfs_erofs::probe(),
+        mksquashfs: mksquashfs::version(),
+    }
+}
+
+fn find_tool(name: &str) -> Option<PathBuf> {
+    find_tool_in(&env::var_os("PATH")?, name)
+}
+
+fn find_tool_in(path: &OsStr, name: &str) -> Option<PathBuf> {
+    env::split_paths(path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| {
+            candidate
+                .metadata()
+                .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn full_caps() -> Caps {
+        Caps {
+            kernel: KernelCaps {
+                overlayfs: true,
+                erofs: true,
+                squashfs: true,
+                tmpfs_xattr: TmpfsXattrSupport {
+                    supported: true,
+                    ..Default::default()
+                },
+                loop_control: true,
+                fsopen: true,
+            },
+            tools: ToolCaps {
+                mkfs_ext4: Some(PathBuf::from("/system/bin/mkfs.ext4")),
+                e2fsck: Some(PathBuf::from("/system/bin/e2fsck")),
+                resize2fs: Some(PathBuf::from("/system/bin/resize2fs")),
+                mkfs_erofs: Some(MkfsErofsInfo::default()),
+                mksquashfs: Some("mksquashfs version 4.6.1".to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn probe_runs_once_for_every_caller() {
+        let cache = OnceLock::new();
+        let runs = AtomicUsize::new(0);
+        let probe = || {
+            runs.fetch_add(1, Ordering::SeqCst);
+            full_caps()
+        };
+
+        let results: Vec<&Caps> = thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| probe_once(&cache, probe)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(
+            results
+                .windows(2)
+                .all(|pair| std::ptr::eq(pair[0], pair[1]))
+        );
+        // later callers get the cached result, not a new probe
+        assert!(probe_once(&cache, Caps::default).kernel.overlayfs);
+    }
+
+    #[test]
+    fn filesystems_are_read_with_and_without_nodev() {
+        let filesystems = "nodev\tsysfs\nnodev\ttmpfs\nnodev\toverlay\n\text4\n\terofs\n";
+
+        assert!(has_filesystem(filesystems, "overlay"));
+        assert!(has_filesystem(filesystems, "erofs"));
+        assert!(has_filesystem(filesystems, "ext4"));
+        assert!(!has_filesystem(filesystems, "squashfs"));
+        assert!(!has_filesystem(filesystems, "nodev"));
+        assert!(!has_filesystem("", "overlay"));
+    }
+
+    #[test]
+    fn tools_are_found_in_path_order() {
+        let dir = TempDir::new().unwrap();
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        fs::create_dir_all(first.join("e2fsck")).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        for (dir, name, mode) in [
+            (&first, "mkfs.ext4", 0o644),
+            (&second, "mkfs.ext4", 0o755),
+            (&first, "resize2fs", 0o755),
+            (&second, "resize2fs", 0o755),
+        ] {
+            let tool = dir.join(name);
+            fs::write(&tool, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&tool, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let path = env::join_paths([&first, &second]).unwrap();
+
+        // not executable, so the second one is used
+        assert_eq!(
+            find_tool_in(&path, "mkfs.ext4"),
+            Some(second.join("mkfs.ext4"))
+        );
+        assert_eq!(
+            find_tool_in(&path, "resize2fs"),
+            Some(first.join("resize2fs"))
+        );
+        // a directory is not a tool
+        assert_eq!(find_tool_in(&path, "e2fsck"), None);
+        assert_eq!(find_tool_in(&path, "mkfs.erofs"), None);
+    }
+
+    #[test]
+    fn missing_lists_what_the_probe_did_not_find() {
+        assert!(full_caps().missing().is_empty());
+
+        let mut caps = full_caps();
+        caps.kernel.overlayfs = false;
+        caps.tools.mksquashfs = None;
+        let missing: Vec<_> = caps.missing().iter().map(|m| (m.name, m.impact)).collect();
+        assert_eq!(
+            missing,
+            [
+                ("overlayfs", "all modules are magic mounted"),
+                ("mksquashfs", "SquashFS storage is unavailable"),
+            ]
+        );
+
+        assert_eq!(Caps::default().missing().len(), 11);
+    }
+}
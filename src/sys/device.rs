@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use crate::defs;
+
+// Facts used by conditional module rules. Everything is read lazily and at most once.
+pub struct DeviceFacts {
+    props: OnceLock<HashMap<String, String>>,
+    kernel: OnceLock<String>,
+    prop_stub: Option<PathBuf>,
+    release_file: PathBuf,
+    root: PathBuf,
+}
+
+impl Default for DeviceFacts {
+    fn default() -> Self {
+        Self::new(
+            env::var_os(defs::PROP_FILE_ENV).map(PathBuf::from),
+            Path::new(defs::KERNEL_RELEASE_FILE),
+            Path::new("/"),
+        )
+    }
+}
+
+impl DeviceFacts {
+    // `prop_stub` replaces every other property source, which keeps rules testable off-device.
+    pub fn new(prop_stub: Option<PathBuf>, release_file: &Path, root: &Path) -> Self {
+        Self {
+            props: OnceLock::new(),
+            kernel: OnceLock::new(),
+            prop_stub,
+            release_file: release_file.to_path_buf(),
+            root: root.to_path_buf(),
+        }
+    }
+
+    pub fn prop(&self, key: &str) -> Option<&str> {
+        self.props
+            .get_or_init(|| load_props(self.prop_stub.as_deref()))
+            .get(key)
+            .map(String::as_str)
+    }
+
+    pub fn kernel_release(&self) -> &str {
+        self.kernel.get_or_init(|| {
+            fs::read_to_string(&self.release_file)
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn has_partition(&self, name: &str) -> bool {
+        !name.contains('/') && self.root.join(name).is_dir()
+    }
+}
+
+fn load_props(stub: Option<&Path>) -> HashMap<String, String> {
+    if let Some(path) = stub {
+        return fs::read_to_string(path)
+            .map(|content| parse_prop_file(&content))
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to read property stub {:?}: {}", path, e);
+                HashMap::new()
+            });
+    }
+
+    if let Ok(output) = Command::new("getprop").output()
+        && output.status.success()
+    {
+        return parse_getprop(&String::from_utf8_lossy(&output.stdout));
+    }
+
+    let mut props = HashMap::new();
+    for file in defs::BUILD_PROP_FILES {
+        if let Ok(content) = fs::read_to_string(file) {
+            for (k, v) in parse_prop_file(&content) {
+                props.entry(k).or_insert(v);
+            }
+        }
+    }
+    props
+}
+
+fn parse_prop_file(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+// `getprop` prints lines of the form `[key]: [value]`.
+fn parse_getprop(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once("]: ["))
+        .filter_map(|(k, v)| {
+            let key = k.trim().strip_prefix('[')?;
+            let value = v.trim().strip_suffix(']')?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
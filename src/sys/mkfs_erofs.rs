@@ -0,0 +1,168 @@
+use std::{
+    ffi::OsStr,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{conf::config::ErofsConfig, defs};
+
+const KNOWN_COMPRESSORS: &[&str] = &["lz4", "lz4hc", "lzma", "deflate", "libdeflate", "zstd"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MkfsErofsInfo {
+    pub version: String,
+    pub compressors: Vec<String>,
+    pub features: Vec<String>,
+}
+
+impl MkfsErofsInfo {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    // Rejects options this mkfs.erofs can't honour, before anything is staged.
+    pub fn check(&self, cfg: &ErofsConfig) -> Result<()> {
+        if let Some(name) = cfg.compressor.name()
+            && !self.compressors.iter().any(|c| c == name)
+        {
+            bail!(
+                "mkfs.erofs {} does not support the {} compressor (available: {})",
+                self.version,
+                name,
+                self.compressors.join(", ")
+            );
+        }
+
+        let required = [
+            (cfg.dedupe, "dedupe"),
+            (cfg.fragments, "fragments"),
+            (cfg.pcluster_size.is_some(), "pcluster"),
+            (cfg.timestamp.is_some(), "timestamp"),
+        ];
+
+        for (_, feature) in required.iter().filter(|(wanted, _)| *wanted) {
+            if !self.supports(feature) {
+                bail!("mkfs.erofs {} does not support {}", self.version, feature);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn command() -> &'static OsStr {
+    let bundled = Path::new(defs::MKFS_EROFS_PATH);
+    if bundled.exists() {
+        bundled.as_os_str()
+    } else {
+        OsStr::new("mkfs.erofs")
+    }
+}
+
+pub fn probe() -> Option<MkfsErofsInfo> {
+    let version = run(&["-V"])?;
+    let help = run(&["--help"]).unwrap_or_default();
+
+    Some(MkfsErofsInfo {
+        version: version
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+        compressors: parse_compressors(&help),
+        features: parse_features(&help),
+    })
+}
+
+pub fn build_args(cfg: &ErofsConfig) -> Vec<String> {
+    let mut args = vec!["-x".to_string(), "256".to_string()];
+
+    if let Some(name) = cfg.compressor.name() {
+        args.push("-z".to_string());
+        args.push(match cfg.level {
+            Some(level) => format!("{name},{level}"),
+            None => name.to_string(),
+        });
+    }
+
+    if let Some(size) = cfg.pcluster_size {
+        args.push(format!("-C{size}"));
+    }
+
+    let extended: Vec<&str> = [(cfg.dedupe, "dedupe"), (cfg.fragments, "fragments")]
+        .into_iter()
+        .filter_map(|(on, name)| on.then_some(name))
+        .collect();
+    if !extended.is_empty() {
+        args.push(format!("-E{}", extended.join(",")));
+    }
+
+    if let Some(timestamp) = cfg.timestamp {
+        args.push(format!("-T{timestamp}"));
+    }
+
+    args
+}
+
+fn run(args: &[&str]) -> Option<String> {
+    let output = Command::new(command())
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .ok()?;
+
+    // Older releases print help and version to stderr and exit non-zero.
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    (!text.trim().is_empty()).then_some(text)
+}
+
+// The list follows "Available compressors are:" and ends at the next option line.
+fn parse_compressors(help: &str) -> Vec<String> {
+    let Some(start) = help.find("Available compressors") else {
+        return Vec::new();
+    };
+
+    let section: String = help[start..]
+        .lines()
+        .enumerate()
+        .take_while(|(i, line)| *i == 0 || !line.trim_start().starts_with('-'))
+        .map(|(_, line)| line)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let words: Vec<&str> = section
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .collect();
+
+    KNOWN_COMPRESSORS
+        .iter()
+        .filter(|name| words.contains(name))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn parse_features(help: &str) -> Vec<String> {
+    let options: Vec<&str> = help.lines().map(str::trim_start).collect();
+
+    let mut features: Vec<String> = ["dedupe", "fragments", "ztailpacking"]
+        .into_iter()
+        .filter(|name| help.contains(name))
+        .map(str::to_string)
+        .collect();
+
+    if options.iter().any(|line| line.starts_with("-C")) {
+        features.push("pcluster".to_string());
+    }
+    if options.iter().any(|line| line.starts_with("-T")) {
+        features.push("timestamp".to_string());
+    }
+
+    features
+}
@@ -0,0 +1,42 @@
+use std::{
+    ffi::OsStr,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::defs;
+
+pub fn command() -> &'static OsStr {
+    let bundled = Path::new(defs::MKSQUASHFS_PATH);
+    if bundled.exists() {
+        bundled.as_os_str()
+    } else {
+        OsStr::new("mksquashfs")
+    }
+}
+
+// First line of `mksquashfs -version`, or None when the tool can't be run.
+pub fn version() -> Option<String> {
+    let output = Command::new(command())
+        .arg("-version")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .ok()?;
+
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+// `-xattrs` keeps SELinux labels and overlay xattrs, `-noappend` replaces an old image.
+pub fn build_args() -> Vec<String> {
+    ["-noappend", "-no-progress", "-xattrs"]
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
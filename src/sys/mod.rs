@@ -1,3 +1,8 @@
+pub mod caps;
+pub mod device;
+pub mod mkfs_erofs;
+pub mod mksquashfs;
 pub mod mount;
 pub mod nuke;
 pub mod poaceae;
+pub mod tmpfs_xattr;
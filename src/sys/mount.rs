@@ -1,4 +1,4 @@
-use std::{fs, path::Path, process::Command};
+use std::{ffi::CString, fs, path::Path, process::Command};
 
 use anyhow::{Context, Result, bail};
 use procfs::process::Process;
@@ -37,13 +37,19 @@ pub fn is_mounted<P: AsRef<Path>>(path: P) -> bool {
 }
 
 pub fn mount_tmpfs(target: &Path, source: &str) -> Result<()> {
+    mount_tmpfs_with(target, source, "")
+}
+
+// `options` is appended to the mount data, e.g. ",size=512m".
+pub fn mount_tmpfs_with(target: &Path, source: &str, options: &str) -> Result<()> {
     ensure_dir_exists(target)?;
+    let data = CString::new(format!("mode=0755{options}")).context("Invalid tmpfs options")?;
     mount(
         source,
         target,
         c"tmpfs",
         MountFlags::empty(),
-        Some(c"mode=0755"),
+        Some(data.as_c_str()),
     )
     .context("Failed to mount tmpfs")?;
     Ok(())
@@ -0,0 +1,147 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::Result;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use extattr::{Flags as XattrFlags, lgetxattr, lsetxattr};
+use rustix::mount::{UnmountFlags, unmount as umount};
+use serde::{Deserialize, Serialize};
+
+use crate::{defs, sys::mount::mount_tmpfs, utils};
+
+const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+const SELINUX_XATTR: &str = "security.selinux";
+const PROBE_CONTEXT: &str = "u:object_r:system_file:s0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum XattrProbeMethod {
+    // xattrs were set and read back on a scratch tmpfs during this boot
+    Probe,
+    // an earlier probe on the same kernel build
+    Cache,
+    // the probe could not run, /proc/config.gz was used as a hint
+    KernelConfig,
+    #[default]
+    Unknown,
+}
+
+impl XattrProbeMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            XattrProbeMethod::Probe => "probe",
+            XattrProbeMethod::Cache => "cached probe",
+            XattrProbeMethod::KernelConfig => "config.gz",
+            XattrProbeMethod::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TmpfsXattrSupport {
+    pub supported: bool,
+    pub method: XattrProbeMethod,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProbeCache {
+    kernel: String,
+    supported: bool,
+}
+
+static SUPPORT: OnceLock<TmpfsXattrSupport> = OnceLock::new();
+
+pub fn support() -> TmpfsXattrSupport {
+    *SUPPORT.get_or_init(detect)
+}
+
+fn detect() -> TmpfsXattrSupport {
+    let kernel = fs::read_to_string("/proc/version").unwrap_or_default();
+
+    if let Some(supported) = read_cache(&kernel) {
+        return TmpfsXattrSupport {
+            supported,
+            method: XattrProbeMethod::Cache,
+        };
+    }
+
+    match probe() {
+        Ok(supported) => {
+            write_cache(&kernel, supported);
+            return TmpfsXattrSupport {
+                supported,
+                method: XattrProbeMethod::Probe,
+            };
+        }
+        Err(e) => log::debug!("tmpfs xattr probe failed: {:#}", e),
+    }
+
+    match utils::is_overlay_xattr_supported() {
+        Ok(supported) => TmpfsXattrSupport {
+            supported,
+            method: XattrProbeMethod::KernelConfig,
+        },
+        Err(_) => TmpfsXattrSupport::default(),
+    }
+}
+
+// Err means the probe could not run at all; Ok(false) means tmpfs rejected or lost an xattr.
+fn probe() -> Result<bool> {
+    let dir = Path::new(defs::RUN_DIR).join("xattr_probe");
+    mount_tmpfs(&dir, "tmpfs")?;
+
+    let file = dir.join("probe");
+    let result = fs::write(&file, b"").map_err(Into::into).map(|()| {
+        roundtrip(&file, OVERLAY_OPAQUE_XATTR, b"y")
+            && roundtrip(&file, SELINUX_XATTR, PROBE_CONTEXT.as_bytes())
+    });
+
+    let _ = umount(&dir, UnmountFlags::DETACH);
+    let _ = fs::remove_dir(&dir);
+
+    result
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn roundtrip(path: &Path, name: &str, value: &[u8]) -> bool {
+    if let Err(e) = lsetxattr(path, name, value, XattrFlags::empty()) {
+        log::debug!("tmpfs rejected {}: {}", name, e);
+        return false;
+    }
+
+    lgetxattr(path, name)
+        .map(|read| read.strip_suffix(b"\0").unwrap_or(&read) == value)
+        .unwrap_or(false)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn roundtrip(_path: &Path, _name: &str, _value: &[u8]) -> bool {
+    false
+}
+
+fn cache_path() -> PathBuf {
+    Path::new(defs::RUN_DIR).join("tmpfs_xattr.json")
+}
+
+fn read_cache(kernel: &str) -> Option<bool> {
+    let content = fs::read_to_string(cache_path()).ok()?;
+    let cache: ProbeCache = serde_json::from_str(&content).ok()?;
+    (cache.kernel == kernel).then_some(cache.supported)
+}
+
+fn write_cache(kernel: &str, supported: bool) {
+    let cache = ProbeCache {
+        kernel: kernel.to_string(),
+        supported,
+    };
+
+    let result = serde_json::to_string(&cache)
+        .map_err(Into::into)
+        .and_then(|json| utils::atomic_write(cache_path(), json));
+    if let Err(e) = result {
+        log::debug!("Failed to cache tmpfs xattr probe: {:#}", e);
+    }
+}
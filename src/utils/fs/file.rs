@@ -9,7 +9,7 @@ use std::{
 };
 
 use anyhow::{Context, Result, bail};
-use rustix::fs::ioctl_ficlone;
+use rustix::fs::{AtFlags, CWD, Gid, Timespec, Timestamps, Uid, chownat, ioctl_ficlone, utimensat};
 use walkdir::WalkDir;
 
 use super::xattr::internal_copy_extended_attributes;
@@ -85,14 +85,8 @@ fn native_cp_r(
     _repair: bool,
     visited: &mut HashSet<(u64, u64)>,
 ) -> Result<()> {
-    if !dst.exists() {
-        if src.is_dir() {
-            fs::create_dir_all(dst)?;
-        }
-        if let Ok(src_meta) = src.metadata() {
-            let _ = fs::set_permissions(dst, src_meta.permissions());
-        }
-        let _ = internal_copy_extended_attributes(src, dst);
+    if !dst.exists() && src.is_dir() {
+        fs::create_dir_all(dst)?;
     }
 
     for entry in fs::read_dir(src)? {
@@ -112,28 +106,100 @@ fn native_cp_r(
                 continue;
             }
             native_cp_r(&src_path, &dst_path, &next_relative, _repair, visited)?;
-        } else if ft.is_symlink() {
-            if dst_path.exists() {
-                fs::remove_file(&dst_path)?;
-            }
-            let link_target = fs::read_link(&src_path)?;
-            symlink(&link_target, &dst_path)?;
-        } else if ft.is_char_device() || ft.is_block_device() || ft.is_fifo() {
-            if dst_path.exists() {
-                fs::remove_file(&dst_path)?;
+            // after the children, which would bump the mtime again
+            preserve_metadata(&src_path, &dst_path);
+        } else {
+            copy_entry(&src_path, &dst_path)?;
+        }
+    }
+    Ok(())
+}
+
+// Copies one entry over whatever is at `dst`. Directories are created without their contents.
+pub fn copy_entry(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = src.symlink_metadata()?;
+    let ft = metadata.file_type();
+
+    if let Ok(existing) = dst.symlink_metadata() {
+        if existing.is_dir() {
+            if !ft.is_dir() {
+                fs::remove_dir_all(dst)?;
             }
-            let mode = metadata.permissions().mode();
-            let rdev = metadata.rdev();
-            make_device_node(&dst_path, mode, rdev)?;
         } else {
-            reflink_or_copy(&src_path, &dst_path)?;
+            fs::remove_file(dst)?;
         }
+    }
+
+    if ft.is_dir() {
+        ensure_dir_exists(dst)?;
+    } else if ft.is_symlink() {
+        let link_target = fs::read_link(src)?;
+        symlink(&link_target, dst)?;
+    } else if ft.is_char_device() || ft.is_block_device() || ft.is_fifo() {
+        let mode = metadata.permissions().mode();
+        let rdev = metadata.rdev();
+        make_device_node(dst, mode, rdev)?;
+    } else {
+        reflink_or_copy(src, dst)?;
+    }
+
+    preserve_metadata(src, dst);
+    Ok(())
+}
+
+// Owner, mode, every xattr and the timestamps of `src`, applied in that order: chown clears
+// setuid bits and security.capability, and the timestamps have to be set last.
+pub fn copy_metadata(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = src.symlink_metadata()?;
+    let mut failed = Vec::new();
 
-        let _ = internal_copy_extended_attributes(&src_path, &dst_path);
+    if let Err(e) = chownat(
+        CWD,
+        dst,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        AtFlags::SYMLINK_NOFOLLOW,
+    ) {
+        failed.push(format!("owner: {}", e));
+    }
+
+    if !metadata.file_type().is_symlink()
+        && let Err(e) = fs::set_permissions(dst, metadata.permissions())
+    {
+        failed.push(format!("mode: {}", e));
+    }
+
+    if let Err(e) = internal_copy_extended_attributes(src, dst) {
+        failed.push(format!("{:#}", e));
+    }
+
+    let times = Timestamps {
+        last_access: Timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec() as _,
+        },
+        last_modification: Timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec() as _,
+        },
+    };
+    if let Err(e) = utimensat(CWD, dst, &times, AtFlags::SYMLINK_NOFOLLOW) {
+        failed.push(format!("timestamps: {}", e));
+    }
+
+    if !failed.is_empty() {
+        bail!("{}", failed.join("; "));
     }
     Ok(())
 }
 
+// The copy itself is still usable, so a metadata failure is reported but not fatal.
+fn preserve_metadata(src: &Path, dst: &Path) {
+    if let Err(e) = copy_metadata(src, dst) {
+        log::warn!("Failed to preserve metadata of {}: {:#}", dst.display(), e);
+    }
+}
+
 pub fn sync_dir(src: &Path, dst: &Path, repair_context: bool) -> Result<()> {
     if !src.exists() {
         return Ok(());
@@ -146,7 +212,9 @@ pub fn sync_dir(src: &Path, dst: &Path, repair_context: bool) -> Result<()> {
             src.display(),
             dst.display()
         )
-    })
+    })?;
+    preserve_metadata(src, dst);
+    Ok(())
 }
 
 pub fn prune_empty_dirs<P: AsRef<Path>>(root: P) -> Result<()> {
@@ -168,3 +236,167 @@ pub fn prune_empty_dirs<P: AsRef<Path>>(root: P) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::FileTimes, time::Duration};
+
+    use extattr::{Flags as XattrFlags, lgetxattr, lsetxattr};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn pin_mtime(path: &Path, secs: u64) {
+        File::open(path)
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(UNIX_EPOCH + Duration::from_secs(secs)))
+            .unwrap();
+    }
+
+    #[test]
+    fn copy_keeps_mode_and_times() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::write(&src, "data").unwrap();
+        // chown clears setuid, so the mode has to be set after it
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o4750)).unwrap();
+        pin_mtime(&src, 1_000_000);
+
+        copy_entry(&src, &dst).unwrap();
+
+        let meta = dst.symlink_metadata().unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"data");
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o4750);
+        assert_eq!(meta.mtime(), 1_000_000);
+    }
+
+    #[test]
+    fn synced_directories_keep_their_mtime() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(src.join("etc")).unwrap();
+        fs::write(src.join("etc/hosts"), "::1 localhost\n").unwrap();
+        pin_mtime(&src.join("etc"), 1_000_000);
+        pin_mtime(&src, 2_000_000);
+
+        sync_dir(&src, &dst, false).unwrap();
+
+        // writing the children would bump these again unless the times are set last
+        assert_eq!(dst.join("etc").metadata().unwrap().mtime(), 1_000_000);
+        assert_eq!(dst.metadata().unwrap().mtime(), 2_000_000);
+    }
+
+    #[test]
+    fn user_xattrs_are_copied() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::write(&src, "data").unwrap();
+        if lsetxattr(&src, "user.hybrid", b"1", XattrFlags::empty()).is_err() {
+            // the filesystem keeps no user xattrs
+            return;
+        }
+
+        copy_entry(&src, &dst).unwrap();
+
+        assert_eq!(lgetxattr(&dst, "user.hybrid").unwrap(), b"1");
+    }
+
+    #[test]
+    fn copy_replaces_a_hardlink_instead_of_writing_through_it() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        let other = dir.path().join("other");
+        fs::write(&src, "new").unwrap();
+        fs::write(&other, "old").unwrap();
+        fs::hard_link(&other, &dst).unwrap();
+
+        copy_entry(&src, &dst).unwrap();
+
+        assert_eq!(fs::read(&dst).unwrap(), b"new");
+        assert_eq!(fs::read(&other).unwrap(), b"old");
+        assert_eq!(other.metadata().unwrap().nlink(), 1);
+    }
+
+    // Capabilities are per thread, so only the calling thread loses CAP_CHOWN.
+    fn drop_cap_chown() {
+        const CAP_CHOWN: u32 = 0;
+
+        #[repr(C)]
+        struct Header {
+            version: u32,
+            pid: i32,
+        }
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        struct Data {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+
+        let mut header = Header {
+            version: 0x2008_0522,
+            pid: 0,
+        };
+        let mut data = [Data::default(); 2];
+        unsafe {
+            assert_eq!(
+                libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()),
+                0
+            );
+            data[0].effective &= !(1 << CAP_CHOWN);
+            assert_eq!(
+                libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn failed_chown_does_not_fail_the_copy() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::write(&src, "data").unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o600)).unwrap();
+        pin_mtime(&src, 1_000_000);
+
+        if unsafe { libc::geteuid() } != 0 {
+            // chown to another owner needs root
+            return;
+        }
+        chownat(
+            CWD,
+            &src,
+            Some(Uid::from_raw(12345)),
+            Some(Gid::from_raw(12345)),
+            AtFlags::empty(),
+        )
+        .unwrap();
+
+        let (copied, metadata) = std::thread::scope(|s| {
+            s.spawn(|| {
+                drop_cap_chown();
+                (copy_entry(&src, &dst), copy_metadata(&src, &dst))
+            })
+            .join()
+            .unwrap()
+        });
+
+        copied.unwrap();
+        let err = metadata.expect_err("chown should fail without CAP_CHOWN");
+        assert!(
+            err.to_string().contains("owner: Operation not permitted"),
+            "{err}"
+        );
+        let meta = dst.metadata().unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"data");
+        assert_eq!(meta.uid(), 0);
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(meta.mtime(), 1_000_000);
+    }
+}
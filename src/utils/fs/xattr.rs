@@ -1,35 +1,31 @@
-use std::path::Path;
+use std::{ffi::OsString, path::Path};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use std::{fs, io::Read, os::unix::ffi::OsStrExt};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use extattr::{Flags as XattrFlags, lgetxattr, llistxattr, lsetxattr};
 
 const SELINUX_XATTR: &str = "security.selinux";
 const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
 
+// Every namespace, so security.capability survives along with SELinux and overlay xattrs.
 #[cfg(any(target_os = "linux", target_os = "android"))]
 fn copy_extended_attributes(src: &Path, dst: &Path) -> Result<()> {
-    if let Ok(ctx) = lgetfilecon(src) {
-        let _ = lsetfilecon(dst, &ctx);
-    }
-
-    if let Ok(opaque) = lgetxattr(src, OVERLAY_OPAQUE_XATTR) {
-        let _ = lsetxattr(dst, OVERLAY_OPAQUE_XATTR, &opaque, XattrFlags::empty());
-    }
-    if let Ok(xattrs) = llistxattr(src) {
-        for xattr_name in xattrs {
-            let name_bytes = xattr_name.as_bytes();
-            let name_str = String::from_utf8_lossy(name_bytes);
-
-            if name_str.starts_with("trusted.overlay.")
-                && name_str != OVERLAY_OPAQUE_XATTR
-                && let Ok(val) = lgetxattr(src, &xattr_name)
-            {
-                let _ = lsetxattr(dst, &xattr_name, &val, XattrFlags::empty());
-            }
-        }
+    let names = llistxattr(src).context("Failed to list xattrs")?;
+
+    let failed: Vec<String> = names
+        .iter()
+        .filter_map(|name| {
+            lgetxattr(src, name)
+                .and_then(|value| lsetxattr(dst, name, &value, XattrFlags::empty()))
+                .err()
+                .map(|e| format!("{}: {}", String::from_utf8_lossy(name.as_bytes()), e))
+        })
+        .collect();
+
+    if !failed.is_empty() {
+        bail!("xattrs: {}", failed.join(", "));
     }
     Ok(())
 }
@@ -122,6 +118,29 @@ pub fn is_overlay_xattr_supported() -> Result<bool> {
     unimplemented!();
 }
 
+// Every xattr sorted by name, empty when they can't be listed.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn list_xattrs(path: &Path) -> Vec<(OsString, Vec<u8>)> {
+    let Ok(names) = llistxattr(path) else {
+        return Vec::new();
+    };
+
+    let mut xattrs: Vec<(OsString, Vec<u8>)> = names
+        .into_iter()
+        .map(|name| {
+            let value = lgetxattr(path, &name).unwrap_or_default();
+            (name, value)
+        })
+        .collect();
+    xattrs.sort();
+    xattrs
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn list_xattrs(_path: &Path) -> Vec<(OsString, Vec<u8>)> {
+    Vec::new()
+}
+
 pub fn internal_copy_extended_attributes(src: &Path, dst: &Path) -> Result<()> {
     copy_extended_attributes(src, dst)
 }
@@ -7,6 +7,7 @@ import type {
   StorageStatus,
   SystemInfo,
   ModuleRules,
+  ConfigValidationReport,
 } from "./types";
 
 const delay = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms));
@@ -20,6 +21,11 @@ export const MockAPI = {
     await delay(500);
     console.log("[Mock] Config saved:", config);
   },
+  async checkConfig(config: AppConfig): Promise<ConfigValidationReport> {
+    await delay(200);
+    console.log("[Mock] Config checked:", config);
+    return { valid: true, issues: [] };
+  },
   async resetConfig(): Promise<void> {
     await delay(500);
     console.log("[Mock] Config reset to defaults");
@@ -8,6 +8,7 @@ import type {
   SystemInfo,
   DeviceInfo,
   ModuleRules,
+  ConfigValidationReport,
 } from "./types";
 
 interface KsuExecResult {
@@ -31,28 +32,16 @@ try {
 
 const shouldUseMock = import.meta.env.DEV || !ksuExec;
 
-function stringToHex(str: string): string {
-  let bytes: Uint8Array;
-  if (typeof TextEncoder !== "undefined") {
-    const encoder = new TextEncoder();
-    bytes = encoder.encode(str);
-  } else {
-    bytes = new Uint8Array(str.length);
-    for (let i = 0; i < str.length; i++) {
-      bytes[i] = str.charCodeAt(i) & 0xff;
-    }
-  }
-  let hex = "";
-  for (let i = 0; i < bytes.length; i++) {
-    const h = bytes[i].toString(16);
-    hex += h.length === 1 ? "0" + h : h;
-  }
-  return hex;
+// Feed JSON through a quoted heredoc so it never shows up in argv.
+// JSON.stringify output has no raw newlines, so it cannot end the heredoc early.
+function withStdin(cmd: string, payload: unknown): string {
+  return `${cmd} --stdin <<'HM_PAYLOAD_EOF'\n${JSON.stringify(payload)}\nHM_PAYLOAD_EOF`;
 }
 
 interface AppAPI {
   loadConfig: () => Promise<AppConfig>;
   saveConfig: (config: AppConfig) => Promise<void>;
+  checkConfig: (config: AppConfig) => Promise<ConfigValidationReport>;
   resetConfig: () => Promise<void>;
   scanModules: (path?: string) => Promise<Module[]>;
   saveModules: (modules: Module[]) => Promise<void>;
@@ -81,12 +70,20 @@ const RealAPI: AppAPI = {
   },
   saveConfig: async (config: AppConfig): Promise<void> => {
     if (!ksuExec) throw new Error("No KSU Environment");
-    const jsonStr = JSON.stringify(config);
-    const hexPayload = stringToHex(jsonStr);
-    const cmd = `${PATHS.BINARY} save-config --payload ${hexPayload}`;
+    const cmd = withStdin(`${PATHS.BINARY} save-config`, config);
     const { errno, stderr } = await ksuExec(cmd);
     if (errno !== 0) throw new Error(`Failed to Save Config: ${stderr}`);
   },
+  checkConfig: async (config: AppConfig): Promise<ConfigValidationReport> => {
+    if (!ksuExec) return { valid: true, issues: [] };
+    const cmd = withStdin(`${PATHS.BINARY} check-config`, config);
+    const { stdout, stderr } = await ksuExec(cmd);
+    try {
+      return JSON.parse(stdout);
+    } catch {
+      throw new Error(`Failed to Check Config: ${stderr}`);
+    }
+  },
   resetConfig: async (): Promise<void> => {
     if (!ksuExec) throw new Error("No KSU environment");
     const cmd = `${PATHS.BINARY} gen-config`;
@@ -121,9 +118,10 @@ const RealAPI: AppAPI = {
     rules: ModuleRules,
   ): Promise<void> => {
     if (!ksuExec) throw new Error("No KSU environment");
-    const jsonStr = JSON.stringify(rules);
-    const hexPayload = stringToHex(jsonStr);
-    const cmd = `${PATHS.BINARY} save-module-rules --module "${moduleId}" --payload ${hexPayload}`;
+    const cmd = withStdin(
+      `${PATHS.BINARY} save-module-rules --module "${moduleId}"`,
+      rules,
+    );
     const { errno, stderr } = await ksuExec(cmd);
     if (errno !== 0) throw new Error(`Failed to save rules: ${stderr}`);
   },
@@ -132,7 +130,7 @@ const RealAPI: AppAPI = {
     try {
       const stateFile =
         (PATHS as Record<string, string>).DAEMON_STATE ||
-        "/data/adb/meta-hybrid/run/daemon_state.json";
+        "/data/adb/hybrid-mount/run/daemon_state.json";
       const { errno, stdout } = await ksuExec(`cat "${stateFile}"`);
       if (errno === 0 && stdout) {
         const state = JSON.parse(stdout);
@@ -165,7 +163,7 @@ const RealAPI: AppAPI = {
       }
       const stateFile =
         (PATHS as Record<string, string>).DAEMON_STATE ||
-        "/data/adb/meta-hybrid/run/daemon_state.json";
+        "/data/adb/hybrid-mount/run/daemon_state.json";
       const { errno: errState, stdout: outState } = await ksuExec(
         `cat "${stateFile}"`,
       );
@@ -180,6 +178,15 @@ const RealAPI: AppAPI = {
           if (state.tmpfs_xattr_supported !== undefined) {
             info.tmpfs_xattr_supported = state.tmpfs_xattr_supported;
           }
+          if (state.tmpfs_xattr_method !== undefined) {
+            info.tmpfs_xattr_method = state.tmpfs_xattr_method;
+          }
+          if (state.caps) {
+            info.caps = state.caps;
+          }
+          if (state.dedup) {
+            info.dedup = state.dedup;
+          }
         } catch {}
       }
       return info;
@@ -9,7 +9,7 @@ import type { AppConfig } from "./types";
 export const DEFAULT_CONFIG: AppConfig = {
   moduledir: "/data/adb/modules",
   mountsource: "KSU",
-  logfile: RUST_PATHS.DAEMON_LOG || "/data/adb/Hybrid-Mount/daemon.log",
+  logfile: RUST_PATHS.DAEMON_LOG || "/data/adb/hybrid-mount/daemon.log",
   partitions: [],
   disable_umount: false,
   allow_umount_coexistence: false,
@@ -4,17 +4,39 @@
  */
 
 export interface ModuleRules {
-  default_mode: MountMode;
+  default_mode?: MountMode;
   paths: Record<string, string>;
+  priority?: number;
 }
 
-export type OverlayMode = "TMPFS" | "Ext4" | "Erofs";
+export type OverlayMode = "TMPFS" | "Ext4" | "Erofs" | "Squashfs";
+
+export interface ErofsOptions {
+  compressor?: "none" | "lz4" | "lz4hc" | "lzma" | "deflate";
+  level?: number;
+  pcluster_size?: number;
+  dedupe?: boolean;
+  fragments?: boolean;
+  timestamp?: number;
+}
+
+export interface TmpfsOptions {
+  size?: string;
+  nr_inodes?: number;
+}
 
 export interface AppConfig {
+  config_version?: number;
   moduledir: string;
   mountsource: string;
   partitions: string[];
+  partition_modes?: Record<string, string>;
+  profile?: string;
+  profiles?: Record<string, Record<string, unknown>>;
   overlay_mode: OverlayMode;
+  erofs?: ErofsOptions;
+  storage_chain?: OverlayMode[];
+  tmpfs?: TmpfsOptions;
   disable_umount: boolean;
   allow_umount_coexistence: boolean;
   logfile?: string;
@@ -22,6 +44,31 @@ export interface AppConfig {
 
 export type MountMode = "Overlay" | "Magic" | "Ignore";
 
+export type ConfigIssueKind =
+  | "parse_error"
+  | "unknown_partition"
+  | "invalid_module_id"
+  | "unknown_module"
+  | "invalid_path_rule"
+  | "invalid_condition"
+  | "invalid_profile"
+  | "invalid_erofs_option"
+  | "invalid_storage_chain"
+  | "invalid_tmpfs_option"
+  | "mount_source_mismatch";
+
+export interface ConfigIssue {
+  kind: ConfigIssueKind;
+  level: "error" | "warning";
+  field: string;
+  message: string;
+}
+
+export interface ConfigValidationReport {
+  valid: boolean;
+  issues: ConfigIssue[];
+}
+
 export interface Module {
   id: string;
   name: string;
@@ -33,10 +80,11 @@ export interface Module {
   enabled?: boolean;
   source_path?: string;
   rules: ModuleRules;
+  partition_modes?: Record<string, string>;
 }
 
 export interface StorageStatus {
-  type: "tmpfs" | "ext4" | "erofs" | "unknown" | null;
+  type: "tmpfs" | "ext4" | "erofs" | "squashfs" | "unknown" | null;
   error?: string;
 }
 
@@ -48,6 +96,31 @@ export interface SystemInfo {
   zygisksuEnforce?: string;
   supported_overlay_modes?: OverlayMode[];
   tmpfs_xattr_supported?: boolean;
+  tmpfs_xattr_method?: "probe" | "cache" | "kernel_config" | "unknown";
+  caps?: Caps;
+  dedup?: { files: number; bytes: number };
+}
+
+export interface Caps {
+  kernel: {
+    overlayfs: boolean;
+    erofs: boolean;
+    squashfs: boolean;
+    tmpfs_xattr: { supported: boolean; method: string };
+    loop_control: boolean;
+    fsopen: boolean;
+  };
+  tools: {
+    mkfs_ext4: string | null;
+    e2fsck: string | null;
+    resize2fs: string | null;
+    mkfs_erofs: {
+      version: string;
+      compressors: string[];
+      features: string[];
+    } | null;
+    mksquashfs: string | null;
+  };
 }
 
 export interface DeviceInfo {
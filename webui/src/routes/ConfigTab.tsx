@@ -130,7 +130,7 @@ export default function ConfigTab() {
     } else {
       modes =
         store.systemInfo?.supported_overlay_modes ??
-        (["tmpfs", "ext4", "erofs"] as OverlayMode[]);
+        (["tmpfs", "ext4", "erofs", "squashfs"] as OverlayMode[]);
     }
 
     if (store.systemInfo?.tmpfs_xattr_supported === false) {
@@ -144,6 +144,7 @@ export default function ConfigTab() {
     tmpfs: "RAM-based. Fastest I/O, reset on reboot.",
     ext4: "Loopback image. Persistent, saves RAM.",
     erofs: "Read-only compressed. High performance, space saving.",
+    squashfs: "Read-only compressed. For kernels without EROFS.",
   };
 
   return (
@@ -123,6 +123,11 @@ export default function ModulesTab() {
     updateModule(modId, (m) => ({ ...m, rules: updateFn(m.rules) }));
   }
 
+  // Without its own default a module follows the partition modes and the global default.
+  function effectiveDefault(mod: Module): string {
+    return mod.rules.default_mode ?? (mod.mode === "auto" ? "overlay" : mod.mode);
+  }
+
   function updateDefaultMode(mod: Module, mode: MountMode) {
     updateModuleRules(mod.id, (rules) => ({ ...rules, default_mode: mode }));
   }
@@ -247,7 +252,7 @@ export default function ModulesTab() {
                             </div>
                             <div class="strategy-selector">
                               <button
-                                class={`strategy-option ${mod.rules.default_mode === "overlay" ? "selected" : ""}`}
+                                class={`strategy-option ${effectiveDefault(mod) === "overlay" ? "selected" : ""}`}
                                 onClick={() =>
                                   updateDefaultMode(mod, "overlay")
                                 }
@@ -259,7 +264,7 @@ export default function ModulesTab() {
                                 <span class="opt-sub">Default</span>
                               </button>
                               <button
-                                class={`strategy-option ${mod.rules.default_mode === "magic" ? "selected" : ""}`}
+                                class={`strategy-option ${effectiveDefault(mod) === "magic" ? "selected" : ""}`}
                                 onClick={() => updateDefaultMode(mod, "magic")}
                               >
                                 <span class="opt-title">
@@ -269,7 +274,7 @@ export default function ModulesTab() {
                                 <span class="opt-sub">Compat</span>
                               </button>
                               <button
-                                class={`strategy-option ${mod.rules.default_mode === "ignore" ? "selected" : ""}`}
+                                class={`strategy-option ${effectiveDefault(mod) === "ignore" ? "selected" : ""}`}
                                 onClick={() => updateDefaultMode(mod, "ignore")}
                               >
                                 <span class="opt-title">